@@ -0,0 +1,1299 @@
+use crate::lrc3::*;
+use core::fmt::{Display, Error, Formatter};
+use std::collections::{HashMap, HashSet};
+
+/* A two pass assembler for LC-3 assembly
+ *
+ * Pass 1 walks every line, assigning an address to each statement and
+ * recording the address of every label. Pass 2 walks the statements again,
+ * now that every label is known, and turns them into Instruction values
+ * (or raw data words for directives), which are encoded into segments.
+ * With relaxation on, pass 1 repeats until every label is in reach (see
+ * relax below).
+ */
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+    pub(crate) text: String,
+    /// 1-based, counted in chars
+    pub(crate) column: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct SourceLine {
+    pub(crate) number: usize,
+    pub(crate) label: Option<Token>,
+    pub(crate) op: Option<Token>,
+    pub(crate) operands: Vec<Token>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Operand {
+    Register(RegisterName),
+    Number(i32),
+    Label(String),
+    Str(String),
+}
+
+#[derive(Debug)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    InvalidLabel(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    InvalidLiteral(String),
+    UnterminatedString,
+    ExpectedRegister(String),
+    ExpectedNumber(String),
+    ExpectedString(String),
+    WrongOperandCount {
+        mnemonic: String,
+        expected: usize,
+        actual: usize,
+    },
+    OutOfRange {
+        field: &'static str,
+        value: i32,
+        min: i32,
+        max: i32,
+    },
+    StatementOutsideOrig,
+    NestedOrig,
+    MissingEnd,
+}
+
+impl Display for AsmErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::UnknownMnemonic(m) => write!(f, "unknown opcode or directive '{}'", m),
+            Self::InvalidLabel(l) => write!(f, "'{}' is not a valid label", l),
+            Self::DuplicateLabel(l) => write!(f, "label '{}' is defined more than once", l),
+            Self::UndefinedLabel(l) => write!(f, "label '{}' is never defined", l),
+            Self::InvalidLiteral(l) => write!(f, "'{}' is not a valid literal", l),
+            Self::UnterminatedString => write!(f, "string literal is missing its closing quote"),
+            Self::ExpectedRegister(t) => write!(f, "expected a register, found '{}'", t),
+            Self::ExpectedNumber(t) => write!(f, "expected a number or label, found '{}'", t),
+            Self::ExpectedString(t) => write!(f, "expected a string literal, found '{}'", t),
+            Self::WrongOperandCount {
+                mnemonic,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} takes {} operand(s), but {} were given",
+                mnemonic, expected, actual
+            ),
+            Self::OutOfRange {
+                field,
+                value,
+                min,
+                max,
+            } => write!(
+                f,
+                "{} does not fit in {} (must be between {} and {})",
+                value, field, min, max
+            ),
+            Self::StatementOutsideOrig => write!(f, "statement is not inside a .ORIG/.END block"),
+            Self::NestedOrig => write!(f, ".ORIG found before the .END of the previous block"),
+            Self::MissingEnd => write!(f, ".ORIG block is missing its .END"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AsmErrorArgs {
+    pub line: usize,
+    /// 1-based, counted in chars, where the offending text starts
+    pub column: usize,
+    /// How many chars the offending text covers
+    pub len: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl Display for AsmErrorArgs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.kind)
+    }
+}
+
+/// # One source statement after assembly, with the address it was placed at
+#[derive(Debug)]
+pub struct AssembledStatement {
+    pub line: usize,
+    pub address: u16,
+    pub words: Vec<u16>,
+    pub instruction: Option<Instruction>,
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    pub segments: Vec<(u16, Vec<u16>)>,
+    pub symbols: Vec<(String, u16)>,
+    pub statements: Vec<AssembledStatement>,
+}
+
+/// # Choices that change what the assembler emits
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsmOptions {
+    /// Rewrite BR, LD, ST, LEA and JSR whose label is out of reach into longer sequences that
+    /// reach it, with far branches jumping through this register
+    pub relax: Option<RegisterName>,
+    /// The edition the code is written for, which decides what relaxing may change
+    pub policy: DecodePolicy,
+}
+
+impl Assembly {
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, address)| *address)
+    }
+}
+
+/// # An error about token, on line
+fn error(line: usize, token: &Token, kind: AsmErrorKind) -> AsmErrorArgs {
+    AsmErrorArgs {
+        line,
+        column: token.column,
+        len: token.text.chars().count().max(1),
+        kind,
+    }
+}
+
+fn tokenize(number: usize, text: &str) -> Result<Vec<Token>, AsmErrorArgs> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ';' {
+            break;
+        }
+        if c.is_whitespace() || c == ',' {
+            i += 1;
+            continue;
+        }
+
+        let start = i;
+        if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            if i >= chars.len() {
+                let rest = Token {
+                    text: chars[start..].iter().collect(),
+                    column: start + 1,
+                };
+                return Err(error(number, &rest, AsmErrorKind::UnterminatedString));
+            }
+            i += 1;
+        } else {
+            while i < chars.len() && !chars[i].is_whitespace() && chars[i] != ',' && chars[i] != ';'
+            {
+                i += 1;
+            }
+        }
+
+        tokens.push(Token {
+            text: chars[start..i].iter().collect(),
+            column: start + 1,
+        });
+    }
+
+    Ok(tokens)
+}
+
+fn is_branch(upper: &str) -> bool {
+    match upper.strip_prefix("BR") {
+        Some(flags) => {
+            let mut rest = flags;
+            for flag in ["N", "Z", "P"] {
+                rest = rest.strip_prefix(flag).unwrap_or(rest);
+            }
+            rest.is_empty()
+        }
+        None => false,
+    }
+}
+
+pub(crate) fn is_mnemonic(text: &str) -> bool {
+    let upper = text.to_ascii_uppercase();
+    is_branch(&upper)
+        || matches!(
+            upper.as_str(),
+            "ADD" | "AND" | "NOT" | "JMP" | "RET" | "JSR" | "JSRR" | "LD" | "LDI" | "LDR"
+                | "LEA" | "ST" | "STI" | "STR" | "TRAP" | "RTI" | "GETC" | "OUT" | "PUTS"
+                | "IN" | "PUTSP" | "HALT" | ".ORIG" | ".FILL" | ".BLKW" | ".STRINGZ" | ".END"
+        )
+}
+
+pub(crate) fn is_valid_label(text: &str) -> bool {
+    let mut chars = text.chars();
+    let leading = match chars.next() {
+        Some(c) => c.is_ascii_alphabetic() || c == '_',
+        None => false,
+    };
+    leading && chars.all(|c| c.is_ascii_alphanumeric() || c == '_') && parse_register(text).is_none()
+}
+
+pub(crate) fn parse_line(number: usize, text: &str) -> Result<SourceLine, AsmErrorArgs> {
+    let mut tokens = tokenize(number, text)?.into_iter();
+    let mut line = SourceLine {
+        number,
+        label: None,
+        op: None,
+        operands: Vec::new(),
+    };
+
+    if let Some(first) = tokens.next() {
+        if is_mnemonic(&first.text) {
+            line.op = Some(first);
+        } else {
+            let name = first.text.trim_end_matches(':').to_string();
+            if !is_valid_label(&name) {
+                return Err(if first.text.starts_with('.') {
+                    error(number, &first, AsmErrorKind::UnknownMnemonic(first.text.clone()))
+                } else {
+                    error(number, &first, AsmErrorKind::InvalidLabel(first.text.clone()))
+                });
+            }
+            line.label = Some(Token {
+                text: name,
+                column: first.column,
+            });
+            line.op = tokens.next();
+        }
+    }
+    line.operands = tokens.collect();
+
+    if let Some(op) = &line.op {
+        if !is_mnemonic(&op.text) {
+            return Err(error(number, op, AsmErrorKind::UnknownMnemonic(op.text.clone())));
+        }
+    }
+
+    Ok(line)
+}
+
+pub fn parse_register(text: &str) -> Option<RegisterName> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('r'), Some(n), None) | (Some('R'), Some(n), None) => match n.to_digit(10) {
+            Some(n) if n < 8 => Some(RegisterName::from_bits(n as u16)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_number(text: &str) -> Option<i32> {
+    let radix = |digits: &str, radix: u32| {
+        let (negative, digits) = match digits.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, digits),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let value = i32::from_str_radix(digits, radix).ok()?;
+        Some(if negative { -value } else { value })
+    };
+
+    let mut chars = text.chars();
+    match chars.next()? {
+        '#' => radix(&text[1..], 10),
+        '0' if text.len() > 2 && (text[1..].starts_with('x') || text[1..].starts_with('X')) => {
+            radix(&text[2..], 16)
+        }
+        'x' | 'X' => radix(&text[1..], 16),
+        'b' | 'B' => radix(&text[1..], 2),
+        c if c.is_ascii_digit() || c == '-' => radix(text, 10),
+        _ => None,
+    }
+}
+
+fn unescape(number: usize, token: &Token) -> Result<String, AsmErrorArgs> {
+    let quoted = token.text.as_str();
+    let mut out = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('0') => out.push('\0'),
+            Some('e') => out.push('\x1b'),
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => out.push(c),
+            _ => return Err(error(number, token, AsmErrorKind::InvalidLiteral(quoted.to_string()))),
+        }
+    }
+    Ok(out)
+}
+
+pub(crate) fn parse_operand(number: usize, token: &Token) -> Result<Operand, AsmErrorArgs> {
+    let text = token.text.as_str();
+    if text.starts_with('"') {
+        return Ok(Operand::Str(unescape(number, token)?));
+    }
+    if let Some(reg) = parse_register(text) {
+        return Ok(Operand::Register(reg));
+    }
+    if let Some(value) = parse_number(text) {
+        return Ok(Operand::Number(value));
+    }
+    if is_valid_label(text) {
+        return Ok(Operand::Label(text.to_string()));
+    }
+    Err(error(number, token, AsmErrorKind::InvalidLiteral(text.to_string())))
+}
+
+struct Operands<'a> {
+    line: &'a SourceLine,
+    symbols: &'a HashMap<String, u16>,
+    address: u16,
+}
+
+impl Operands<'_> {
+    fn mnemonic(&self) -> &Token {
+        self.line.op.as_ref().expect("only statements have operands")
+    }
+
+    /// # An error about the operand at index
+    fn error(&self, index: usize, kind: AsmErrorKind) -> AsmErrorArgs {
+        error(self.line.number, &self.line.operands[index], kind)
+    }
+
+    fn expect_count(&self, expected: usize) -> Result<(), AsmErrorArgs> {
+        let actual = self.line.operands.len();
+        if actual != expected {
+            let mnemonic = self.mnemonic().text.clone();
+            return Err(error(
+                self.line.number,
+                self.mnemonic(),
+                AsmErrorKind::WrongOperandCount {
+                    mnemonic,
+                    expected,
+                    actual,
+                },
+            ));
+        }
+        Ok(())
+    }
+
+    fn operand(&self, index: usize) -> Result<Operand, AsmErrorArgs> {
+        parse_operand(self.line.number, &self.line.operands[index])
+    }
+
+    fn register(&self, index: usize) -> Result<RegisterName, AsmErrorArgs> {
+        match self.operand(index)? {
+            Operand::Register(reg) => Ok(reg),
+            _ => Err(self.error(
+                index,
+                AsmErrorKind::ExpectedRegister(self.line.operands[index].text.clone()),
+            )),
+        }
+    }
+
+    fn lookup(&self, index: usize, label: String) -> Result<u16, AsmErrorArgs> {
+        match self.symbols.get(&label) {
+            Some(address) => Ok(*address),
+            None => Err(self.error(index, AsmErrorKind::UndefinedLabel(label))),
+        }
+    }
+
+    /// # A literal or label, where labels stand for their address
+    fn value(&self, index: usize) -> Result<i32, AsmErrorArgs> {
+        match self.operand(index)? {
+            Operand::Number(value) => Ok(value),
+            Operand::Label(label) => Ok(self.lookup(index, label)? as i32),
+            _ => Err(self.error(
+                index,
+                AsmErrorKind::ExpectedNumber(self.line.operands[index].text.clone()),
+            )),
+        }
+    }
+
+    /// # A literal offset, or a label turned into an offset from the incremented PC
+    fn pc_offset(&self, index: usize) -> Result<i32, AsmErrorArgs> {
+        match self.operand(index)? {
+            Operand::Label(label) => {
+                Ok(self.lookup(index, label)? as i32 - (self.address as i32 + 1))
+            }
+            _ => self.value(index),
+        }
+    }
+
+    /// # The value of the operand at index, if it fits between min and max
+    fn ranged(&self, index: usize, value: i32, field: &'static str, min: i32, max: i32) -> Result<u16, AsmErrorArgs> {
+        if value < min || value > max {
+            return Err(self.error(
+                index,
+                AsmErrorKind::OutOfRange {
+                    field,
+                    value,
+                    min,
+                    max,
+                },
+            ));
+        }
+        Ok(value as u16)
+    }
+
+    fn imm5(&self, index: usize) -> Result<Imm5, AsmErrorArgs> {
+        Ok(Imm5::new(self.ranged(index, self.value(index)?, "imm5", -16, 15)?))
+    }
+
+    fn offset6(&self, index: usize) -> Result<Offset6, AsmErrorArgs> {
+        Ok(Offset6::new(self.ranged(index, self.value(index)?, "offset6", -32, 31)?))
+    }
+
+    fn pcoffset9(&self, index: usize) -> Result<PcOffset9, AsmErrorArgs> {
+        Ok(PcOffset9::new(self.ranged(index, self.pc_offset(index)?, "PCoffset9", -256, 255)?))
+    }
+
+    fn pcoffset11(&self, index: usize) -> Result<PcOffset11, AsmErrorArgs> {
+        Ok(PcOffset11::new(self.ranged(index, self.pc_offset(index)?, "PCoffset11", -1024, 1023)?))
+    }
+
+    fn string(&self, index: usize) -> Result<String, AsmErrorArgs> {
+        match self.operand(index)? {
+            Operand::Str(s) => Ok(s),
+            _ => Err(self.error(
+                index,
+                AsmErrorKind::ExpectedString(self.line.operands[index].text.clone()),
+            )),
+        }
+    }
+}
+
+fn trap(vector: u16) -> Instruction {
+    Instruction::Trap(TrapArgs {
+        trapvect8: TrapVect::new(vector),
+    })
+}
+
+fn build_instruction(mnemonic: &str, ops: &Operands) -> Result<Instruction, AsmErrorArgs> {
+    match mnemonic {
+        "ADD" | "AND" => {
+            ops.expect_count(3)?;
+            let (dr, sr1) = (ops.register(0)?, ops.register(1)?);
+            let two_source = matches!(ops.operand(2)?, Operand::Register(_));
+            Ok(match (mnemonic, two_source) {
+                ("ADD", true) => Instruction::Add(TwoSourceArithArgs {
+                    dr,
+                    sr1,
+                    sr2: ops.register(2)?,
+                }),
+                ("AND", true) => Instruction::And(TwoSourceArithArgs {
+                    dr,
+                    sr1,
+                    sr2: ops.register(2)?,
+                }),
+                ("ADD", false) => Instruction::Addi(ImmedArithArgs {
+                    dr,
+                    sr1,
+                    imm5: ops.imm5(2)?,
+                }),
+                _ => Instruction::Andi(ImmedArithArgs {
+                    dr,
+                    sr1,
+                    imm5: ops.imm5(2)?,
+                }),
+            })
+        }
+        "NOT" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::Not(OneSourceArithArgs {
+                dr: ops.register(0)?,
+                sr: ops.register(1)?,
+            }))
+        }
+        "JMP" | "JSRR" => {
+            ops.expect_count(1)?;
+            let args = BaseRArgs {
+                base_r: ops.register(0)?,
+            };
+            Ok(match mnemonic {
+                "JMP" => Instruction::Jmp(args),
+                _ => Instruction::Jsrr(args),
+            })
+        }
+        "RET" => {
+            ops.expect_count(0)?;
+            Ok(Instruction::Jmp(BaseRArgs {
+                base_r: RegisterName::R7,
+            }))
+        }
+        "JSR" => {
+            ops.expect_count(1)?;
+            Ok(Instruction::Jsr(JsrArgs {
+                pcoffset11: ops.pcoffset11(0)?,
+            }))
+        }
+        "LD" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::Ld(LdArgs {
+                dr: ops.register(0)?,
+                pcoffset9: ops.pcoffset9(1)?,
+            }))
+        }
+        "LDI" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::Ldi(LdiArgs {
+                dr: ops.register(0)?,
+                pcoffset9: ops.pcoffset9(1)?,
+            }))
+        }
+        "LEA" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::Lea(LeaArgs {
+                dr: ops.register(0)?,
+                pcoffset9: ops.pcoffset9(1)?,
+            }))
+        }
+        "ST" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::St(StArgs {
+                sr: ops.register(0)?,
+                offset9: ops.pcoffset9(1)?,
+            }))
+        }
+        "STI" => {
+            ops.expect_count(2)?;
+            Ok(Instruction::Sti(StiArgs {
+                sr: ops.register(0)?,
+                offset9: ops.pcoffset9(1)?,
+            }))
+        }
+        "LDR" => {
+            ops.expect_count(3)?;
+            Ok(Instruction::Ldr(LdrArgs {
+                dr: ops.register(0)?,
+                base_r: ops.register(1)?,
+                offset6: ops.offset6(2)?,
+            }))
+        }
+        "STR" => {
+            ops.expect_count(3)?;
+            Ok(Instruction::Str(StrArgs {
+                sr: ops.register(0)?,
+                base_r: ops.register(1)?,
+                offset6: ops.offset6(2)?,
+            }))
+        }
+        "TRAP" => {
+            ops.expect_count(1)?;
+            Ok(trap(ops.ranged(0, ops.value(0)?, "trapvect8", 0, 0xff)?))
+        }
+        "RTI" => {
+            ops.expect_count(0)?;
+            Ok(Instruction::Rti())
+        }
+        "GETC" | "OUT" | "PUTS" | "IN" | "PUTSP" | "HALT" => {
+            ops.expect_count(0)?;
+            Ok(trap(match mnemonic {
+                "GETC" => 0x20,
+                "OUT" => 0x21,
+                "PUTS" => 0x22,
+                "IN" => 0x23,
+                "PUTSP" => 0x24,
+                _ => 0x25,
+            }))
+        }
+        _ => {
+            // Only branches are left after is_mnemonic, plain BR meaning BRnzp
+            ops.expect_count(1)?;
+            let (n, z, p) = branch_flags(mnemonic);
+            Ok(Instruction::Br(BranchArgs {
+                n: BranchFlag(n),
+                z: BranchFlag(z),
+                p: BranchFlag(p),
+                pcoffset9: ops.pcoffset9(0)?,
+            }))
+        }
+    }
+}
+
+/// # Number of words a statement occupies, which pass 1 needs before labels are known
+pub(crate) fn statement_size(line: &SourceLine, mnemonic: &str) -> Result<u16, AsmErrorArgs> {
+    let ops = Operands {
+        line,
+        symbols: &HashMap::new(),
+        address: 0,
+    };
+    match mnemonic {
+        ".FILL" => Ok(1),
+        ".BLKW" => {
+            ops.expect_count(1)?;
+            match ops.operand(0)? {
+                Operand::Number(n) => ops.ranged(0, n, ".BLKW count", 0, 0xffff),
+                _ => Err(ops.error(
+                    0,
+                    AsmErrorKind::ExpectedNumber(line.operands[0].text.clone()),
+                )),
+            }
+        }
+        ".STRINGZ" => {
+            ops.expect_count(1)?;
+            Ok(ops.string(0)?.chars().count() as u16 + 1)
+        }
+        _ => Ok(1),
+    }
+}
+
+/// # Assembles source, stopping at its first error
+pub fn assemble(source: &str) -> Result<Assembly, AsmErrorArgs> {
+    assemble_all(source).map_err(|mut errors| errors.remove(0))
+}
+
+/* Relaxation: the long forms of statements whose label is out of reach
+ *
+ * Each long form keeps its target's address in a .FILL of its own, an inline
+ * literal pool that the code loads from or jumps around:
+ *
+ *  BR LABEL         LD Rs, #1 / JMP Rs / .FILL LABEL
+ *  BRnz LABEL       BRp #3 / LD Rs, #1 / JMP Rs / .FILL LABEL
+ *  LD R0, LABEL     LDI R0, #1 / BR #1 / .FILL LABEL
+ *  ST R0, LABEL     STI R0, #1 / BR #1 / .FILL LABEL
+ *  LEA R0, LABEL    LD R0, #1 / BR #1 / .FILL LABEL
+ *  JSR LABEL        BR #1 / .FILL LABEL / LD R7, #-2 / JSRR R7
+ *
+ * LD, ST and LEA behave exactly as before, condition codes included. A far
+ * branch clobbers the scratch register Rs and, when taken, the condition
+ * codes; a far JSR clobbers the condition codes, which the callee sees.
+ * Literal offsets are never relaxed, since the programmer chose them, and
+ * nor is LEA for the third edition, where it leaves the condition codes
+ * alone but its long form would not.
+ */
+
+/// # The operand index and PCoffset range of instructions that can be relaxed
+fn relaxable(mnemonic: &str) -> Option<(usize, i32, i32)> {
+    match mnemonic {
+        "JSR" => Some((0, -1024, 1023)),
+        "LD" | "ST" | "LEA" => Some((1, -256, 255)),
+        m if m.starts_with("BR") => Some((0, -256, 255)),
+        _ => None,
+    }
+}
+
+/// # Whether line is an instruction whose label is too far from address for its PCoffset, and can be relaxed
+fn out_of_reach(line: &SourceLine, address: u16, symbols: &HashMap<String, u16>, edition: Edition) -> bool {
+    let mnemonic = match &line.op {
+        Some(op) => op.text.to_ascii_uppercase(),
+        None => return false,
+    };
+    if mnemonic == "LEA" && edition == Edition::Third {
+        return false;
+    }
+    let (index, min, max) = match relaxable(&mnemonic) {
+        Some(field) if line.operands.len() == field.0 + 1 => field,
+        _ => return false,
+    };
+    match parse_operand(line.number, &line.operands[index]) {
+        Ok(Operand::Label(label)) => match symbols.get(&label) {
+            Some(target) => {
+                let offset = *target as i32 - (address as i32 + 1);
+                offset < min || offset > max
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// # The flags of a branch mnemonic, plain BR meaning BRnzp
+fn branch_flags(mnemonic: &str) -> (bool, bool, bool) {
+    let flags = &mnemonic[2..];
+    let always = flags.is_empty();
+    (
+        always || flags.contains('N'),
+        always || flags.contains('Z'),
+        always || flags.contains('P'),
+    )
+}
+
+/// # Number of words the long form of a relaxable statement occupies
+fn relaxed_size(mnemonic: &str) -> u16 {
+    match mnemonic {
+        "JSR" => 4,
+        "LD" | "ST" | "LEA" => 3,
+        _ => match branch_flags(mnemonic) {
+            (true, true, true) => 3,
+            _ => 4,
+        },
+    }
+}
+
+fn branch(n: bool, z: bool, p: bool, offset: i16) -> Instruction {
+    Instruction::Br(BranchArgs {
+        n: BranchFlag(n),
+        z: BranchFlag(z),
+        p: BranchFlag(p),
+        pcoffset9: PcOffset9::new(offset as u16),
+    })
+}
+
+/// # The long form of a relaxable statement, and the instruction it starts with
+fn relax(mnemonic: &str, ops: &Operands, scratch: RegisterName) -> Result<(Vec<u16>, Option<Instruction>), AsmErrorArgs> {
+    let (index, _, _) = relaxable(mnemonic).expect("only relaxable statements are relaxed");
+    ops.expect_count(index + 1)?;
+    let target = ops.value(index)? as u16;
+    let skip = branch(true, true, true, 1);
+    let sequence = match mnemonic {
+        "LD" => vec![
+            Instruction::Ldi(LdiArgs {
+                dr: ops.register(0)?,
+                pcoffset9: PcOffset9::new(1),
+            }),
+            skip,
+        ],
+        "ST" => vec![
+            Instruction::Sti(StiArgs {
+                sr: ops.register(0)?,
+                offset9: PcOffset9::new(1),
+            }),
+            skip,
+        ],
+        "LEA" => vec![
+            Instruction::Ld(LdArgs {
+                dr: ops.register(0)?,
+                pcoffset9: PcOffset9::new(1),
+            }),
+            skip,
+        ],
+        "JSR" => {
+            let words = vec![
+                skip.encode_bits(),
+                target,
+                Instruction::Ld(LdArgs {
+                    dr: RegisterName::R7,
+                    pcoffset9: PcOffset9::new(-2i16 as u16),
+                })
+                .encode_bits(),
+                Instruction::Jsrr(BaseRArgs {
+                    base_r: RegisterName::R7,
+                })
+                .encode_bits(),
+            ];
+            return Ok((words, Some(skip)));
+        }
+        _ => {
+            let (n, z, p) = branch_flags(mnemonic);
+            let mut sequence = Vec::new();
+            if !(n && z && p) {
+                sequence.push(branch(!n, !z, !p, 3));
+            }
+            sequence.push(Instruction::Ld(LdArgs {
+                dr: scratch,
+                pcoffset9: PcOffset9::new(1),
+            }));
+            sequence.push(Instruction::Jmp(BaseRArgs { base_r: scratch }));
+            sequence
+        }
+    };
+    let mut words: Vec<u16> = sequence.iter().map(Instruction::encode_bits).collect();
+    words.push(target);
+    Ok((words, sequence.into_iter().next()))
+}
+
+/// # Records an error, if result is one
+fn keep<T>(errors: &mut Vec<AsmErrorArgs>, result: Result<T, AsmErrorArgs>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(args) => {
+            errors.push(args);
+            None
+        }
+    }
+}
+
+/// # What can be saved of a line that doesn't parse: its label, so uses of the label aren't reported as well
+fn salvage(number: usize, text: &str) -> SourceLine {
+    let label = tokenize(number, text)
+        .ok()
+        .and_then(|tokens| tokens.into_iter().next())
+        .map(|first| Token {
+            text: first.text.trim_end_matches(':').to_string(),
+            column: first.column,
+        })
+        .filter(|first| !is_mnemonic(&first.text) && is_valid_label(&first.text));
+    SourceLine {
+        number,
+        label,
+        op: None,
+        operands: Vec::new(),
+    }
+}
+
+/// # Where statements landed in pass 1, and the problems found placing them
+struct Layout<'a> {
+    symbols: HashMap<String, u16>,
+    symbol_order: Vec<String>,
+    placed: Vec<(&'a SourceLine, u16)>,
+    errors: Vec<AsmErrorArgs>,
+}
+
+/// # Pass 1: lays out every statement and collects the symbol table
+///
+/// Lines in relaxed take the size of their long form, which pass 2 emits in their place.
+fn lay_out<'a>(lines: &'a [SourceLine], broken: &[usize], relaxed: &HashSet<usize>) -> Layout<'a> {
+    let mut errors = Vec::new();
+    let mut symbols = HashMap::new();
+    let mut symbol_order = Vec::new();
+    let mut placed = Vec::new();
+    let mut location: Option<u16> = None;
+    let mut open_orig: Option<(usize, &Token)> = None;
+
+    for line in lines {
+        let mnemonic = line.op.as_ref().map(|op| op.text.to_ascii_uppercase());
+
+        if let Some(".ORIG") = mnemonic.as_deref() {
+            let op = line.op.as_ref().unwrap();
+            if location.is_some() {
+                errors.push(error(line.number, op, AsmErrorKind::NestedOrig));
+            }
+            let ops = Operands {
+                line,
+                symbols: &symbols,
+                address: 0,
+            };
+            let origin = ops
+                .expect_count(1)
+                .and_then(|_| ops.value(0))
+                .and_then(|value| ops.ranged(0, value, ".ORIG address", 0, 0xffff));
+            let origin = keep(&mut errors, origin).unwrap_or(0);
+            location = Some(origin);
+            open_orig = Some((line.number, op));
+            placed.push((line, origin));
+            continue;
+        }
+
+        let address = match (location, &line.label, &mnemonic) {
+            (Some(address), _, _) => address,
+            (None, None, None) => continue,
+            (None, _, _) if broken.contains(&line.number) => continue,
+            (None, label, _) => {
+                let first = label.as_ref().or(line.op.as_ref()).unwrap();
+                errors.push(error(line.number, first, AsmErrorKind::StatementOutsideOrig));
+                continue;
+            }
+        };
+
+        if let Some(label) = &line.label {
+            if symbols.contains_key(&label.text) {
+                let duplicate = AsmErrorKind::DuplicateLabel(label.text.clone());
+                errors.push(error(line.number, label, duplicate));
+            } else {
+                symbols.insert(label.text.clone(), address);
+                symbol_order.push(label.text.clone());
+            }
+        }
+
+        match mnemonic.as_deref() {
+            Some(".END") => {
+                location = None;
+                open_orig = None;
+                placed.push((line, address));
+            }
+            Some(mnemonic) if relaxed.contains(&line.number) => {
+                placed.push((line, address));
+                location = Some(address.wrapping_add(relaxed_size(mnemonic)));
+            }
+            Some(mnemonic) => {
+                // A statement whose size is unknown is reported here, and takes up one word
+                let size = keep(&mut errors, statement_size(line, mnemonic));
+                if size.is_some() {
+                    placed.push((line, address));
+                }
+                location = Some(address.wrapping_add(size.unwrap_or(1)));
+            }
+            None if broken.contains(&line.number) => location = Some(address.wrapping_add(1)),
+            None => {}
+        }
+    }
+
+    if let Some((number, op)) = open_orig {
+        errors.push(error(number, op, AsmErrorKind::MissingEnd));
+    }
+
+    Layout {
+        symbols,
+        symbol_order,
+        placed,
+        errors,
+    }
+}
+
+/// # Assembles source, or reports every error in it, in source order
+///
+/// A line that fails is left out, and assembly carries on with the next one, so one mistake
+/// doesn't hide the others. Lines that don't parse still define their label, and a .ORIG with a
+/// bad address still opens a block, so that neither is reported again by the lines that follow.
+pub fn assemble_all(source: &str) -> Result<Assembly, Vec<AsmErrorArgs>> {
+    assemble_all_with(source, &AsmOptions::default())
+}
+
+/// # assemble_all, with options such as branch relaxation
+pub fn assemble_all_with(source: &str, options: &AsmOptions) -> Result<Assembly, Vec<AsmErrorArgs>> {
+    match assemble_best_effort(source, options) {
+        (assembly, errors) if errors.is_empty() => Ok(assembly),
+        (_, errors) => Err(errors),
+    }
+}
+
+/// # Whatever of source assembles, beside every error in the rest
+///
+/// Statements with an error are left out of statements, and the words they would have filled
+/// before the next statement that did assemble are zero in segments.
+pub fn assemble_best_effort(source: &str, options: &AsmOptions) -> (Assembly, Vec<AsmErrorArgs>) {
+    let mut errors = Vec::new();
+    let mut broken = Vec::new();
+    let mut lines = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let parsed = keep(&mut errors, parse_line(i + 1, text));
+        lines.push(parsed.unwrap_or_else(|| {
+            broken.push(i + 1);
+            salvage(i + 1, text)
+        }));
+    }
+
+    // Relaxing a statement grows it, which can push other targets out of reach, so
+    // layout repeats until nothing more needs relaxing. Statements only ever grow,
+    // so this settles.
+    let mut relaxed = HashSet::new();
+    let mut layout = lay_out(&lines, &broken, &relaxed);
+    if options.relax.is_some() {
+        let edition = options.policy.edition;
+        loop {
+            let far: Vec<usize> = layout
+                .placed
+                .iter()
+                .filter(|(line, address)| {
+                    !relaxed.contains(&line.number) && out_of_reach(line, *address, &layout.symbols, edition)
+                })
+                .map(|(line, _)| line.number)
+                .collect();
+            if far.is_empty() {
+                break;
+            }
+            relaxed.extend(far);
+            layout = lay_out(&lines, &broken, &relaxed);
+        }
+    }
+    let Layout {
+        symbols,
+        symbol_order,
+        placed,
+        errors: layout_errors,
+    } = layout;
+    errors.extend(layout_errors);
+
+    /* Pass 2: every label has an address, so operands can be resolved */
+    let mut segments: Vec<(u16, Vec<u16>)> = Vec::new();
+    let mut statements = Vec::new();
+
+    for (line, address) in placed {
+        let mnemonic = line.op.as_ref().unwrap().text.to_ascii_uppercase();
+        let ops = Operands {
+            line,
+            symbols: &symbols,
+            address,
+        };
+
+        let assembled = match mnemonic.as_str() {
+            ".ORIG" => {
+                segments.push((address, Vec::new()));
+                continue;
+            }
+            ".END" => continue,
+            ".FILL" => ops
+                .expect_count(1)
+                .and_then(|_| ops.value(0))
+                .and_then(|value| ops.ranged(0, value, ".FILL value", -32768, 0xffff))
+                .map(|value| (vec![value], None)),
+            ".BLKW" => statement_size(line, ".BLKW").map(|size| (vec![0; size as usize], None)),
+            ".STRINGZ" => ops.string(0).map(|string| {
+                let mut words: Vec<u16> = string.chars().map(|c| c as u16).collect();
+                words.push(0);
+                (words, None)
+            }),
+            _ if relaxed.contains(&line.number) => {
+                relax(&mnemonic, &ops, options.relax.expect("statements are only relaxed when asked to"))
+            }
+            _ => build_instruction(&mnemonic, &ops)
+                .map(|instruction| (vec![instruction.encode_bits()], Some(instruction))),
+        };
+        let (words, instruction) = match keep(&mut errors, assembled) {
+            Some(assembled) => assembled,
+            None => continue,
+        };
+
+        let (origin, segment) = segments.last_mut().unwrap();
+        segment.resize(address.wrapping_sub(*origin) as usize, 0);
+        segment.extend(&words);
+        statements.push(AssembledStatement {
+            line: line.number,
+            address,
+            words,
+            instruction,
+        });
+    }
+
+    errors.sort_by_key(|e| (e.line, e.column));
+    let assembly = Assembly {
+        segments,
+        symbols: symbol_order
+            .into_iter()
+            .map(|label| {
+                let address = symbols[&label];
+                (label, address)
+            })
+            .collect(),
+        statements,
+    };
+    (assembly, errors)
+}
+
+/// # Assembler errors shown against their source, each with its location and the offending text underlined
+///
+/// error: label 'NOWHERE' is never defined
+///  --> prog.asm:2:4
+///   |
+/// 2 | BR NOWHERE
+///   |    ^^^^^^^
+pub struct Diagnostics<'a> {
+    path: &'a str,
+    source: &'a str,
+    errors: &'a [AsmErrorArgs],
+}
+
+impl<'a> Diagnostics<'a> {
+    pub fn new(path: &'a str, source: &'a str, errors: &'a [AsmErrorArgs]) -> Self {
+        Self {
+            path,
+            source,
+            errors,
+        }
+    }
+}
+
+impl Display for Diagnostics<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let lines: Vec<&str> = self.source.lines().collect();
+        for e in self.errors {
+            let text = lines.get(e.line.wrapping_sub(1)).copied().unwrap_or("");
+            let gutter = e.line.to_string().len();
+            // Tabs stay tabs under the snippet, so the carets line up however wide the terminal draws them
+            let indent: String = text
+                .chars()
+                .take(e.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(f, "error: {}", e.kind)?;
+            writeln!(f, "{:gutter$}--> {}:{}:{}", "", self.path, e.line, e.column, gutter = gutter)?;
+            writeln!(f, "{:gutter$} |", "", gutter = gutter)?;
+            writeln!(f, "{} | {}", e.line, text)?;
+            writeln!(f, "{:gutter$} | {}{}", "", indent, "^".repeat(e.len), gutter = gutter)?;
+            writeln!(f)?;
+        }
+        let plural = if self.errors.len() == 1 { "" } else { "s" };
+        writeln!(
+            f,
+            "could not assemble {} due to {} error{}",
+            self.path,
+            self.errors.len(),
+            plural
+        )
+    }
+}
+
+#[test]
+fn test_assemble_hello() {
+    let source = "
+        .ORIG x3000
+        LEA R0, HELLO     ; string address
+        PUTS
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+HELLO   .STRINGZ \"Hi\"
+        .BLKW 2
+        .FILL xBEEF
+        .END
+    ";
+    let assembly = assemble(source).unwrap();
+    assert_eq!(
+        assembly.segments,
+        vec![(
+            0x3000,
+            vec![0xe004, 0xf022, 0x127f, 0x03fe, 0xf025, 0x48, 0x69, 0, 0, 0, 0xbeef]
+        )]
+    );
+    assert_eq!(assembly.symbol("LOOP"), Some(0x3002));
+    assert_eq!(assembly.symbol("HELLO"), Some(0x3005));
+}
+
+#[test]
+fn test_assemble_every_opcode_round_trips() {
+    let source = "
+        .ORIG x3000
+TOP     ADD R1, R2, R3
+        ADD R1, R2, #-16
+        AND R4, R5, R6
+        AND R4, R5, b1111
+        BRnzp TOP
+        BR TOP
+        JMP R3
+        RET
+        JSR TOP
+        JSRR R4
+        LD R0, TOP
+        LDI R1, TOP
+        LDR R2, R3, #-32
+        LEA R3, TOP
+        NOT R4, R5
+        RTI
+        ST R5, TOP
+        STI R6, TOP
+        STR R7, R0, #31
+        TRAP x20
+        .END
+    ";
+    let assembly = assemble(source).unwrap();
+    for statement in &assembly.statements {
+        let decoded = Instruction::decode_bits(statement.words[0]).unwrap();
+        assert_eq!(decoded.encode_bits(), statement.words[0]);
+        assert!(statement.instruction.is_some());
+    }
+    assert_eq!(assembly.statements[4].words[0], 0x0ffb);
+    assert_eq!(assembly.statements[8].words[0], 0x4ff7);
+}
+
+#[test]
+fn test_assemble_reports_errors() {
+    let undefined = assemble(".ORIG x3000\nBR NOWHERE\n.END");
+    assert!(matches!(
+        undefined,
+        Err(AsmErrorArgs {
+            line: 2,
+            kind: AsmErrorKind::UndefinedLabel(_),
+            ..
+        })
+    ));
+
+    let imm5 = assemble(".ORIG x3000\nADD R0, R0, #16\n.END");
+    assert!(matches!(
+        imm5,
+        Err(AsmErrorArgs {
+            kind: AsmErrorKind::OutOfRange { .. },
+            ..
+        })
+    ));
+
+    let far = assemble(".ORIG x3000\nLD R0, FAR\n.BLKW 300\nFAR .FILL 0\n.END");
+    assert!(far.is_err());
+}
+
+#[test]
+fn test_assemble_all_reports_every_error_with_its_location() {
+    let source = "\t.ORIG x3000
+LOOP\tADD R0, R0, #16
+\tBR NOWHERE
+\tLD R1, FAR
+LOOP\tHALT
+\t.BLKW 300
+FAR\t.FILL 0
+";
+    let errors = assemble_all(source).unwrap_err();
+    let found: Vec<(usize, usize, usize, String)> = errors
+        .iter()
+        .map(|e| (e.line, e.column, e.len, e.kind.to_string()))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            (1, 2, 5, ".ORIG block is missing its .END".to_string()),
+            (2, 18, 3, "16 does not fit in imm5 (must be between -16 and 15)".to_string()),
+            (3, 5, 7, "label 'NOWHERE' is never defined".to_string()),
+            (4, 9, 3, "301 does not fit in PCoffset9 (must be between -256 and 255)".to_string()),
+            (5, 1, 4, "label 'LOOP' is defined more than once".to_string()),
+        ]
+    );
+
+    let rendered = Diagnostics::new("prog.asm", source, &errors[2..3]).to_string();
+    assert_eq!(
+        rendered,
+        "error: label 'NOWHERE' is never defined
+ --> prog.asm:3:5
+  |
+3 | \tBR NOWHERE
+  | \t   ^^^^^^^
+
+could not assemble prog.asm due to 1 error
+"
+    );
+
+    // A line that doesn't parse still defines its label
+    let errors = assemble_all(".ORIG x3000\nTOP ADDD R0, R0, R0\nBR TOP\n.END").unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0].kind, AsmErrorKind::UnknownMnemonic(_)));
+}
+
+#[test]
+fn test_relaxed_statements_reach_far_labels() {
+    use crate::machine::{HaltReason, Machine};
+    use crate::obj::ObjectFile;
+
+    let source = "
+        .ORIG x3000
+        LEA R0, DATA
+        LD R1, DATA
+        JSR DOUBLE
+        ST R1, RESULT
+        BRnz DONE
+        ADD R4, R4, #1
+        BRp DONE
+        ADD R4, R4, #1
+        HALT
+        .BLKW 1100
+DOUBLE  ADD R1, R1, R1
+        RET
+DONE    HALT
+DATA    .FILL 5
+RESULT  .BLKW 1
+        .END
+    ";
+    let errors = assemble_all(source).unwrap_err();
+    assert_eq!(errors.len(), 6);
+
+    let mut options = AsmOptions {
+        relax: Some(RegisterName::R5),
+        ..AsmOptions::default()
+    };
+    let assembly = assemble_all_with(source, &options).unwrap();
+    let sizes: Vec<usize> = assembly.statements.iter().map(|s| s.words.len()).collect();
+    assert_eq!(&sizes[..8], &[3, 3, 4, 3, 4, 1, 4, 1]);
+
+    let mut machine = Machine::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap();
+    assert_eq!(machine.run(10_000), HaltReason::Halted);
+    let data = assembly.symbol("DATA").unwrap();
+    assert_eq!(machine.register(RegisterName::R0), data);
+    assert_eq!(machine.memory().read(assembly.symbol("RESULT").unwrap()), 10);
+    assert_eq!(machine.register(RegisterName::R4), 1);
+    assert_eq!(machine.register(RegisterName::R5), assembly.symbol("DONE").unwrap());
+
+    // The long form of LEA sets the condition codes, which the third edition's LEA doesn't
+    options.policy.edition = Edition::Third;
+    let errors = assemble_all_with(source, &options).unwrap_err();
+    assert_eq!((errors.len(), errors[0].line), (1, 3));
+}
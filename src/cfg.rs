@@ -0,0 +1,322 @@
+use crate::disasm::{word_at, Canonical, Flow};
+use crate::lrc3::*;
+use crate::obj::ObjectFile;
+use core::fmt::{Display, Error, Formatter};
+use std::collections::{BTreeMap, BTreeSet};
+
+/* Basic blocks and the control-flow graph between them
+ *
+ * A block is a run of code that is only entered at its first word and only
+ * left after its last. Blocks end at branches, jumps, calls and returns, and
+ * just before any word something else branches to. Edges come from the
+ * instruction that ends a block:
+ *
+ *  BRnzp              Jump to the target
+ *  BRz, BRnp, ...     Taken to the target, and NotTaken to the next word
+ *  JSR, TRAP          Call to the routine, if it is in the program, and
+ *                     FallThrough to the next word, where it returns to
+ *  JSRR               FallThrough only, since the target is in a register
+ *  JMP, RET, RTI      nothing that can be known without running
+ *
+ * and a block that ends only because the next word starts another falls
+ * through to it. Each subroutine holds the blocks reached from its entry
+ * without following calls. Dot draws the graph for Graphviz, with each
+ * subroutine in a box of its own.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Jump,
+    Taken,
+    NotTaken,
+    FallThrough,
+    Call,
+}
+
+#[derive(Debug)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub words: Vec<u16>,
+    /// Where control goes after the last word, to the start of another block
+    pub edges: Vec<(Edge, u16)>,
+}
+
+#[derive(Debug)]
+pub struct Subroutine {
+    pub entry: u16,
+    /// The start of each of its blocks, in address order
+    pub blocks: Vec<u16>,
+}
+
+#[derive(Debug)]
+pub struct Cfg {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    pub subroutines: Vec<Subroutine>,
+    /// How the words of the blocks were decoded
+    pub policy: DecodePolicy,
+    /// Labels made up for targets by the trace, for blocks without a symbol
+    pub labels: Vec<(String, u16)>,
+}
+
+/// # The edges out of instruction at address, and whether it ends its block
+fn exits(instruction: &Instruction, address: u16, objects: &[ObjectFile]) -> (Vec<(Edge, u16)>, bool) {
+    let next = address.wrapping_add(1);
+    let target = instruction.target(address);
+    match (instruction.control_flow(), instruction) {
+        (ControlFlow::FallThrough, _) => (vec![], false),
+        (ControlFlow::Jump, _) => (target.map(|to| (Edge::Jump, to)).into_iter().collect(), true),
+        (ControlFlow::Conditional, _) => {
+            let edges = target.map(|to| (Edge::Taken, to)).into_iter();
+            (edges.chain(Some((Edge::NotTaken, next))).collect(), true)
+        }
+        (ControlFlow::Call, _) => {
+            let edges = target.map(|to| (Edge::Call, to)).into_iter();
+            (edges.chain(Some((Edge::FallThrough, next))).collect(), true)
+        }
+        (ControlFlow::Trap, Instruction::Trap(args)) => {
+            let vector = args.trapvect8.masked();
+            let mut edges = Vec::new();
+            if let Some(routine) = word_at(objects, vector) {
+                edges.push((Edge::Call, routine));
+            }
+            match vector {
+                // HALT doesn't come back
+                0x25 => (edges, true),
+                _ if edges.is_empty() => (edges, false),
+                _ => {
+                    edges.push((Edge::FallThrough, next));
+                    (edges, true)
+                }
+            }
+        }
+        _ => (vec![], true),
+    }
+}
+
+impl Cfg {
+    /// # The graph of the code reachable from the entry points, the first of which is the main program
+    pub fn build(objects: &[ObjectFile], entries: &[u16], policy: &DecodePolicy) -> Self {
+        let flow = Flow::trace(objects, entries, policy);
+        let code = &flow.code;
+        let decoded: BTreeMap<u16, (Vec<(Edge, u16)>, bool)> = code
+            .iter()
+            .filter_map(|address| {
+                let (instruction, _) = Instruction::decode_with(word_at(objects, *address)?, policy).ok()?;
+                let (edges, ends) = exits(&instruction, *address, objects);
+                let edges = edges.into_iter().filter(|(_, to)| code.contains(to)).collect();
+                Some((*address, (edges, ends)))
+            })
+            .collect();
+
+        let mut leaders: BTreeSet<u16> = entries.iter().copied().filter(|a| code.contains(a)).collect();
+        for (address, (edges, ends)) in &decoded {
+            leaders.extend(edges.iter().map(|(_, to)| *to));
+            if *ends {
+                leaders.insert(address.wrapping_add(1));
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut current: Option<BasicBlock> = None;
+        for (address, (edges, ends)) in &decoded {
+            let mut block = match current.take() {
+                Some(block) if !leaders.contains(address) => block,
+                _ => BasicBlock {
+                    start: *address,
+                    words: Vec::new(),
+                    edges: Vec::new(),
+                },
+            };
+            block.words.push(word_at(objects, *address).unwrap());
+            let next = address.wrapping_add(1);
+            if *ends {
+                block.edges = edges.clone();
+                blocks.insert(block.start, block);
+            } else if !code.contains(&next) || leaders.contains(&next) {
+                if code.contains(&next) {
+                    block.edges.push((Edge::FallThrough, next));
+                }
+                blocks.insert(block.start, block);
+            } else {
+                current = Some(block);
+            }
+        }
+        if let Some(block) = current {
+            blocks.insert(block.start, block);
+        }
+
+        // The main program first, then every routine called, each owning what it reaches first
+        let mut entry_points: Vec<u16> = entries.iter().copied().filter(|a| blocks.contains_key(a)).collect();
+        for block in blocks.values() {
+            for (edge, to) in &block.edges {
+                if *edge == Edge::Call && !entry_points.contains(to) {
+                    entry_points.push(*to);
+                }
+            }
+        }
+        let mut owned = BTreeSet::new();
+        let mut subroutines = Vec::new();
+        for entry in entry_points {
+            let mut reached = Vec::new();
+            let mut pending = vec![entry];
+            while let Some(start) = pending.pop() {
+                if !owned.insert(start) {
+                    continue;
+                }
+                reached.push(start);
+                let edges = blocks.get(&start).map(|b: &BasicBlock| &b.edges[..]).unwrap_or(&[]);
+                for (edge, to) in edges {
+                    if *edge != Edge::Call {
+                        pending.push(*to);
+                    }
+                }
+            }
+            if reached.is_empty() {
+                continue;
+            }
+            reached.sort_unstable();
+            subroutines.push(Subroutine {
+                entry,
+                blocks: reached,
+            });
+        }
+
+        Self {
+            blocks,
+            subroutines,
+            policy: *policy,
+            labels: flow.labels,
+        }
+    }
+
+    /// # The block holding address
+    pub fn block(&self, address: u16) -> Option<&BasicBlock> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| (address - block.start) < block.words.len() as u16)
+    }
+}
+
+/// # A Cfg in Graphviz's DOT language, each block listing its instructions
+pub struct Dot<'a> {
+    cfg: &'a Cfg,
+    symbols: Vec<(String, u16)>,
+}
+
+impl<'a> Dot<'a> {
+    /// # Symbols name blocks and targets, before the labels the trace made up
+    pub fn new(cfg: &'a Cfg, symbols: &[(String, u16)]) -> Self {
+        let mut names = symbols.to_vec();
+        names.extend(cfg.labels.iter().cloned());
+        Self { cfg, symbols: names }
+    }
+
+    fn name(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, a)| *a == address)
+            .map(|(label, _)| label.as_str())
+    }
+
+    fn block(&self, f: &mut Formatter<'_>, block: &BasicBlock, indent: &str) -> Result<(), Error> {
+        let mut text = String::new();
+        if let Some(label) = self.name(block.start) {
+            text.push_str(label);
+            text.push_str(":\\l");
+        }
+        for (offset, bits) in block.words.iter().enumerate() {
+            let address = block.start.wrapping_add(offset as u16);
+            let (instruction, _) = Instruction::decode_with(*bits, &self.cfg.policy).expect("blocks only hold code");
+            let line = format!("x{:04X}  {}", address, Canonical::new(&instruction, address, &self.symbols));
+            text.push_str(&line.replace('\\', "\\\\").replace('"', "\\\""));
+            text.push_str("\\l");
+        }
+        writeln!(f, "{}b{:04X} [label=\"{}\"];", indent, block.start, text)
+    }
+}
+
+impl Display for Dot<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "digraph cfg {{")?;
+        writeln!(f, "    node [shape=box, fontname=\"monospace\"];")?;
+        let mut drawn = BTreeSet::new();
+        for subroutine in &self.cfg.subroutines {
+            writeln!(f, "    subgraph cluster_{:04X} {{", subroutine.entry)?;
+            match self.name(subroutine.entry) {
+                Some(label) => writeln!(f, "        label=\"{}\";", label)?,
+                None => writeln!(f, "        label=\"x{:04X}\";", subroutine.entry)?,
+            }
+            for start in &subroutine.blocks {
+                self.block(f, &self.cfg.blocks[start], "        ")?;
+                drawn.insert(*start);
+            }
+            writeln!(f, "    }}")?;
+        }
+        for block in self.cfg.blocks.values().filter(|b| !drawn.contains(&b.start)) {
+            self.block(f, block, "    ")?;
+        }
+        for block in self.cfg.blocks.values() {
+            for (edge, to) in &block.edges {
+                let style = match edge {
+                    Edge::Jump | Edge::FallThrough => "",
+                    Edge::Taken => " [label=\"taken\"]",
+                    Edge::NotTaken => " [label=\"not taken\"]",
+                    Edge::Call => " [style=dashed]",
+                };
+                writeln!(f, "    b{:04X} -> b{:04X}{};", block.start, to, style)?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[test]
+fn test_cfg_splits_blocks_at_branches_and_calls() {
+    use crate::asm::assemble;
+
+    let assembly = assemble(
+        "
+        .ORIG x3000
+        AND R1, R1, #0
+        ADD R1, R1, #3
+LOOP    JSR TICK
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+TICK    ADD R2, R2, #1
+        BRz DONE
+        ADD R2, R2, #1
+DONE    RET
+        .END
+    ",
+    )
+    .unwrap();
+    let objects = ObjectFile::from_assembly(&assembly);
+    let cfg = Cfg::build(&objects, &[0x3000], &DecodePolicy::default());
+
+    let sizes: Vec<(u16, usize)> = cfg.blocks.values().map(|b| (b.start, b.words.len())).collect();
+    assert_eq!(
+        sizes,
+        vec![(0x3000, 2), (0x3002, 1), (0x3003, 2), (0x3005, 1), (0x3006, 2), (0x3008, 1), (0x3009, 1)]
+    );
+    let edges = |start: u16| cfg.blocks[&start].edges.clone();
+    assert_eq!(edges(0x3000), vec![(Edge::FallThrough, 0x3002)]);
+    assert_eq!(edges(0x3002), vec![(Edge::Call, 0x3006), (Edge::FallThrough, 0x3003)]);
+    assert_eq!(edges(0x3003), vec![(Edge::Taken, 0x3002), (Edge::NotTaken, 0x3005)]);
+    assert_eq!(edges(0x3005), vec![]);
+    assert_eq!(edges(0x3006), vec![(Edge::Taken, 0x3009), (Edge::NotTaken, 0x3008)]);
+    assert_eq!(edges(0x3008), vec![(Edge::FallThrough, 0x3009)]);
+    assert_eq!(edges(0x3009), vec![]);
+    assert_eq!(cfg.subroutines[0].blocks, vec![0x3000, 0x3002, 0x3003, 0x3005]);
+    assert_eq!(cfg.subroutines[1].entry, 0x3006);
+    assert_eq!(cfg.block(0x3004).unwrap().start, 0x3003);
+
+    let dot = Dot::new(&cfg, &assembly.symbols).to_string();
+    assert!(dot.starts_with("digraph cfg {\n"));
+    assert!(dot.contains("    subgraph cluster_3006 {\n        label=\"TICK\";\n"));
+    assert!(dot.contains("        b3003 [label=\"x3003  ADD R1, R1, #-1\\lx3004  BRp LOOP\\l\"];\n"));
+    assert!(dot.contains("    b3002 -> b3006 [style=dashed];\n"));
+    assert!(dot.contains("    b3003 -> b3005 [label=\"not taken\"];\n"));
+}
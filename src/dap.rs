@@ -0,0 +1,698 @@
+use crate::asm::{assemble_all_with, parse_number, AsmOptions, Assembly};
+use crate::debugger::{Debugger, Stop};
+use crate::devices::{Display, Keyboard};
+use crate::json::{object, read_message, write_message, Json};
+use crate::lrc3::*;
+use crate::machine::{HaltReason, Machine};
+use crate::obj::ObjectFile;
+use crate::sym::SymbolTable;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+/* A Debug Adapter Protocol server
+ *
+ * Editors speak DAP to the adapter over stdin and stdout, so the program's
+ * display output is sent back as output events rather than printed. Requests
+ * are read on their own thread: that way a pause request can stop a program
+ * that is still running, and every other request waits its turn.
+ *
+ * There is one thread, with one stack frame at the PC, named after the nearest
+ * label from the .asm source or the .sym beside an .obj. Breakpoints are set by
+ * source line in the launched .asm file, and land on the first line at or
+ * after the requested one that assembled to memory. Memory references are
+ * LC-3 word addresses, and reading n bytes returns big-endian byte pairs, as
+ * the gdb stub does. Evaluating an expression in the debug console runs it as
+ * a command of the text debugger. Launch takes "permissive" and "edition"
+ * arguments, as lrc3 run takes --permissive and --edition, falling back on
+ * the server's own decode policy.
+ */
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+
+/// # What launch loaded: the debugger, and for an .asm program, its path and statements
+struct Session {
+    debugger: Debugger,
+    source: Option<(String, Assembly)>,
+    stop_on_entry: bool,
+}
+
+impl Session {
+    /// # The source line of the statement at address, if the program came from an .asm file
+    fn line_of(&self, address: u16) -> Option<usize> {
+        let (_, assembly) = self.source.as_ref()?;
+        assembly
+            .statements
+            .iter()
+            .find(|statement| {
+                let start = statement.address;
+                let len = statement.words.len() as u16;
+                address.wrapping_sub(start) < len
+            })
+            .map(|statement| statement.line)
+    }
+
+    /// # The first address of the first statement at or after line that occupies memory
+    fn address_of(&self, line: usize) -> Option<(usize, u16)> {
+        let (_, assembly) = self.source.as_ref()?;
+        assembly
+            .statements
+            .iter()
+            .filter(|statement| statement.line >= line && !statement.words.is_empty())
+            .min_by_key(|statement| statement.line)
+            .map(|statement| (statement.line, statement.address))
+    }
+
+    /// # The nearest label at or before address, as a name for the stack frame
+    fn frame_name(&self, address: u16) -> String {
+        self.debugger
+            .symbols()
+            .iter()
+            .filter(|(_, at)| *at <= address)
+            .max_by_key(|(_, at)| *at)
+            .map(|(label, at)| match address - at {
+                0 => label.clone(),
+                offset => format!("{}+{}", label, offset),
+            })
+            .unwrap_or_else(|| format!("x{:04X}", address))
+    }
+}
+
+pub struct DapServer {
+    seq: i64,
+    session: Option<Session>,
+    pause_requested: Arc<AtomicBool>,
+    policy: DecodePolicy,
+}
+
+impl Default for DapServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> Self {
+        Self {
+            seq: 0,
+            session: None,
+            pause_requested: Arc::new(AtomicBool::new(false)),
+            policy: DecodePolicy::default(),
+        }
+    }
+
+    /// # The policy launches assemble and run with, unless their arguments say otherwise
+    pub fn set_decode_policy(&mut self, policy: DecodePolicy) {
+        self.policy = policy;
+    }
+
+    /// # Answers requests from input until the client disconnects or input ends
+    pub fn serve(
+        &mut self,
+        mut input: impl BufRead + Send + 'static,
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let (requests, received) = mpsc::channel();
+        let pause_requested = self.pause_requested.clone();
+        std::thread::spawn(move || {
+            while let Ok(Some(body)) = read_message(&mut input) {
+                if let Ok(request) = Json::parse(&body) {
+                    if request.get("command").as_str() == Some("pause") {
+                        pause_requested.store(true, Ordering::SeqCst);
+                    }
+                    if requests.send(request).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        for request in received {
+            if !self.handle(&request, output)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// # Answers one request, returning false once the session is over
+    fn handle(&mut self, request: &Json, output: &mut impl Write) -> io::Result<bool> {
+        let command = request.get("command").as_str().unwrap_or("");
+        let arguments = request.get("arguments");
+        let answer = match command {
+            "initialize" => Ok(Some(object(vec![
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsReadMemoryRequest", true.into()),
+                ("supportsEvaluateForHovers", false.into()),
+            ]))),
+            "launch" => self.launch(arguments).map(|_| None),
+            "disconnect" => {
+                self.respond(output, request, Ok(None))?;
+                return Ok(false);
+            }
+            "configurationDone"
+            | "setBreakpoints"
+            | "threads"
+            | "stackTrace"
+            | "scopes"
+            | "variables"
+            | "readMemory"
+            | "evaluate"
+            | "continue"
+            | "next"
+            | "stepIn"
+            | "stepOut"
+            | "pause" => match self.session.as_mut() {
+                Some(session) => Self::answer(session, command, arguments),
+                None => Err("no program has been launched".to_string()),
+            },
+            _ => Err(format!("unsupported request {:?}", command)),
+        };
+        self.respond(output, request, answer)?;
+
+        match command {
+            "launch" if self.session.is_some() => self.event(output, "initialized", None)?,
+            "configurationDone" => {
+                if let Some(stop_on_entry) = self.session.as_ref().map(|s| s.stop_on_entry) {
+                    if stop_on_entry {
+                        self.stopped(output, "entry", None)?;
+                    } else {
+                        self.run(output, Debugger::run_to_breakpoint)?;
+                    }
+                }
+            }
+            "continue" => self.run(output, Debugger::run_to_breakpoint)?,
+            "next" => self.run(output, Debugger::step_over)?,
+            "stepIn" => self.run(output, |debugger| debugger.step(1))?,
+            "stepOut" => self.run(output, Debugger::finish)?,
+            // A run that was going when the pause arrived has already taken it, and sent its stopped
+            // event. Otherwise nothing ran, and the pause mustn't be left to stop the next run.
+            "pause" if self.pause_requested.swap(false, Ordering::SeqCst) => self.stopped(output, "pause", None)?,
+            "evaluate" => self.flush_output(output)?,
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<(), String> {
+        let program = arguments
+            .get("program")
+            .as_str()
+            .ok_or("launch needs a program")?
+            .to_string();
+        let mut policy = self.policy;
+        if let Some(permissive) = arguments.get("permissive").as_bool() {
+            policy.strictness = match permissive {
+                true => Strictness::Permissive,
+                false => Strictness::Strict,
+            };
+        }
+        policy.edition = match arguments.get("edition").as_i64() {
+            None => policy.edition,
+            Some(2) => Edition::Second,
+            Some(3) => Edition::Third,
+            Some(edition) => return Err(format!("edition {} is not 2 or 3", edition)),
+        };
+        let (objects, source) = if program.ends_with(".asm") {
+            let text = std::fs::read_to_string(&program).map_err(|e| e.to_string())?;
+            let options = AsmOptions {
+                policy,
+                ..AsmOptions::default()
+            };
+            let assembly = assemble_all_with(&text, &options).map_err(|errors| errors[0].to_string())?;
+            (ObjectFile::from_assembly(&assembly), Some((program.clone(), assembly)))
+        } else {
+            let object = ObjectFile::read(&program).map_err(|e| e.to_string())?;
+            (vec![object], None)
+        };
+        let symbols = match &source {
+            Some((_, assembly)) => assembly.symbols.clone(),
+            // An .obj from lc3as comes with its labels in a .sym beside it
+            None => match Path::new(&program).with_extension("sym") {
+                beside if beside.exists() => {
+                    SymbolTable::read(beside).map_err(|e| e.to_string())?.symbols
+                }
+                _ => Vec::new(),
+            },
+        };
+        let mut machine = Machine::from_objects(&objects).map_err(|e| e.to_string())?;
+        machine.set_decode_policy(policy);
+        if let Some(input) = arguments.get("input").as_str() {
+            if let Some(keyboard) = machine.devices().get::<Keyboard>() {
+                keyboard.type_bytes(input.as_bytes());
+            }
+        }
+        let mut debugger = Debugger::new(machine, symbols);
+        // The client decides when to give up on a program, by pausing it
+        debugger.set_step_limit(usize::MAX);
+        let pause_requested = self.pause_requested.clone();
+        debugger.set_interrupt(move || pause_requested.swap(false, Ordering::SeqCst));
+        self.session = Some(Session {
+            debugger,
+            source,
+            stop_on_entry: arguments.get("stopOnEntry").as_bool().unwrap_or(false),
+        });
+        Ok(())
+    }
+
+    /// # The body of the response to a request about a launched program
+    fn answer(session: &mut Session, command: &str, arguments: &Json) -> Result<Option<Json>, String> {
+        let machine = session.debugger.machine();
+        match command {
+            "setBreakpoints" => {
+                let lines: Vec<i64> = arguments
+                    .get("breakpoints")
+                    .elements()
+                    .iter()
+                    .filter_map(|breakpoint| breakpoint.get("line").as_i64())
+                    .collect();
+                let mut placed = Vec::new();
+                let mut addresses = Vec::new();
+                for line in lines {
+                    placed.push(match session.address_of(line.max(0) as usize) {
+                        Some((line, address)) => {
+                            addresses.push(address);
+                            object(vec![
+                                ("verified", true.into()),
+                                ("line", (line as i64).into()),
+                            ])
+                        }
+                        None => object(vec![
+                            ("verified", false.into()),
+                            ("line", line.into()),
+                            ("message", "no code at or after this line".into()),
+                        ]),
+                    });
+                }
+                // Only the launched source has lines, so its breakpoints are all there are
+                let breakpoints = session.debugger.breakpoints_mut();
+                breakpoints.clear();
+                breakpoints.extend(addresses);
+                Ok(Some(object(vec![("breakpoints", placed.into())])))
+            }
+            "threads" => Ok(Some(object(vec![(
+                "threads",
+                vec![object(vec![
+                    ("id", THREAD_ID.into()),
+                    ("name", "LC-3".into()),
+                ])]
+                .into(),
+            )]))),
+            "stackTrace" => {
+                let pc = machine.pc();
+                let mut frame = vec![
+                    ("id", 1.into()),
+                    ("name", session.frame_name(pc).into()),
+                    ("instructionPointerReference", format!("0x{:04X}", pc).into()),
+                    ("column", 1.into()),
+                ];
+                match (&session.source, session.line_of(pc)) {
+                    (Some((path, _)), Some(line)) => {
+                        frame.push(("line", (line as i64).into()));
+                        frame.push(("source", object(vec![("path", path.as_str().into())])));
+                    }
+                    _ => frame.push(("line", 0.into())),
+                }
+                Ok(Some(object(vec![
+                    ("stackFrames", vec![object(frame)].into()),
+                    ("totalFrames", 1.into()),
+                ])))
+            }
+            "scopes" => Ok(Some(object(vec![(
+                "scopes",
+                vec![object(vec![
+                    ("name", "Registers".into()),
+                    ("variablesReference", REGISTERS_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ])]
+                .into(),
+            )]))),
+            "variables" => {
+                let mut variables = Vec::new();
+                if arguments.get("variablesReference").as_i64() == Some(REGISTERS_REFERENCE) {
+                    for index in 0..8 {
+                        let value = machine.register(RegisterName::from_bits(index));
+                        variables.push(word_variable(&format!("R{}", index), value));
+                    }
+                    variables.push(word_variable("PC", machine.pc()));
+                    let (n, z, p) = machine.nzp();
+                    let cc: String = [(n, 'n'), (z, 'z'), (p, 'p')]
+                        .iter()
+                        .filter(|(set, _)| *set)
+                        .map(|(_, flag)| *flag)
+                        .collect();
+                    variables.push(object(vec![
+                        ("name", "CC".into()),
+                        ("value", cc.into()),
+                        ("variablesReference", 0.into()),
+                    ]));
+                }
+                Ok(Some(object(vec![("variables", variables.into())])))
+            }
+            "readMemory" => {
+                let reference = arguments.get("memoryReference").as_str().unwrap_or("");
+                let base = parse_number(reference)
+                    .filter(|address| (0..=u16::MAX as i32).contains(address))
+                    .ok_or_else(|| format!("{:?} is not an address", reference))?;
+                let offset = arguments.get("offset").as_i64().unwrap_or(0);
+                let count = arguments.get("count").as_i64().unwrap_or(0).clamp(0, 0x20000) as usize;
+                // Byte offsets that split a word start at the word that holds them
+                let start = (base as i64 + offset.div_euclid(2)) as u16;
+                let bytes: Vec<u8> = (0..count)
+                    .map(|index| {
+                        let byte = offset.rem_euclid(2) as usize + index;
+                        let word = machine.peek(start.wrapping_add((byte / 2) as u16));
+                        word.to_be_bytes()[byte % 2]
+                    })
+                    .collect();
+                Ok(Some(object(vec![
+                    ("address", format!("0x{:04X}", start).into()),
+                    ("data", base64(&bytes).into()),
+                ])))
+            }
+            "evaluate" => {
+                let expression = arguments.get("expression").as_str().unwrap_or("");
+                match session.debugger.execute(expression) {
+                    Ok(result) => Ok(Some(object(vec![
+                        ("result", result.trim_end().into()),
+                        ("variablesReference", 0.into()),
+                    ]))),
+                    Err(e) => Err(e.to_string()),
+                }
+            }
+            "continue" => Ok(Some(object(vec![("allThreadsContinued", true.into())]))),
+            _ => Ok(None),
+        }
+    }
+
+    /// # Resumes with how, then tells the client where and why the program stopped
+    fn run(&mut self, output: &mut impl Write, how: impl FnOnce(&mut Debugger) -> Stop) -> io::Result<()> {
+        let stop = match self.session.as_mut() {
+            Some(session) => how(&mut session.debugger),
+            None => return Ok(()),
+        };
+        self.flush_output(output)?;
+        match stop {
+            Stop::Stepped => self.stopped(output, "step", None),
+            Stop::Breakpoint => self.stopped(output, "breakpoint", None),
+            Stop::Interrupted => self.stopped(output, "pause", None),
+            Stop::StepLimit => self.stopped(output, "pause", Some("step limit reached".to_string())),
+            Stop::Halted(HaltReason::IllegalInstruction { pc, bits }) => self.stopped(
+                output,
+                "exception",
+                Some(format!("Illegal instruction x{:04X} at x{:04X}", bits, pc)),
+            ),
+            Stop::Halted(_) => {
+                self.event(output, "exited", Some(object(vec![("exitCode", 0.into())])))?;
+                self.event(output, "terminated", None)
+            }
+        }
+    }
+
+    /// # Sends what the program displayed since the last flush as an output event
+    fn flush_output(&mut self, output: &mut impl Write) -> io::Result<()> {
+        let displayed = match self.session.as_mut() {
+            Some(session) => session
+                .debugger
+                .machine_mut()
+                .devices_mut()
+                .get_mut::<Display>()
+                .map(Display::take_output)
+                .unwrap_or_default(),
+            None => return Ok(()),
+        };
+        if displayed.is_empty() {
+            return Ok(());
+        }
+        let text = String::from_utf8_lossy(&displayed).into_owned();
+        self.event(
+            output,
+            "output",
+            Some(object(vec![("category", "stdout".into()), ("output", text.into())])),
+        )
+    }
+
+    fn stopped(&mut self, output: &mut impl Write, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text", text.into()));
+        }
+        self.event(output, "stopped", Some(object(body)))
+    }
+
+    fn event(&mut self, output: &mut impl Write, event: &str, body: Option<Json>) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![
+            ("seq", self.seq.into()),
+            ("type", "event".into()),
+            ("event", event.into()),
+        ];
+        if let Some(body) = body {
+            message.push(("body", body));
+        }
+        write_message(output, &object(message))
+    }
+
+    fn respond(
+        &mut self,
+        output: &mut impl Write,
+        request: &Json,
+        answer: Result<Option<Json>, String>,
+    ) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![
+            ("seq", self.seq.into()),
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+            ("success", answer.is_ok().into()),
+        ];
+        match answer {
+            Ok(Some(body)) => message.push(("body", body)),
+            Ok(None) => {}
+            Err(text) => message.push(("message", text.into())),
+        }
+        write_message(output, &object(message))
+    }
+}
+
+/// # A register's value in hex and as a signed number, with a memory reference to what it points at
+fn word_variable(name: &str, value: u16) -> Json {
+    object(vec![
+        ("name", name.into()),
+        ("value", format!("x{:04X} ({})", value, value as i16).into()),
+        ("memoryReference", format!("0x{:04X}", value).into()),
+        ("variablesReference", 0.into()),
+    ])
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |group, (index, byte)| group | (*byte as u32) << (16 - 8 * index));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                out.push(ALPHABET[(group >> (18 - 6 * index) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_dap_server_breaks_on_source_lines_and_shows_state() {
+    let path = std::env::temp_dir().join(format!("lrc3-dap-{}.asm", std::process::id()));
+    std::fs::write(
+        &path,
+        "        .ORIG x3000
+        AND R0, R0, #0
+        ; a comment, so the breakpoint on it moves down
+LOOP    ADD R0, R0, #1
+        ADD R1, R0, #-3
+        BRn LOOP
+        LEA R0, MSG
+        PUTS
+        HALT
+MSG     .STRINGZ \"hi\"
+        .END
+",
+    )
+    .unwrap();
+    let path_text = path.to_str().unwrap();
+    let requests = [
+        object(vec![("command", "initialize".into())]),
+        object(vec![
+            ("command", "launch".into()),
+            (
+                "arguments",
+                object(vec![("program", path_text.into()), ("stopOnEntry", true.into())]),
+            ),
+        ]),
+        object(vec![
+            ("command", "setBreakpoints".into()),
+            (
+                "arguments",
+                object(vec![(
+                    "breakpoints",
+                    vec![object(vec![("line", 3.into())])].into(),
+                )]),
+            ),
+        ]),
+        object(vec![("command", "configurationDone".into())]),
+        object(vec![("command", "continue".into())]),
+        object(vec![("command", "stackTrace".into())]),
+        object(vec![
+            ("command", "variables".into()),
+            ("arguments", object(vec![("variablesReference", 1.into())])),
+        ]),
+        object(vec![
+            ("command", "readMemory".into()),
+            (
+                "arguments",
+                object(vec![("memoryReference", "0x3000".into()), ("count", 4.into())]),
+            ),
+        ]),
+        object(vec![
+            ("command", "setBreakpoints".into()),
+            ("arguments", object(vec![("breakpoints", Vec::new().into())])),
+        ]),
+        object(vec![("command", "continue".into())]),
+        object(vec![("command", "disconnect".into())]),
+    ];
+    let mut input = Vec::new();
+    for (seq, request) in requests.iter().enumerate() {
+        let mut request = match request.clone() {
+            Json::Object(members) => members,
+            _ => unreachable!(),
+        };
+        request.insert(0, ("seq".to_string(), (seq as i64 + 1).into()));
+        request.insert(1, ("type".to_string(), "request".into()));
+        write_message(&mut input, &Json::Object(request)).unwrap();
+    }
+
+    let mut output = Vec::new();
+    DapServer::new()
+        .serve(io::Cursor::new(input), &mut output)
+        .unwrap();
+    std::fs::remove_file(&path).ok();
+
+    let mut reader = io::Cursor::new(output);
+    let mut messages = Vec::new();
+    while let Some(body) = read_message(&mut reader).unwrap() {
+        messages.push(Json::parse(&body).unwrap());
+    }
+    let response = |command: &str, nth: usize| {
+        messages
+            .iter()
+            .filter(|m| m.get("type").as_str() == Some("response"))
+            .filter(|m| m.get("command").as_str() == Some(command))
+            .nth(nth)
+            .unwrap()
+            .get("body")
+            .clone()
+    };
+    let events: Vec<&str> = messages
+        .iter()
+        .filter_map(|m| m.get("event").as_str())
+        .collect();
+    assert_eq!(
+        events,
+        ["initialized", "stopped", "stopped", "output", "exited", "terminated"]
+    );
+    assert!(messages
+        .iter()
+        .all(|m| m.get("success").as_bool() != Some(false)));
+
+    // Line 3 is a comment, so the breakpoint lands on LOOP, which first stops with R0 still 0
+    let placed = response("setBreakpoints", 0).get("breakpoints").elements()[0].clone();
+    assert_eq!(placed.get("line").as_i64(), Some(4));
+    let frame = response("stackTrace", 0).get("stackFrames").elements()[0].clone();
+    assert_eq!(frame.get("name").as_str(), Some("LOOP"));
+    assert_eq!(frame.get("line").as_i64(), Some(4));
+    let variables = response("variables", 0);
+    let variables = variables.get("variables").elements();
+    assert_eq!(variables[0].get("value").as_str(), Some("x0000 (0)"));
+    assert_eq!(variables[8].get("value").as_str(), Some("x3001 (12289)"));
+    assert_eq!(variables[9].get("value").as_str(), Some("z"));
+    // AND R0, R0, #0 is x5020, ADD R0, R0, #1 is x1021
+    assert_eq!(response("readMemory", 0).get("data").as_str(), Some("UCAQIQ=="));
+
+    let output = messages
+        .iter()
+        .find(|m| m.get("event").as_str() == Some("output"))
+        .unwrap();
+    assert_eq!(
+        output.get("body").get("output").as_str(),
+        Some("hi\n--- Halting the LC-3 ---\n")
+    );
+}
+
+#[test]
+fn test_dap_server_reports_a_pause_once() {
+    let path = std::env::temp_dir().join(format!("lrc3-dap-pause-{}.asm", std::process::id()));
+    std::fs::write(&path, ".ORIG x3000\nLOOP BRnzp LOOP\n.END\n").unwrap();
+    let mut server = DapServer::new();
+    let mut output = Vec::new();
+    let launch = object(vec![
+        ("command", "launch".into()),
+        ("arguments", object(vec![("program", path.to_str().unwrap().into())])),
+    ]);
+    server.handle(&launch, &mut output).unwrap();
+    std::fs::remove_file(&path).ok();
+    let pause = object(vec![("command", "pause".into())]);
+    let stopped = |output: &[u8]| String::from_utf8_lossy(output).matches("\"stopped\"").count();
+
+    // Paused while stopped: the reader thread has raised the flag, and nothing has taken it
+    server.pause_requested.store(true, Ordering::SeqCst);
+    server.handle(&pause, &mut output).unwrap();
+    assert_eq!(stopped(&output), 1);
+
+    // Paused while running: the run took the flag and already said it stopped
+    server.pause_requested.store(true, Ordering::SeqCst);
+    server.handle(&object(vec![("command", "continue".into())]), &mut output).unwrap();
+    assert_eq!(stopped(&output), 2);
+    server.handle(&pause, &mut output).unwrap();
+    assert_eq!(stopped(&output), 2);
+}
+
+#[test]
+fn test_dap_server_launches_with_a_decode_policy() {
+    let path = std::env::temp_dir().join(format!("lrc3-dap-policy-{}.asm", std::process::id()));
+    std::fs::write(&path, ".ORIG x3000\nHALT\n.END\n").unwrap();
+    let launch = |arguments: Vec<(&str, Json)>| {
+        let mut arguments = arguments;
+        arguments.push(("program", path.to_str().unwrap().into()));
+        object(vec![("command", "launch".into()), ("arguments", object(arguments))])
+    };
+    let mut server = DapServer::new();
+    server.set_decode_policy(DecodePolicy::permissive());
+    let mut output = Vec::new();
+
+    // The server's policy, then the launch's own arguments over it
+    server.handle(&launch(vec![]), &mut output).unwrap();
+    let machine = server.session.as_mut().unwrap().debugger.machine();
+    assert_eq!(machine.decode_policy(), DecodePolicy::permissive());
+    server
+        .handle(&launch(vec![("permissive", false.into()), ("edition", 3.into())]), &mut output)
+        .unwrap();
+    let machine = server.session.as_mut().unwrap().debugger.machine();
+    let third = DecodePolicy {
+        strictness: Strictness::Strict,
+        edition: Edition::Third,
+    };
+    assert_eq!(machine.decode_policy(), third);
+    std::fs::remove_file(&path).ok();
+}
@@ -0,0 +1,509 @@
+use crate::asm::{parse_number, parse_register};
+use crate::devices::Keyboard;
+use crate::lrc3::*;
+use crate::machine::{HaltReason, Machine};
+use core::fmt::{Display, Error, Formatter};
+use std::collections::BTreeSet;
+
+/* A command line debugger
+ *
+ * Every command is a single line of text, and execute() returns what it
+ * would print rather than printing it, so the `lrc3 debug` REPL, scripts
+ * piped in on stdin and tests all drive the same Debugger.
+ */
+
+pub const HELP: &str = "\
+step [n]              run n instructions (default 1)
+next                  run one instruction, stepping over JSR, JSRR and TRAP
+continue              run until a breakpoint or the program halts
+finish                run until the current subroutine returns with RET
+break [addr|label]    set a breakpoint, or list them all
+delete [addr|label]   delete a breakpoint, or all of them
+regs                  show the registers
+mem <addr> [len]      show len words of memory (default 8)
+disas [addr] [len]    disassemble len words (default 8) from addr (default PC)
+set reg <reg> <value> set R0-R7, PC or PSR
+set mem <addr> <value>
+limit [n]             show or set how many instructions continue, next and finish may run
+input <text>          type text on the keyboard
+quit
+";
+
+#[derive(Debug)]
+pub enum DebugError {
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidValue(String),
+    InvalidRegister(String),
+    NoBreakpoint(u16),
+}
+
+/// # How many instructions continue, next and finish run before giving up on a spinning program
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+impl Display for DebugError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::UnknownCommand(command) => {
+                write!(f, "unknown command {:?}, try help", command)
+            }
+            Self::MissingArgument(argument) => write!(f, "missing {}", argument),
+            Self::InvalidValue(text) => {
+                write!(f, "{:?} is neither a number nor a known label", text)
+            }
+            Self::InvalidRegister(text) => write!(f, "{:?} is not a register", text),
+            Self::NoBreakpoint(address) => write!(f, "no breakpoint at x{:04X}", address),
+        }
+    }
+}
+
+/// # Why a resuming command handed control back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stop {
+    /// The command ran as far as it was asked to
+    Stepped,
+    /// The PC reached a breakpoint
+    Breakpoint,
+    Halted(HaltReason),
+    /// The step limit ran out first, usually because the program is spinning
+    StepLimit,
+    /// The interrupt check asked to stop
+    Interrupted,
+}
+
+/// # How many instructions run between calls to the interrupt check
+const INTERRUPT_POLL_INTERVAL: usize = 4096;
+
+pub struct Debugger {
+    machine: Machine,
+    symbols: Vec<(String, u16)>,
+    breakpoints: BTreeSet<u16>,
+    step_limit: usize,
+    interrupt: Option<Box<dyn FnMut() -> bool>>,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, symbols: Vec<(String, u16)>) -> Self {
+        Self {
+            machine,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            step_limit: DEFAULT_STEP_LIMIT,
+            interrupt: None,
+        }
+    }
+
+    /// # A check, called every few thousand instructions while running, that stops the run when it returns true
+    pub fn set_interrupt(&mut self, interrupted: impl FnMut() -> bool + 'static) {
+        self.interrupt = Some(Box::new(interrupted));
+    }
+
+    pub fn symbols(&self) -> &[(String, u16)] {
+        &self.symbols
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<u16> {
+        &self.breakpoints
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut BTreeSet<u16> {
+        &mut self.breakpoints
+    }
+
+    pub fn set_step_limit(&mut self, step_limit: usize) {
+        self.step_limit = step_limit.max(1);
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    /// # Runs one command line, returning its output
+    pub fn execute(&mut self, line: &str) -> Result<String, DebugError> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let args: Vec<&str> = words.collect();
+        match command {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => self.value(count)? as usize,
+                    None => 1,
+                };
+                let stop = self.step(count);
+                Ok(self.report(stop))
+            }
+            "next" | "n" => {
+                let stop = self.step_over();
+                Ok(self.report(stop))
+            }
+            "continue" | "c" => {
+                let stop = self.run_to_breakpoint();
+                Ok(self.report(stop))
+            }
+            "finish" => {
+                let stop = self.finish();
+                Ok(self.report(stop))
+            }
+            "break" | "b" => match args.first() {
+                Some(text) => {
+                    let address = self.value(text)?;
+                    self.breakpoints.insert(address);
+                    Ok(format!("Breakpoint at {}\n", self.describe(address)))
+                }
+                None => Ok(self
+                    .breakpoints
+                    .iter()
+                    .map(|address| format!("Breakpoint at {}\n", self.describe(*address)))
+                    .collect()),
+            },
+            "delete" | "d" => match args.first() {
+                Some(text) => {
+                    let address = self.value(text)?;
+                    if !self.breakpoints.remove(&address) {
+                        return Err(DebugError::NoBreakpoint(address));
+                    }
+                    Ok(String::new())
+                }
+                None => {
+                    self.breakpoints.clear();
+                    Ok(String::new())
+                }
+            },
+            "regs" | "r" => Ok(self.registers()),
+            "mem" | "x" => {
+                let start = self.value(
+                    args.first()
+                        .ok_or(DebugError::MissingArgument("address"))?,
+                )?;
+                let len = self.length(args.get(1))?;
+                Ok(self.memory(start, len))
+            }
+            "disas" => {
+                let start = match args.first() {
+                    Some(text) => self.value(text)?,
+                    None => self.machine.pc(),
+                };
+                let len = self.length(args.get(1))?;
+                Ok((0..len)
+                    .map(|offset| self.disassemble(start.wrapping_add(offset)))
+                    .collect())
+            }
+            "set" => match args.as_slice() {
+                ["reg", name, value] => {
+                    let reg = register_named(name)
+                        .ok_or_else(|| DebugError::InvalidRegister(name.to_string()))?;
+                    let value = self.value(value)?;
+                    self.machine.set_register(reg, value);
+                    Ok(String::new())
+                }
+                ["mem", address, value] => {
+                    let address = self.value(address)?;
+                    let value = self.value(value)?;
+                    self.machine.poke(address, value);
+                    Ok(String::new())
+                }
+                _ => Err(DebugError::MissingArgument(
+                    "reg <reg> <value> or mem <addr> <value>",
+                )),
+            },
+            "limit" => {
+                if let Some(text) = args.first() {
+                    let limit = text
+                        .parse()
+                        .map_err(|_| DebugError::InvalidValue(text.to_string()))?;
+                    self.set_step_limit(limit);
+                }
+                Ok(format!("Step limit {}\n", self.step_limit))
+            }
+            "input" => {
+                let text = line.trim_start()[command.len()..].trim_start();
+                if let Some(keyboard) = self.machine.devices().get::<Keyboard>() {
+                    keyboard.type_bytes(text.as_bytes());
+                }
+                Ok(String::new())
+            }
+            "help" | "h" => Ok(HELP.to_string()),
+            _ => Err(DebugError::UnknownCommand(command.to_string())),
+        }
+    }
+
+    /// # Runs count instructions (at least one)
+    pub fn step(&mut self, count: usize) -> Stop {
+        let mut left = count.max(1);
+        self.resume(|_, _| {
+            left -= 1;
+            left == 0
+        })
+    }
+
+    /// # Runs one instruction, running a JSR, JSRR or TRAP through to its return
+    pub fn step_over(&mut self) -> Stop {
+        let resume_at = self.machine.pc().wrapping_add(1);
+        let sp = self.machine.register(RegisterName::R6);
+        match self.current_instruction() {
+            Some(Instruction::Jsr(_)) | Some(Instruction::Jsrr(_)) | Some(Instruction::Trap(_)) => {
+                self.resume(|machine, _| {
+                    machine.pc() == resume_at && machine.register(RegisterName::R6) == sp
+                })
+            }
+            _ => self.resume(|_, _| true),
+        }
+    }
+
+    /// # Runs until a breakpoint or the program halts
+    pub fn run_to_breakpoint(&mut self) -> Stop {
+        self.resume(|_, _| false)
+    }
+
+    /// # Runs until the current subroutine returns with RET
+    pub fn finish(&mut self) -> Stop {
+        let mut depth = 0;
+        self.resume(|_, executed| match executed {
+            Some(Instruction::Jsr(_)) | Some(Instruction::Jsrr(_)) => {
+                depth += 1;
+                false
+            }
+            Some(Instruction::Jmp(args)) if args.base_r == RegisterName::R7 => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+                false
+            }
+            _ => false,
+        })
+    }
+
+    /// # Steps until stop_after accepts the instruction just executed, a breakpoint is reached, the machine halts, the step limit runs out or the interrupt check fires
+    fn resume(&mut self, mut stop_after: impl FnMut(&Machine, Option<Instruction>) -> bool) -> Stop {
+        for steps in 0..self.step_limit {
+            if steps % INTERRUPT_POLL_INTERVAL == INTERRUPT_POLL_INTERVAL - 1 {
+                if let Some(interrupted) = self.interrupt.as_mut() {
+                    if interrupted() {
+                        return Stop::Interrupted;
+                    }
+                }
+            }
+            let executed = self.current_instruction();
+            if let Some(reason) = self.machine.step() {
+                return Stop::Halted(reason);
+            }
+            if stop_after(&self.machine, executed) {
+                return Stop::Stepped;
+            }
+            if self.breakpoints.contains(&self.machine.pc()) {
+                return Stop::Breakpoint;
+            }
+        }
+        Stop::StepLimit
+    }
+
+    /// # What the REPL prints when a resuming command stops
+    fn report(&self, stop: Stop) -> String {
+        let pc = self.machine.pc();
+        match stop {
+            Stop::Stepped => self.disassemble(pc),
+            Stop::Breakpoint => {
+                format!("Breakpoint at {}\n{}", self.describe(pc), self.disassemble(pc))
+            }
+            Stop::Halted(HaltReason::IllegalInstruction { pc, bits }) => {
+                format!("Illegal instruction x{:04X} at x{:04X}\n", bits, pc)
+            }
+            Stop::Halted(_) => "Program halted\n".to_string(),
+            Stop::StepLimit => format!(
+                "Stopped after {} steps\n{}",
+                self.step_limit,
+                self.disassemble(pc)
+            ),
+            Stop::Interrupted => format!("Interrupted\n{}", self.disassemble(pc)),
+        }
+    }
+
+    fn current_instruction(&self) -> Option<Instruction> {
+        self.machine.decode(self.machine.peek(self.machine.pc())).ok()
+    }
+
+    /// # A number, or the address of a label
+    fn value(&self, text: &str) -> Result<u16, DebugError> {
+        match parse_number(text) {
+            Some(value) if (i16::MIN as i32..=u16::MAX as i32).contains(&value) => Ok(value as u16),
+            Some(_) => Err(DebugError::InvalidValue(text.to_string())),
+            None => self
+                .symbols
+                .iter()
+                .find(|(label, _)| label == text)
+                .map(|(_, address)| *address)
+                .ok_or_else(|| DebugError::InvalidValue(text.to_string())),
+        }
+    }
+
+    fn length(&self, text: Option<&&str>) -> Result<u16, DebugError> {
+        match text {
+            Some(text) => self.value(text),
+            None => Ok(8),
+        }
+    }
+
+    fn label_at(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, at)| *at == address)
+            .map(|(label, _)| label.as_str())
+    }
+
+    fn describe(&self, address: u16) -> String {
+        match self.label_at(address) {
+            Some(label) => format!("x{:04X} ({})", address, label),
+            None => format!("x{:04X}", address),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let regfile = self.machine.regfile();
+        let mut out = String::new();
+        for row in 0..2 {
+            let names: Vec<String> = (0..4)
+                .map(|column| {
+                    let reg = RegisterName::from_bits(row * 4 + column);
+                    format!("R{} {}", row * 4 + column, regfile.contents_of(reg))
+                })
+                .collect();
+            out += &names.join("  ");
+            out += "\n";
+        }
+        out + &format!("PC x{:04X}  {}\n", self.machine.pc(), self.machine.psr())
+    }
+
+    fn memory(&self, start: u16, len: u16) -> String {
+        let mut out = String::new();
+        for offset in 0..len {
+            let address = start.wrapping_add(offset);
+            if offset % 8 == 0 {
+                if offset != 0 {
+                    out += "\n";
+                }
+                out += &format!("x{:04X}:", address);
+            }
+            out += &format!(" x{:04X}", self.machine.peek(address));
+        }
+        out + "\n"
+    }
+
+    /// # One line of disassembly, marking the PC with => and breakpoints with *
+    fn disassemble(&self, address: u16) -> String {
+        let bits = self.machine.peek(address);
+        let marker = if address == self.machine.pc() {
+            "=>"
+        } else if self.breakpoints.contains(&address) {
+            " *"
+        } else {
+            "  "
+        };
+        let label = match self.label_at(address) {
+            Some(label) => format!("{}:\n", label),
+            None => String::new(),
+        };
+        match self.machine.decode(bits) {
+            Ok(instruction) => format!(
+                "{}{} x{:04X}: x{:04X}  {}\n",
+                label, marker, address, bits, instruction
+            ),
+            Err(_) => format!(
+                "{}{} x{:04X}: x{:04X}  .FILL x{:04X}\n",
+                label, marker, address, bits, bits
+            ),
+        }
+    }
+}
+
+fn register_named(name: &str) -> Option<RegisterName> {
+    match name.to_ascii_uppercase().as_str() {
+        "PC" => Some(RegisterName::PC),
+        "PSR" => Some(RegisterName::PSR),
+        _ => parse_register(name),
+    }
+}
+
+#[test]
+fn test_debugger_breaks_steps_over_and_finishes() {
+    use crate::asm::assemble;
+    use crate::obj::ObjectFile;
+
+    let assembly = assemble(
+        "
+        .ORIG x3000
+        AND R0, R0, #0
+        JSR DOUBLE
+        ADD R0, R0, #1
+LOOP    JSR DOUBLE
+        ADD R1, R0, #-16
+        BRn LOOP
+        HALT
+DOUBLE  ADD R0, R0, R0
+        ST R7, SAVE_R7
+        JSR NOTHING
+        LD R7, SAVE_R7
+        RET
+NOTHING RET
+SAVE_R7 .BLKW 1
+        .END
+    ",
+    )
+    .unwrap();
+    let machine = Machine::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap();
+    let mut debugger = Debugger::new(machine, assembly.symbols.clone());
+
+    debugger.execute("step").unwrap();
+    assert!(debugger.execute("next").unwrap().starts_with("=> x3002:"));
+    assert_eq!(
+        debugger.execute("break DOUBLE").unwrap(),
+        "Breakpoint at x3007 (DOUBLE)\n"
+    );
+    assert!(debugger
+        .execute("continue")
+        .unwrap()
+        .starts_with("Breakpoint at x3007 (DOUBLE)\nDOUBLE:\n=> x3007:"));
+    assert!(debugger.execute("finish").unwrap().starts_with("=> x3004:"));
+    assert_eq!(debugger.machine().register(RegisterName::R0), 2);
+
+    debugger.execute("set reg R0 #7").unwrap();
+    debugger.execute("delete").unwrap();
+    assert_eq!(debugger.execute("continue").unwrap(), "Program halted\n");
+    assert_eq!(debugger.machine().register(RegisterName::R0), 28);
+
+    assert!(debugger
+        .execute("regs")
+        .unwrap()
+        .starts_with("R0 Reg[001c]  R1 Reg[000c]"));
+    debugger.execute("set mem x4000 x-1").unwrap();
+    assert_eq!(
+        debugger.execute("mem x4000 2").unwrap(),
+        "x4000: xFFFF x0000\n"
+    );
+    debugger.execute("set mem xFFFE 0").unwrap();
+    assert_eq!(debugger.execute("mem xFFFE 1").unwrap(), "xFFFE: x0000\n");
+    assert!(matches!(
+        debugger.execute("break NOWHERE"),
+        Err(DebugError::InvalidValue(_))
+    ));
+}
+
+#[test]
+fn test_debugger_gives_up_on_spinning_program() {
+    let mut memory = Memory::new();
+    // BRnzp #-1
+    memory.write(0x3000, 0x0fff);
+    let mut debugger = Debugger::new(Machine::new(memory, 0x3000), Vec::new());
+    debugger.execute("limit 50").unwrap();
+    assert!(debugger
+        .execute("continue")
+        .unwrap()
+        .starts_with("Stopped after 50 steps\n=> x3000:"));
+}
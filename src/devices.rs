@@ -0,0 +1,499 @@
+use crate::lrc3::Interrupt;
+use core::fmt::{Display as FmtDisplay, Error, Formatter};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+/* Memory mapped I/O
+ *
+ * A Device answers for a range of addresses, usually in the xFE00-xFFFF page.
+ * The datapath and Machine route every program access to a mapped address to
+ * its Device instead of Memory, tick each device once per instruction, and
+ * take the highest priority interrupt any of them is requesting.
+ */
+
+#[derive(Debug)]
+pub enum DeviceError {
+    Overlap { mapped: (u16, u16), requested: (u16, u16) },
+}
+
+impl FmtDisplay for DeviceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Overlap { mapped, requested } => write!(
+                f,
+                "device at x{:04X}-x{:04X} overlaps the device at x{:04X}-x{:04X}",
+                requested.0, requested.1, mapped.0, mapped.1
+            ),
+        }
+    }
+}
+
+/// # Lets Devices::get find a device by its concrete type
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+pub trait Device: AsAny {
+    /// # A program read of one of this device's registers
+    fn read(&mut self, address: u16) -> u16;
+
+    fn write(&mut self, address: u16, data: u16);
+
+    /// # What read would return, without any of its side effects
+    fn peek(&self, address: u16) -> u16;
+
+    /// # Called once per instruction, before the fetch
+    fn tick(&mut self) {}
+
+    /// # The interrupt this device is currently requesting, if any
+    fn interrupt(&self) -> Option<Interrupt> {
+        None
+    }
+}
+
+/// # The devices on the memory bus and the address ranges they answer for
+#[derive(Default)]
+pub struct Devices {
+    mapped: Vec<(RangeInclusive<u16>, Box<dyn Device>)>,
+}
+
+impl Devices {
+    /// # A bus with nothing mapped, so every address is plain memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # The keyboard, display and machine control register at their standard addresses
+    pub fn console() -> Self {
+        let mut devices = Self::new();
+        let disjoint = "the console devices don't overlap";
+        devices.map(KBSR..=KBDR, Keyboard::new()).expect(disjoint);
+        devices.map(DSR..=DDR, Display::new()).expect(disjoint);
+        devices.map(MCR..=MCR, MachineControl::new()).expect(disjoint);
+        devices
+    }
+
+    /// # Maps device onto range, which must not overlap anything already mapped
+    pub fn map(&mut self, range: RangeInclusive<u16>, device: impl Device) -> Result<(), DeviceError> {
+        if let Some((other, _)) = self
+            .mapped
+            .iter()
+            .find(|(other, _)| range.start() <= other.end() && other.start() <= range.end())
+        {
+            return Err(DeviceError::Overlap {
+                mapped: (*other.start(), *other.end()),
+                requested: (*range.start(), *range.end()),
+            });
+        }
+        self.mapped.push((range, Box::new(device)));
+        Ok(())
+    }
+
+    fn at(&mut self, address: u16) -> Option<&mut Box<dyn Device>> {
+        self.mapped
+            .iter_mut()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(_, device)| device)
+    }
+
+    pub fn is_mapped(&self, address: u16) -> bool {
+        self.mapped.iter().any(|(range, _)| range.contains(&address))
+    }
+
+    /// # The mapped device's answer, or None if address is plain memory
+    pub fn read(&mut self, address: u16) -> Option<u16> {
+        self.at(address).map(|device| device.read(address))
+    }
+
+    /// # Hands the write to the mapped device, returning false if address is plain memory
+    pub fn write(&mut self, address: u16, data: u16) -> bool {
+        match self.at(address) {
+            Some(device) => {
+                device.write(address, data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn peek(&self, address: u16) -> Option<u16> {
+        self.mapped
+            .iter()
+            .find(|(range, _)| range.contains(&address))
+            .map(|(_, device)| device.peek(address))
+    }
+
+    pub fn tick(&mut self) {
+        for (_, device) in self.mapped.iter_mut() {
+            device.tick();
+        }
+    }
+
+    /// # MCR[15], cleared by HALT to stop the clock; always set if no MCR is mapped
+    pub fn clock_enabled(&self) -> bool {
+        match self.peek(MCR) {
+            Some(mcr) => mcr & CLOCK_ENABLE != 0,
+            None => true,
+        }
+    }
+
+    /// # The highest priority request among all devices
+    pub fn interrupt(&self) -> Option<Interrupt> {
+        self.mapped
+            .iter()
+            .filter_map(|(_, device)| device.interrupt())
+            .max_by_key(|interrupt| interrupt.priority)
+    }
+
+    /// # The first mapped device of type T
+    pub fn get<T: Device>(&self) -> Option<&T> {
+        self.mapped
+            .iter()
+            .find_map(|(_, device)| device.as_ref().as_any().downcast_ref())
+    }
+
+    pub fn get_mut<T: Device>(&mut self) -> Option<&mut T> {
+        self.mapped
+            .iter_mut()
+            .find_map(|(_, device)| device.as_mut().as_any_mut().downcast_mut())
+    }
+}
+
+/* The console devices from chapter 9 of Patt & Patel
+ *
+ * Each device has a status register whose bit 15 is the ready bit and bit 14
+ * the interrupt enable, and a data register holding one character in its low
+ * byte.
+ */
+
+/// # Keyboard status register: [15] a character is waiting in KBDR, [14] interrupt enable
+pub const KBSR: u16 = 0xfe00;
+/// # Keyboard data register: the last character typed
+pub const KBDR: u16 = 0xfe02;
+/// # Display status register: [15] DDR may be written, [14] interrupt enable
+pub const DSR: u16 = 0xfe04;
+/// # Display data register: writing it prints the low byte
+pub const DDR: u16 = 0xfe06;
+
+/// # Machine control register: [15] clock enable
+pub const MCR: u16 = 0xfffe;
+
+const CLOCK_ENABLE: u16 = 0x8000;
+const READY: u16 = 0x8000;
+const INTERRUPT_ENABLE: u16 = 0x4000;
+
+/// # The keyboard interrupt from the textbook: vector x80 at priority 4
+pub const KEYBOARD_INTERRUPT: Interrupt = Interrupt {
+    vector: 0x80,
+    priority: 4,
+};
+
+#[derive(Clone, Default)]
+pub struct Keyboard {
+    // Shared so a reader thread can type into it while the program runs
+    input: Arc<Mutex<VecDeque<u8>>>,
+    status: u16,
+    data: u16,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// # Queues bytes to be typed, one per read of KBDR
+    pub fn type_bytes(&self, bytes: &[u8]) {
+        self.input.lock().unwrap().extend(bytes);
+    }
+
+    /// # Types whatever arrives on stdin, from a background thread
+    pub fn attach_stdin(&self) {
+        let input = Arc::clone(&self.input);
+        std::thread::spawn(move || {
+            for byte in std::io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) => input.lock().unwrap().push_back(byte),
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
+    /// # Moves the next typed character into KBDR if the program has consumed the last one
+    fn poll(&mut self) {
+        if self.status & READY == 0 {
+            if let Some(byte) = self.input.lock().unwrap().pop_front() {
+                self.data = byte as u16;
+                self.status |= READY;
+            }
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn read(&mut self, address: u16) -> u16 {
+        match address {
+            KBSR => {
+                self.poll();
+                self.status
+            }
+            KBDR => {
+                // Reading KBDR takes the character, so KBSR is clear until another arrives
+                self.status &= !READY;
+                self.data
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u16) {
+        // Only the interrupt enable is writable; KBDR belongs to the keyboard
+        if address == KBSR {
+            self.status = (self.status & READY) | (data & INTERRUPT_ENABLE);
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            KBSR => self.status,
+            KBDR => self.data,
+            _ => 0,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.poll();
+    }
+
+    fn interrupt(&self) -> Option<Interrupt> {
+        match self.status & (READY | INTERRUPT_ENABLE) == READY | INTERRUPT_ENABLE {
+            true => Some(KEYBOARD_INTERRUPT),
+            false => None,
+        }
+    }
+}
+
+/// # A display that finishes each character as it is written, so DSR[15] is always set
+#[derive(Clone)]
+pub struct Display {
+    status: u16,
+    data: u16,
+    output: Vec<u8>,
+    echo: bool,
+}
+
+impl Display {
+    pub fn new() -> Self {
+        Self {
+            status: READY,
+            data: 0,
+            output: Vec::new(),
+            echo: false,
+        }
+    }
+
+    /// # Write every character to stdout as it is displayed, instead of keeping it for output()
+    pub fn attach_stdout(&mut self) {
+        self.echo = true;
+    }
+
+    /// # Every character displayed so far while not attached to stdout
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl Device for Display {
+    fn read(&mut self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, address: u16, data: u16) {
+        match address {
+            DSR => self.status = READY | (data & INTERRUPT_ENABLE),
+            DDR => {
+                self.data = data;
+                let byte = data as u8;
+                if self.echo {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+                } else {
+                    self.output.push(byte);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        match address {
+            DSR => self.status,
+            DDR => self.data,
+            _ => 0,
+        }
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct MachineControl {
+    mcr: u16,
+}
+
+impl MachineControl {
+    pub fn new() -> Self {
+        Self { mcr: CLOCK_ENABLE }
+    }
+}
+
+impl Default for MachineControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for MachineControl {
+    fn read(&mut self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    fn write(&mut self, _address: u16, data: u16) {
+        self.mcr = data;
+    }
+
+    fn peek(&self, _address: u16) -> u16 {
+        self.mcr
+    }
+}
+
+#[test]
+fn test_keyboard_ready_bit_follows_kbdr_reads() {
+    let mut keyboard = Keyboard::new();
+    assert_eq!(keyboard.read(KBSR) & READY, 0);
+
+    keyboard.type_bytes(b"hi");
+    assert_eq!(keyboard.read(KBSR) & READY, READY);
+    assert_eq!(keyboard.peek(KBSR) & READY, READY);
+    assert_eq!(keyboard.read(KBDR), b'h' as u16);
+    assert_eq!(keyboard.peek(KBSR) & READY, 0);
+    assert_eq!(keyboard.read(KBSR) & READY, READY);
+    assert_eq!(keyboard.read(KBDR), b'i' as u16);
+    assert_eq!(keyboard.read(KBSR) & READY, 0);
+
+    keyboard.write(KBSR, 0xffff);
+    assert_eq!(keyboard.read(KBSR), INTERRUPT_ENABLE);
+    assert_eq!(keyboard.interrupt(), None);
+    keyboard.type_bytes(b"!");
+    keyboard.tick();
+    assert_eq!(keyboard.interrupt(), Some(KEYBOARD_INTERRUPT));
+}
+
+/// # A lab style timer that counts ticks and interrupts once it reaches 5
+#[cfg(test)]
+struct Timer {
+    ticks: u16,
+    pending: bool,
+}
+
+#[cfg(test)]
+impl Device for Timer {
+    fn read(&mut self, _address: u16) -> u16 {
+        self.pending = false;
+        self.ticks
+    }
+
+    fn write(&mut self, _address: u16, _data: u16) {}
+
+    fn peek(&self, _address: u16) -> u16 {
+        self.ticks
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        self.pending |= self.ticks == 5;
+    }
+
+    fn interrupt(&self) -> Option<Interrupt> {
+        match self.pending {
+            true => Some(Interrupt {
+                vector: 0x81,
+                priority: 2,
+            }),
+            false => None,
+        }
+    }
+}
+
+#[test]
+fn test_custom_device_interrupts_both_simulators() {
+    use crate::lrc3::{Lrc3Cpu, RegisterName};
+    use crate::machine::Machine;
+    use crate::obj::ObjectFile;
+
+    let assembly = crate::asm::assemble(
+        "
+        .ORIG x0181
+        .FILL HANDLER
+        .END
+        .ORIG x1000
+HANDLER LDI R2, TIMER
+        RTI
+TIMER   .FILL xFE10
+        .END
+        .ORIG x3000
+LOOP    ADD R1, R1, #1
+        BR LOOP
+        .END
+    ",
+    )
+    .unwrap();
+    let objects = ObjectFile::from_assembly(&assembly);
+    let timer = || Timer {
+        ticks: 0,
+        pending: false,
+    };
+
+    let mut machine = Machine::from_objects(&objects).unwrap();
+    machine.devices_mut().map(0xfe10..=0xfe10, timer()).unwrap();
+    machine.set_register(RegisterName::PC, 0x3000);
+    let mut cpu = Lrc3Cpu::from_objects(&objects).unwrap();
+    cpu.devices_mut().map(0xfe10..=0xfe10, timer()).unwrap();
+    assert!(matches!(
+        cpu.devices_mut().map(0xfe08..=0xfe10, timer()),
+        Err(DeviceError::Overlap { mapped: (0xfe10, 0xfe10), .. })
+    ));
+    cpu.set_register(RegisterName::PC, 0x3000);
+
+    for _ in 0..12 {
+        machine.step();
+        cpu.step_instruction();
+    }
+    // Taken on the fifth instruction, acknowledged by the handler's read on the sixth
+    assert_eq!(machine.register(RegisterName::R2), 6);
+    assert_eq!(machine.devices().get::<Timer>().unwrap().ticks, 12);
+    for reg in [RegisterName::R1, RegisterName::R2, RegisterName::PC] {
+        assert_eq!(machine.register(reg), cpu.register(reg));
+    }
+}
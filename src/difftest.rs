@@ -0,0 +1,488 @@
+use crate::devices::Devices;
+use crate::lrc3::*;
+use core::fmt::{Display, Error, Formatter};
+
+/* Differential testing of the microcoded datapath
+ *
+ * reference_step below is a direct transcription of the instruction semantics
+ * from Appendix A of Patt & Patel, written without reference to the datapath.
+ * The harness starts it and Lrc3Cpu from the same randomized architectural
+ * state, runs one instruction on each, and reports the first place where
+ * they disagree. Random states run in user mode about half the time, so the
+ * exceptions are exercised alongside the ordinary instruction semantics, and
+ * under either edition, so both microcodes for LEA and TRAP are.
+ *
+ * The reference decodes as the hardware does, ignoring fixed fields such as
+ * IR[4:3] of a register ADD, so every one of the 64K words is compared.
+ * Machine is deliberately stricter there, halting with IllegalInstruction.
+ */
+
+/// # Everything a program can observe: the GPRs, PC, PSR, saved stack pointers and memory,
+/// and the edition whose LEA and TRAP it runs under
+#[derive(Clone)]
+pub struct ArchState {
+    pub registers: [u16; 8],
+    pub pc: u16,
+    pub psr: Psr,
+    pub saved_ssp: u16,
+    pub saved_usp: u16,
+    pub memory: Memory,
+    pub edition: Edition,
+}
+
+impl ArchState {
+    fn reg(&self, reg: RegisterName) -> u16 {
+        self.registers[reg.index()]
+    }
+
+    fn read(&self, address: u16) -> u16 {
+        match address {
+            PSR_ADDRESS => self.psr.bits(),
+            _ => self.memory.read(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u16) {
+        match address {
+            PSR_ADDRESS => self.psr = Psr::from_bits(data),
+            _ => self.memory.write(address, data),
+        }
+    }
+
+    /// # Program accesses in user mode are confined to x3000-xFDFF
+    fn check(&self, address: u16) -> Result<(), Exception> {
+        match self.psr.privilege == Privilege::User && !(0x3000..0xfe00).contains(&address) {
+            true => Err(Exception::AccessControlViolation),
+            false => Ok(()),
+        }
+    }
+
+    fn load(&self, address: u16) -> Result<u16, Exception> {
+        self.check(address)?;
+        Ok(self.read(address))
+    }
+
+    fn store(&mut self, address: u16, data: u16) -> Result<(), Exception> {
+        self.check(address)?;
+        self.write(address, data);
+        Ok(())
+    }
+
+    fn push(&mut self, data: u16) {
+        let sp = self.registers[6].wrapping_sub(1);
+        self.registers[6] = sp;
+        self.write(sp, data);
+    }
+
+    /// # Switches to the supervisor stack, pushes PSR and PC, and jumps through the table entry
+    fn enter_supervisor(&mut self, entry: u16) {
+        let psr = self.psr;
+        if psr.privilege == Privilege::User {
+            self.saved_usp = self.registers[6];
+            self.registers[6] = self.saved_ssp;
+        }
+        self.psr.privilege = Privilege::Supervisor;
+        self.push(psr.bits());
+        self.push(self.pc);
+        self.pc = self.read(entry);
+    }
+
+    fn raise(&mut self, exception: Exception) {
+        self.enter_supervisor(0x0100 | exception.vector() as u16);
+    }
+
+    fn set_cc(&mut self, data: u16) {
+        self.psr.n = data >> 15 == 1;
+        self.psr.z = data == 0;
+        self.psr.p = data >> 15 == 0 && data != 0;
+    }
+
+    fn write_dr(&mut self, dr: RegisterName, data: u16) {
+        self.registers[dr.index()] = data;
+        self.set_cc(data);
+    }
+}
+
+/// # Executes the instruction at state.pc, or returns the decode error if its encoding is malformed
+pub fn reference_step(state: &mut ArchState) -> Result<(), Lrc3Error> {
+    let bits = match state.load(state.pc) {
+        Ok(bits) => bits,
+        Err(exception) => {
+            state.raise(exception);
+            return Ok(());
+        }
+    };
+    // The datapath never looks at fixed fields, so runs every word as if they held their required values
+    let instruction = match Instruction::decode_with(bits, &DecodePolicy::permissive()) {
+        Ok((instruction, _)) => Ok(instruction),
+        Err(Lrc3Error::UnknownOpcode(_)) => Err(Exception::IllegalOpcode),
+        Err(e) => return Err(e),
+    };
+    let pc = state.pc.wrapping_add(1);
+    state.pc = pc;
+
+    if let Err(exception) = instruction.and_then(|instruction| execute(state, instruction, pc)) {
+        state.raise(exception);
+    }
+    Ok(())
+}
+
+/// # Carries out instruction, checking every access before anything is changed
+fn execute(state: &mut ArchState, instruction: Instruction, pc: u16) -> Result<(), Exception> {
+    match instruction {
+        Instruction::Add(args) => {
+            state.write_dr(args.dr, state.reg(args.sr1).wrapping_add(state.reg(args.sr2)))
+        }
+        Instruction::Addi(args) => {
+            state.write_dr(args.dr, state.reg(args.sr1).wrapping_add(args.imm5.value()))
+        }
+        Instruction::And(args) => state.write_dr(args.dr, state.reg(args.sr1) & state.reg(args.sr2)),
+        Instruction::Andi(args) => state.write_dr(args.dr, state.reg(args.sr1) & args.imm5.value()),
+        Instruction::Not(args) => state.write_dr(args.dr, !state.reg(args.sr)),
+        Instruction::Br(args) => {
+            let Psr { n, z, p, .. } = state.psr;
+            if (args.n.0 && n) || (args.z.0 && z) || (args.p.0 && p) {
+                state.pc = pc.wrapping_add(args.pcoffset9.value());
+            }
+        }
+        Instruction::Jmp(args) => state.pc = state.reg(args.base_r),
+        Instruction::Jsr(args) => {
+            state.registers[7] = pc;
+            state.pc = pc.wrapping_add(args.pcoffset11.value());
+        }
+        Instruction::Jsrr(args) => {
+            let temp = state.reg(args.base_r);
+            state.registers[7] = pc;
+            state.pc = temp;
+        }
+        Instruction::Ld(args) => {
+            let data = state.load(pc.wrapping_add(args.pcoffset9.value()))?;
+            state.write_dr(args.dr, data);
+        }
+        Instruction::Ldi(args) => {
+            let pointer = state.load(pc.wrapping_add(args.pcoffset9.value()))?;
+            let data = state.load(pointer)?;
+            state.write_dr(args.dr, data);
+        }
+        Instruction::Ldr(args) => {
+            let data = state.load(state.reg(args.base_r).wrapping_add(args.offset6.value()))?;
+            state.write_dr(args.dr, data);
+        }
+        Instruction::Lea(args) => {
+            let address = pc.wrapping_add(args.pcoffset9.value());
+            match state.edition {
+                Edition::Second => state.write_dr(args.dr, address),
+                Edition::Third => state.registers[args.dr.index()] = address,
+            }
+        }
+        Instruction::St(args) => {
+            let data = state.reg(args.sr);
+            state.store(pc.wrapping_add(args.offset9.value()), data)?;
+        }
+        Instruction::Sti(args) => {
+            let pointer = state.load(pc.wrapping_add(args.offset9.value()))?;
+            let data = state.reg(args.sr);
+            state.store(pointer, data)?;
+        }
+        Instruction::Str(args) => {
+            let address = state.reg(args.base_r).wrapping_add(args.offset6.value());
+            let data = state.reg(args.sr);
+            state.store(address, data)?;
+        }
+        Instruction::Trap(args) => match state.edition {
+            Edition::Second => {
+                state.registers[7] = pc;
+                state.pc = state.read(args.trapvect8.masked());
+            }
+            Edition::Third => state.enter_supervisor(args.trapvect8.masked()),
+        },
+        Instruction::Rti() => {
+            if state.psr.privilege == Privilege::User {
+                return Err(Exception::PrivilegeViolation);
+            }
+            let sp = state.registers[6];
+            state.pc = state.read(sp);
+            state.psr = Psr::from_bits(state.read(sp.wrapping_add(1)));
+            state.registers[6] = sp.wrapping_add(2);
+            if state.psr.privilege == Privilege::User {
+                state.saved_ssp = state.registers[6];
+                state.registers[6] = state.saved_usp;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Divergence {
+    Register {
+        reg: RegisterName,
+        reference: u16,
+        microcode: u16,
+    },
+    Pc {
+        reference: u16,
+        microcode: u16,
+    },
+    Psr {
+        reference: Psr,
+        microcode: Psr,
+    },
+    SavedSp {
+        reg: RegisterName,
+        reference: u16,
+        microcode: u16,
+    },
+    Memory {
+        address: u16,
+        reference: u16,
+        microcode: u16,
+    },
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Register {
+                reg,
+                reference,
+                microcode,
+            } => write!(
+                f,
+                "{:?} is x{:04X} in the reference model, x{:04X} in the microcode",
+                reg, reference, microcode
+            ),
+            Self::Pc {
+                reference,
+                microcode,
+            } => write!(
+                f,
+                "PC is x{:04X} in the reference model, x{:04X} in the microcode",
+                reference, microcode
+            ),
+            Self::Psr {
+                reference,
+                microcode,
+            } => write!(
+                f,
+                "PSR is {} in the reference model, {} in the microcode",
+                reference, microcode
+            ),
+            Self::SavedSp {
+                reg,
+                reference,
+                microcode,
+            } => write!(
+                f,
+                "{:?} is x{:04X} in the reference model, x{:04X} in the microcode",
+                reg, reference, microcode
+            ),
+            Self::Memory {
+                address,
+                reference,
+                microcode,
+            } => write!(
+                f,
+                "MEM[x{:04X}] is x{:04X} in the reference model, x{:04X} in the microcode",
+                address, reference, microcode
+            ),
+        }
+    }
+}
+
+/// # The instruction word, starting state and first difference of a failed comparison
+pub struct DiffFailure {
+    pub bits: u16,
+    pub initial: ArchState,
+    pub divergence: Divergence,
+}
+
+impl Display for DiffFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let instruction = match Instruction::decode_bits(self.bits) {
+            Ok(instruction) => format!("{}", instruction),
+            Err(e) => format!("{}", e),
+        };
+        write!(
+            f,
+            "x{:04X} ({}) at PC x{:04X} with {} and registers {:04X?} under the {:?} edition: {}",
+            self.bits,
+            instruction,
+            self.initial.pc,
+            self.initial.psr,
+            self.initial.registers,
+            self.initial.edition,
+            self.divergence
+        )
+    }
+}
+
+/// # xorshift64, enough to scatter test states without pulling in a dependency
+pub struct XorShift64(u64);
+
+impl XorShift64 {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u16(&mut self) -> u16 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 32) as u16
+    }
+}
+
+/// # Random registers and PSR, with bits placed at a random PC in memory
+pub fn random_state(rng: &mut XorShift64, memory: &Memory, bits: u16) -> ArchState {
+    let mut registers = [0; 8];
+    for reg in registers.iter_mut() {
+        *reg = rng.next_u16();
+    }
+    // Exactly one of n, z and p is set, as after any instruction that sets the condition codes
+    let cc = 0b1 << (rng.next_u16() % 3);
+    let psr = Psr::from_bits((rng.next_u16() & 0x8700) | cc);
+    let edition = match rng.next_u16() & 1 {
+        0 => Edition::Second,
+        _ => Edition::Third,
+    };
+
+    let mut state = ArchState {
+        registers,
+        pc: rng.next_u16(),
+        psr,
+        saved_ssp: rng.next_u16(),
+        saved_usp: rng.next_u16(),
+        memory: memory.clone(),
+        edition,
+    };
+    state.write(state.pc, bits);
+    state
+}
+
+pub fn random_memory(rng: &mut XorShift64) -> Memory {
+    let mut memory = Memory::new();
+    for address in u16::MIN..=u16::MAX {
+        memory.write(address, rng.next_u16());
+    }
+    memory
+}
+
+/// # Runs one instruction from initial on both models, returning the first architectural difference
+pub fn compare(initial: &ArchState) -> Option<Divergence> {
+    let mut reference = initial.clone();
+    let reference_result = reference_step(&mut reference);
+
+    let mut cpu = Lrc3Cpu::new(initial.memory.clone(), initial.pc);
+    cpu.set_edition(initial.edition);
+    // The reference model has no devices, so the whole address space is memory for both
+    *cpu.devices_mut() = Devices::new();
+    for (index, value) in initial.registers.iter().enumerate() {
+        cpu.set_register(RegisterName::from_bits(index as u16), *value);
+    }
+    cpu.set_register(RegisterName::PSR, initial.psr.bits());
+    cpu.set_register(RegisterName::SavedSSP, initial.saved_ssp);
+    cpu.set_register(RegisterName::SavedUSP, initial.saved_usp);
+    cpu.step_instruction();
+
+    if reference_result.is_err() {
+        // Neither model defines what happens after an undecodable word
+        return None;
+    }
+
+    for (index, value) in reference.registers.iter().enumerate() {
+        let reg = RegisterName::from_bits(index as u16);
+        if cpu.register(reg) != *value {
+            return Some(Divergence::Register {
+                reg,
+                reference: *value,
+                microcode: cpu.register(reg),
+            });
+        }
+    }
+    if cpu.register(RegisterName::PC) != reference.pc {
+        return Some(Divergence::Pc {
+            reference: reference.pc,
+            microcode: cpu.register(RegisterName::PC),
+        });
+    }
+    if cpu.psr() != reference.psr {
+        return Some(Divergence::Psr {
+            reference: reference.psr,
+            microcode: cpu.psr(),
+        });
+    }
+    for (reg, value) in [
+        (RegisterName::SavedSSP, reference.saved_ssp),
+        (RegisterName::SavedUSP, reference.saved_usp),
+    ] {
+        if cpu.register(reg) != value {
+            return Some(Divergence::SavedSp {
+                reg,
+                reference: value,
+                microcode: cpu.register(reg),
+            });
+        }
+    }
+    reference
+        .memory
+        .first_difference(cpu.memory())
+        .map(|address| Divergence::Memory {
+            address,
+            reference: reference.memory.read(address),
+            microcode: cpu.memory().read(address),
+        })
+}
+
+/// # Compares the models on every word, returning how many were checked
+pub fn sweep(seed: u64) -> Result<usize, Box<DiffFailure>> {
+    let mut rng = XorShift64::new(seed);
+    let memory = random_memory(&mut rng);
+    let mut checked = 0;
+
+    for bits in u16::MIN..=u16::MAX {
+        let initial = random_state(&mut rng, &memory, bits);
+        if let Some(divergence) = compare(&initial) {
+            return Err(Box::new(DiffFailure {
+                bits,
+                initial,
+                divergence,
+            }));
+        }
+        checked += 1;
+    }
+
+    Ok(checked)
+}
+
+#[test]
+fn test_microcode_matches_reference_on_every_encoding() {
+    match sweep(0x1c3_2021) {
+        Ok(checked) => assert_eq!(checked, 0x10000),
+        Err(failure) => panic!("{}", failure),
+    }
+}
+
+#[test]
+fn test_machine_halts_where_microcode_ignores_fixed_fields() {
+    use crate::machine::{HaltReason, Machine};
+
+    // ADD R0, R0, R0 with IR[3] set
+    let mut memory = Memory::new();
+    memory.write(0x3000, 0x1018);
+    let mut machine = Machine::new(memory.clone(), 0x3000);
+    machine.set_register(RegisterName::R0, 3);
+    assert_eq!(
+        machine.step(),
+        Some(HaltReason::IllegalInstruction {
+            pc: 0x3000,
+            bits: 0x1018
+        })
+    );
+
+    let mut cpu = Lrc3Cpu::new(memory, 0x3000);
+    cpu.set_register(RegisterName::R0, 3);
+    cpu.step_instruction();
+    assert_eq!(cpu.register(RegisterName::R0), 6);
+    assert_eq!(cpu.register(RegisterName::PC), 0x3001);
+}
@@ -0,0 +1,464 @@
+use crate::asm::{is_mnemonic, is_valid_label, parse_number};
+use crate::lrc3::*;
+use crate::obj::ObjectFile;
+use core::fmt::{Display, Error, Formatter};
+use std::collections::{BTreeMap, BTreeSet};
+
+/* A disassembler whose output is LC-3 assembly again
+ *
+ * The Display of Instruction explains what each instruction does, for
+ * people reading a trace. Canonical writes what an assembler would have
+ * been given instead: operands in source order, RET and the trap aliases
+ * by name, and PC-relative targets as labels where one is known.
+ *
+ *         .ORIG x3000
+ * LOOP    ADD R1, R1, #-1
+ *         BRp LOOP
+ *         HALT
+ *         .END
+ *
+ * Assembling the output gives back the same words. Words that don't decode,
+ * and branches with no flags set, which no mnemonic spells, become .FILL.
+ *
+ * Without the source, a Flow traced from the entry point tells code from
+ * data: only words some path of execution reaches are shown as code, and
+ * the rest as .FILL, or .STRINGZ where they spell out a string. Targets
+ * that have no symbol get one made up from their address: SUB_3010 for
+ * subroutines, L_3004 for branch targets, DATA_3020 for what LD, ST and
+ * friends refer to, and TRAP_25 for trap service routines in the image.
+ */
+
+/// # Whether the output shows only assembly, or explains each instruction in a comment too
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Canonical,
+    Explained,
+}
+
+/// # An instruction in assembler syntax, as it sits at address
+pub struct Canonical<'a> {
+    instruction: &'a Instruction,
+    address: u16,
+    symbols: &'a [(String, u16)],
+}
+
+impl<'a> Canonical<'a> {
+    /// # Targets with an address in symbols are named after it, others are left as offsets
+    pub fn new(instruction: &'a Instruction, address: u16, symbols: &'a [(String, u16)]) -> Self {
+        Self {
+            instruction,
+            address,
+            symbols,
+        }
+    }
+
+    fn target(&self, f: &mut Formatter<'_>, offset: u16) -> Result<(), Error> {
+        let target = self.address.wrapping_add(1).wrapping_add(offset);
+        match self.symbols.iter().find(|(_, address)| *address == target) {
+            Some((label, _)) => write!(f, "{}", label),
+            None => write!(f, "#{}", offset as i16),
+        }
+    }
+}
+
+impl Display for Canonical<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self.instruction {
+            Instruction::Add(args) => write!(f, "ADD {:?}, {:?}, {:?}", args.dr, args.sr1, args.sr2),
+            Instruction::Addi(args) => write!(f, "ADD {:?}, {:?}, {}", args.dr, args.sr1, args.imm5),
+            Instruction::And(args) => write!(f, "AND {:?}, {:?}, {:?}", args.dr, args.sr1, args.sr2),
+            Instruction::Andi(args) => write!(f, "AND {:?}, {:?}, {}", args.dr, args.sr1, args.imm5),
+            Instruction::Br(args) if !(args.n.0 || args.z.0 || args.p.0) => {
+                write!(f, ".FILL x{:04X}", self.instruction.encode_bits())
+            }
+            Instruction::Br(args) => {
+                write!(
+                    f,
+                    "BR{}{}{} ",
+                    if args.n.0 { "n" } else { "" },
+                    if args.z.0 { "z" } else { "" },
+                    if args.p.0 { "p" } else { "" }
+                )?;
+                self.target(f, args.pcoffset9.value())
+            }
+            Instruction::Jmp(BaseRArgs {
+                base_r: RegisterName::R7,
+            }) => write!(f, "RET"),
+            Instruction::Jmp(args) => write!(f, "JMP {:?}", args.base_r),
+            Instruction::Jsr(args) => {
+                write!(f, "JSR ")?;
+                self.target(f, args.pcoffset11.value())
+            }
+            Instruction::Jsrr(args) => write!(f, "JSRR {:?}", args.base_r),
+            Instruction::Ld(args) => {
+                write!(f, "LD {:?}, ", args.dr)?;
+                self.target(f, args.pcoffset9.value())
+            }
+            Instruction::Ldi(args) => {
+                write!(f, "LDI {:?}, ", args.dr)?;
+                self.target(f, args.pcoffset9.value())
+            }
+            Instruction::Ldr(args) => write!(f, "LDR {:?}, {:?}, {}", args.dr, args.base_r, args.offset6),
+            Instruction::Lea(args) => {
+                write!(f, "LEA {:?}, ", args.dr)?;
+                self.target(f, args.pcoffset9.value())
+            }
+            Instruction::Not(args) => write!(f, "NOT {:?}, {:?}", args.dr, args.sr),
+            Instruction::Rti() => write!(f, "RTI"),
+            Instruction::St(args) => {
+                write!(f, "ST {:?}, ", args.sr)?;
+                self.target(f, args.offset9.value())
+            }
+            Instruction::Sti(args) => {
+                write!(f, "STI {:?}, ", args.sr)?;
+                self.target(f, args.offset9.value())
+            }
+            Instruction::Str(args) => write!(f, "STR {:?}, {:?}, {}", args.sr, args.base_r, args.offset6),
+            Instruction::Trap(args) => match args.trapvect8.masked() {
+                0x20 => write!(f, "GETC"),
+                0x21 => write!(f, "OUT"),
+                0x22 => write!(f, "PUTS"),
+                0x23 => write!(f, "IN"),
+                0x24 => write!(f, "PUTSP"),
+                0x25 => write!(f, "HALT"),
+                vector => write!(f, "TRAP x{:02X}", vector),
+            },
+        }
+    }
+}
+
+/// # Which words of a program execution can reach, and labels for the places it goes
+#[derive(Debug, Default)]
+pub struct Flow {
+    pub code: BTreeSet<u16>,
+    pub labels: Vec<(String, u16)>,
+}
+
+/// # The word at address in whichever object covers it
+pub(crate) fn word_at(objects: &[ObjectFile], address: u16) -> Option<u16> {
+    objects.iter().find_map(|object| {
+        let offset = address.wrapping_sub(object.origin) as usize;
+        object.words.get(offset).copied()
+    })
+}
+
+impl Flow {
+    /// # Follows every branch, call and trap from the entry points through the objects
+    ///
+    /// JMP through anything but R7, and JSRR, go where a register says, so only the instruction
+    /// after a JSRR is followed, as the place the call returns to. Words that policy can't decode
+    /// end a path.
+    pub fn trace(objects: &[ObjectFile], entries: &[u16], policy: &DecodePolicy) -> Self {
+        let mut code = BTreeSet::new();
+        // Address -> the name to give it, kept as (rank, name) so subroutines win over branch targets over data
+        let mut names: BTreeMap<u16, (u8, String)> = BTreeMap::new();
+        let mut name = |address: u16, rank: u8, text: String| {
+            if word_at(objects, address).is_some() {
+                let entry = names.entry(address).or_insert((rank, text.clone()));
+                if rank < entry.0 {
+                    *entry = (rank, text);
+                }
+            }
+        };
+
+        let mut pending = entries.to_vec();
+        while let Some(address) = pending.pop() {
+            if code.contains(&address) {
+                continue;
+            }
+            let decoded = word_at(objects, address).map(|bits| Instruction::decode_with(bits, policy));
+            let instruction = match decoded {
+                Some(Ok((instruction, _))) => instruction,
+                _ => continue,
+            };
+            code.insert(address);
+            let next = address.wrapping_add(1);
+
+            if let Some(target) = instruction.target(address) {
+                match instruction.control_flow() {
+                    ControlFlow::Call => name(target, 1, format!("SUB_{:04X}", target)),
+                    _ => name(target, 2, format!("L_{:04X}", target)),
+                }
+                pending.push(target);
+            }
+            match (instruction.control_flow(), &instruction) {
+                (ControlFlow::Jump, _) | (ControlFlow::Return, _) | (ControlFlow::Rti, _) => {}
+                (ControlFlow::Trap, Instruction::Trap(args)) => {
+                    let vector = args.trapvect8.masked();
+                    if let Some(routine) = word_at(objects, vector) {
+                        name(routine, 0, format!("TRAP_{:02X}", vector));
+                        pending.push(routine);
+                    }
+                    // HALT doesn't come back
+                    if vector != 0x25 {
+                        pending.push(next);
+                    }
+                }
+                (_, Instruction::Ld(LdArgs { pcoffset9, .. }))
+                | (_, Instruction::Ldi(LdiArgs { pcoffset9, .. }))
+                | (_, Instruction::Lea(LeaArgs { pcoffset9, .. }))
+                | (_, Instruction::St(StArgs {
+                    offset9: pcoffset9, ..
+                }))
+                | (_, Instruction::Sti(StiArgs {
+                    offset9: pcoffset9, ..
+                })) => {
+                    let target = next.wrapping_add(pcoffset9.value());
+                    name(target, 3, format!("DATA_{:04X}", target));
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+        }
+
+        Self {
+            code,
+            labels: names
+                .into_iter()
+                .map(|(address, (_, label))| (label, address))
+                .collect(),
+        }
+    }
+}
+
+/// # A whole object file as a .ORIG/.END block of assembly
+pub struct Disassembly<'a> {
+    object: &'a ObjectFile,
+    symbols: &'a [(String, u16)],
+    syntax: Syntax,
+    flow: Option<&'a Flow>,
+    policy: DecodePolicy,
+}
+
+impl<'a> Disassembly<'a> {
+    /// # Every word is shown as code, unless it doesn't decode
+    pub fn new(object: &'a ObjectFile, symbols: &'a [(String, u16)], syntax: Syntax) -> Self {
+        Self {
+            object,
+            symbols,
+            syntax,
+            flow: None,
+            policy: DecodePolicy::default(),
+        }
+    }
+
+    /// # Words that only decode permissively are kept as .FILL, with what they decode to beside them
+    pub fn with_policy(mut self, policy: DecodePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// # Only words the flow reaches are shown as code, with its labels where symbols have none
+    pub fn following(mut self, flow: &'a Flow) -> Self {
+        self.flow = Some(flow);
+        self
+    }
+}
+
+/// # The string a run of words starting at words[0] spells, if they are printable chars ending in a 0
+///
+/// A string must not run across a label, since the label would be lost inside the .STRINGZ.
+fn string_at(words: &[u16], labelled: impl Fn(usize) -> bool) -> Option<(String, usize)> {
+    let mut text = String::new();
+    for (index, word) in words.iter().enumerate() {
+        if index > 0 && labelled(index) {
+            return None;
+        }
+        match *word {
+            0 if text.chars().count() >= 2 => return Some((text, index + 1)),
+            0x0a => text.push_str("\\n"),
+            0x09 => text.push_str("\\t"),
+            0x22 => text.push_str("\\\""),
+            0x5c => text.push_str("\\\\"),
+            0x20..=0x7e => text.push(*word as u8 as char),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// # Whether the assembler would take text as a label definition
+fn is_label(text: &str) -> bool {
+    is_valid_label(text) && !is_mnemonic(text) && parse_number(text).is_none()
+}
+
+impl Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let origin = self.object.origin;
+        let end = origin as usize + self.object.words.len();
+
+        // Only labels the block defines can be used in it, one per address and one address per label
+        let mut labels: Vec<(String, u16)> = Vec::new();
+        let made_up = self.flow.map_or(&[][..], |flow| &flow.labels[..]);
+        for (label, address) in self.symbols.iter().chain(made_up) {
+            let inside = (origin as usize..end).contains(&(*address as usize));
+            let taken = labels.iter().any(|(l, a)| l == label || a == address);
+            if inside && !taken && is_label(label) {
+                labels.push((label.clone(), *address));
+            }
+        }
+
+        writeln!(f, "        .ORIG x{:04X}", origin)?;
+        let words = &self.object.words;
+        let label_at = |address: u16| {
+            labels
+                .iter()
+                .find(|(_, a)| *a == address)
+                .map(|(label, _)| label.as_str())
+        };
+        let mut offset = 0;
+        while offset < words.len() {
+            let address = origin.wrapping_add(offset as u16);
+            let label = label_at(address).unwrap_or("");
+            let bits = words[offset];
+            let mut size = 1;
+            let is_code = match self.flow {
+                Some(flow) => flow.code.contains(&address),
+                None => true,
+            };
+            let (text, explanation) = match Instruction::decode_with(bits, &self.policy) {
+                Ok((instruction, ignored)) if is_code && ignored.is_empty() => (
+                    Canonical::new(&instruction, address, &labels).to_string(),
+                    instruction.to_string(),
+                ),
+                // Assembling the instruction would clear the fields it ignores, so the word stays as it is
+                Ok((instruction, _)) if is_code => (
+                    format!(
+                        ".FILL x{:04X} ; {}, decoded permissively",
+                        bits,
+                        Canonical::new(&instruction, address, &labels)
+                    ),
+                    instruction.to_string(),
+                ),
+                _ => {
+                    let unreached = |index: usize| {
+                        let at = address.wrapping_add(index as u16);
+                        self.flow.map_or(false, |flow| !flow.code.contains(&at))
+                    };
+                    let labelled = |index: usize| label_at(address.wrapping_add(index as u16)).is_some();
+                    let run = words[offset..]
+                        .iter()
+                        .enumerate()
+                        .take_while(|(index, _)| unreached(*index))
+                        .count();
+                    match string_at(&words[offset..offset + run], labelled) {
+                        Some((string, length)) => {
+                            size = length;
+                            (format!(".STRINGZ \"{}\"", string), String::new())
+                        }
+                        _ => (format!(".FILL x{:04X}", bits), String::new()),
+                    }
+                }
+            };
+            let line = match self.syntax {
+                Syntax::Explained if !explanation.is_empty() => {
+                    format!("{:<7} {:<24} ; {}", label, text, explanation)
+                }
+                _ => format!("{:<7} {}", label, text),
+            };
+            writeln!(f, "{}", line.trim_end())?;
+            offset += size;
+        }
+        writeln!(f, "        .END")
+    }
+}
+
+#[test]
+fn test_disassembly_assembles_to_the_same_words() {
+    use crate::asm::assemble;
+
+    let source = "
+        .ORIG x3000
+TOP     ADD R1, R2, R3
+        AND R4, R5, #-16
+        BRnz TOP
+        JMP R3
+        RET
+        JSR SUB
+        JSRR R4
+        LDI R1, DATA
+        LDR R2, R3, #-32
+        LEA R3, TOP
+        NOT R4, R5
+        STI R6, DATA
+        TRAP x26
+        HALT
+SUB     RTI
+DATA    .FILL x1018
+        .FILL x0005
+        .END
+    ";
+    let assembly = assemble(source).unwrap();
+    let object = &ObjectFile::from_assembly(&assembly)[0];
+    let text = Disassembly::new(object, &assembly.symbols, Syntax::Canonical).to_string();
+    assert!(text.contains("TOP     ADD R1, R2, R3\n"));
+    assert!(text.contains("        BRnz TOP\n        JMP R3\n        RET\n        JSR SUB\n"));
+    assert!(text.contains("        TRAP x26\n        HALT\n"));
+    assert!(text.contains("DATA    .FILL x1018\n        .FILL x0005\n"));
+    assert_eq!(assemble(&text).unwrap().segments, assembly.segments);
+
+    let explained = Disassembly::new(object, &[], Syntax::Explained).to_string();
+    assert!(explained.contains("        JSRR R4                  ; JSRR R4 ; PC -> R7, R4 -> PC\n"));
+    assert_eq!(assemble(&explained).unwrap().segments, assembly.segments);
+
+    // Every word that decodes comes back as itself
+    for bits in u16::MIN..=u16::MAX {
+        if let Ok(instruction) = Instruction::decode_bits(bits) {
+            let line = Canonical::new(&instruction, 0x3000, &[]).to_string();
+            let source = format!(".ORIG x3000\n{}\n.END", line);
+            assert_eq!(assemble(&source).unwrap().segments[0].1, vec![bits], "{}", line);
+        }
+    }
+}
+
+#[test]
+fn test_flow_separates_code_from_data() {
+    use crate::asm::assemble;
+
+    let source = "
+        .ORIG x3000
+        LEA R0, MSG
+        PUTS
+        LD R1, COUNT
+LOOP    JSR TICK
+        ADD R1, R1, #-1
+        BRp LOOP
+        HALT
+MSG     .STRINGZ \"Hi \\\"you\\\"\\n\"
+COUNT   .FILL 3
+TICK    ADD R2, R2, #1
+        RET
+        .FILL x1018
+        .END
+    ";
+    let assembly = assemble(source).unwrap();
+    let objects = ObjectFile::from_assembly(&assembly);
+    let flow = Flow::trace(&objects, &[0x3000], &DecodePolicy::default());
+    assert_eq!(flow.code.len(), 9);
+    let labels: Vec<(&str, u16)> = flow.labels.iter().map(|(l, a)| (l.as_str(), *a)).collect();
+    assert_eq!(
+        labels,
+        vec![("L_3003", 0x3003), ("DATA_3007", 0x3007), ("DATA_3011", 0x3011), ("SUB_3012", 0x3012)]
+    );
+
+    let text = Disassembly::new(&objects[0], &[], Syntax::Canonical)
+        .following(&flow)
+        .to_string();
+    let expected = r#"        .ORIG x3000
+        LEA R0, DATA_3007
+        PUTS
+        LD R1, DATA_3011
+L_3003  JSR SUB_3012
+        ADD R1, R1, #-1
+        BRp L_3003
+        HALT
+DATA_3007 .STRINGZ "Hi \"you\"\n"
+DATA_3011 .FILL x0003
+SUB_3012 ADD R2, R2, #1
+        RET
+        .FILL x1018
+        .END
+"#;
+    assert_eq!(text, expected);
+    assert_eq!(assemble(&text).unwrap().segments, assembly.segments);
+}
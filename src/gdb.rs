@@ -0,0 +1,516 @@
+use crate::devices::Display;
+use crate::lrc3::*;
+use crate::machine::{HaltReason, Machine};
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+/* A GDB remote serial protocol stub
+ *
+ * gdb has no LC-3 architecture, so the stub presents the machine as a plain
+ * 16 bit target:
+ *
+ * - registers are R0-R7 from the Regfile, then PC and PSR, each sent as two
+ *   big-endian bytes, the same byte order as .obj files
+ * - addresses are LC-3 word addresses, and reading n bytes from A returns
+ *   the words at A, A+1, ... as big-endian byte pairs
+ *
+ * Software breakpoints (Z0) and hardware breakpoints (Z1) are both checked
+ * before each fetch. Watchpoints (Z2 write, Z3 read, Z4 access) see the data
+ * accesses of LD, LDI, LDR, ST, STI and STR, and stop after the access.
+ * Whatever the program writes to the display while it runs is sent to gdb
+ * as console output (O packets), unless the display echoes to stdout itself.
+ */
+
+/// # How many instructions continue runs between checks for an interrupt from the debugger
+const INTERRUPT_POLL: usize = 4096;
+
+const REGISTERS: [RegisterName; 10] = [
+    RegisterName::R0,
+    RegisterName::R1,
+    RegisterName::R2,
+    RegisterName::R3,
+    RegisterName::R4,
+    RegisterName::R5,
+    RegisterName::R6,
+    RegisterName::R7,
+    RegisterName::PC,
+    RegisterName::PSR,
+];
+
+/// # A byte stream to the debugger
+pub trait Connection: Read + Write {
+    /// # Whether the debugger has sent an interrupt (^C) while the program runs
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let peeked = self.peek(&mut byte);
+        let _ = self.set_nonblocking(false);
+        match peeked {
+            Ok(1) if byte[0] == 0x03 => self.read_exact(&mut byte).is_ok(),
+            _ => false,
+        }
+    }
+}
+
+/// # gdb talking to the stub through a pipe, as in `target remote | lrc3 gdb --stdio prog.obj`
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Connection for Stdio {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Watch {
+    Write,
+    Read,
+    Access,
+}
+
+impl Watch {
+    fn stop_reason(self) -> &'static str {
+        match self {
+            Self::Write => "watch",
+            Self::Read => "rwatch",
+            Self::Access => "awatch",
+        }
+    }
+}
+
+pub struct GdbStub {
+    machine: Machine,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<(Watch, u16, u16)>,
+}
+
+impl GdbStub {
+    pub fn new(machine: Machine) -> Self {
+        Self {
+            machine,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// # Answers packets until the debugger detaches, kills the program or hangs up
+    pub fn serve(&mut self, connection: &mut impl Connection) -> io::Result<()> {
+        while let Some(packet) = receive(connection)? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    send(connection, "OK")?;
+                    return Ok(());
+                }
+                Some(b's') => self.resume(connection, true)?,
+                Some(b'c') => self.resume(connection, false)?,
+                _ => self.answer(&packet).unwrap_or_else(|| "E01".to_string()),
+            };
+            send(connection, &reply)?;
+        }
+        Ok(())
+    }
+
+    /// # The reply to every packet other than those that resume or end the session, None if it is malformed
+    fn answer(&mut self, packet: &str) -> Option<String> {
+        let command = packet.get(..1)?;
+        let rest = &packet[1..];
+        match command {
+            "?" => Some("S05".to_string()),
+            "g" => Some(REGISTERS.iter().map(|reg| format!("{:04x}", self.machine.register(*reg))).collect()),
+            "G" => {
+                let words = words_from_hex(rest)?;
+                for (reg, value) in REGISTERS.iter().zip(words) {
+                    self.machine.set_register(*reg, value);
+                }
+                Some("OK".to_string())
+            }
+            "p" => {
+                let reg = REGISTERS.get(usize::from_str_radix(rest, 16).ok()?)?;
+                Some(format!("{:04x}", self.machine.register(*reg)))
+            }
+            "P" => {
+                let (index, value) = rest.split_once('=')?;
+                let reg = REGISTERS.get(usize::from_str_radix(index, 16).ok()?)?;
+                self.machine.set_register(*reg, u16::from_str_radix(value, 16).ok()?);
+                Some("OK".to_string())
+            }
+            "m" => {
+                let (address, len) = address_and_len(rest)?;
+                let bytes: String = (0..(len as u32 + 1) / 2)
+                    .map(|offset| format!("{:04x}", self.machine.peek(address.wrapping_add(offset as u16))))
+                    .collect();
+                Some(bytes[..len as usize * 2].to_string())
+            }
+            "M" => {
+                let (range, data) = rest.split_once(':')?;
+                let (address, len) = address_and_len(range)?;
+                if data.len() != len as usize * 2 {
+                    return None;
+                }
+                for (offset, chunk) in data.as_bytes().chunks(4).enumerate() {
+                    let address = address.wrapping_add(offset as u16);
+                    let chunk = std::str::from_utf8(chunk).ok()?;
+                    let word = match chunk.len() {
+                        4 => u16::from_str_radix(chunk, 16).ok()?,
+                        // A trailing odd byte replaces only the high half of its word
+                        _ => u16::from_str_radix(chunk, 16).ok()? << 8 | (self.machine.peek(address) & 0xff),
+                    };
+                    self.machine.poke(address, word);
+                }
+                Some("OK".to_string())
+            }
+            "Z" | "z" => {
+                let mut fields = rest.splitn(3, ',');
+                let kind = fields.next()?;
+                let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+                let len = u16::from_str_radix(fields.next()?, 16).ok()?;
+                let insert = command == "Z";
+                let watch = match kind {
+                    "0" | "1" => {
+                        if insert {
+                            self.breakpoints.insert(address);
+                        } else {
+                            self.breakpoints.remove(&address);
+                        }
+                        return Some("OK".to_string());
+                    }
+                    "2" => Watch::Write,
+                    "3" => Watch::Read,
+                    "4" => Watch::Access,
+                    _ => return Some(String::new()),
+                };
+                let words = ((len as u32 + 1) / 2).max(1) as u16;
+                if insert {
+                    self.watchpoints.push((watch, address, words));
+                } else {
+                    self.watchpoints.retain(|w| *w != (watch, address, words));
+                }
+                Some("OK".to_string())
+            }
+            "H" => Some("OK".to_string()),
+            "q" => Some(
+                match rest {
+                    _ if rest.starts_with("Supported") => "PacketSize=1000;swbreak+;hwbreak+",
+                    "Attached" => "1",
+                    "fThreadInfo" => "m1",
+                    "sThreadInfo" => "l",
+                    "C" => "QC1",
+                    _ => "",
+                }
+                .to_string(),
+            ),
+            // Anything else is unsupported, which gdb is told with an empty reply
+            _ => Some(String::new()),
+        }
+    }
+
+    /// # Runs one instruction, or until a breakpoint, watchpoint, halt or interrupt, returning the stop reply
+    fn resume(&mut self, connection: &mut impl Connection, single_step: bool) -> io::Result<String> {
+        let mut steps = 0;
+        let reply = loop {
+            let watched = self.watched_access();
+            if let Some(reason) = self.machine.step() {
+                break match reason {
+                    HaltReason::Halted => "W00".to_string(),
+                    // SIGILL
+                    HaltReason::IllegalInstruction { .. } => "S04".to_string(),
+                    HaltReason::StepLimit => unreachable!("step never runs out of steps"),
+                };
+            }
+            if let Some((watch, address)) = watched {
+                break format!("T05{}:{:04x};", watch.stop_reason(), address);
+            }
+            if single_step || self.breakpoints.contains(&self.machine.pc()) {
+                break "S05".to_string();
+            }
+            steps += 1;
+            if steps % INTERRUPT_POLL == 0 {
+                self.forward_output(connection)?;
+                if connection.interrupted() {
+                    // SIGINT
+                    break "S02".to_string();
+                }
+            }
+        };
+        self.forward_output(connection)?;
+        Ok(reply)
+    }
+
+    /// # Sends what the program has displayed since the last call as console output, which gdb prints
+    fn forward_output(&mut self, connection: &mut impl Connection) -> io::Result<()> {
+        let output = match self.machine.devices_mut().get_mut::<Display>() {
+            Some(display) => display.take_output(),
+            None => return Ok(()),
+        };
+        // Two hex digits a byte, kept well inside the PacketSize offered to gdb
+        for chunk in output.chunks(0x400) {
+            let hex: String = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            send(connection, &format!("O{}", hex))?;
+        }
+        Ok(())
+    }
+
+    /// # The first watchpoint the instruction about to run will trigger, and the address it touches
+    fn watched_access(&self) -> Option<(Watch, u16)> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let (reads, writes) = self.data_accesses();
+        self.watchpoints.iter().find_map(|(watch, start, words)| {
+            let hit = |address: &&u16| address.wrapping_sub(*start) < *words;
+            let read = reads.iter().find(hit);
+            let written = writes.iter().find(hit);
+            match watch {
+                Watch::Write => written,
+                Watch::Read => read,
+                Watch::Access => read.or(written),
+            }
+            .map(|address| (*watch, *address))
+        })
+    }
+
+    /// # The addresses the instruction at PC is about to load from and store to
+    fn data_accesses(&self) -> (Vec<u16>, Vec<u16>) {
+        let machine = &self.machine;
+        let next = machine.pc().wrapping_add(1);
+        let reg = |reg: RegisterName| machine.register(reg);
+        match machine.decode(machine.peek(machine.pc())) {
+            Ok(Instruction::Ld(args)) => (vec![next.wrapping_add(args.pcoffset9.value())], vec![]),
+            Ok(Instruction::Ldi(args)) => {
+                let pointer = next.wrapping_add(args.pcoffset9.value());
+                (vec![pointer, machine.peek(pointer)], vec![])
+            }
+            Ok(Instruction::Ldr(args)) => (vec![reg(args.base_r).wrapping_add(args.offset6.value())], vec![]),
+            Ok(Instruction::St(args)) => (vec![], vec![next.wrapping_add(args.offset9.value())]),
+            Ok(Instruction::Sti(args)) => {
+                let pointer = next.wrapping_add(args.offset9.value());
+                (vec![pointer], vec![machine.peek(pointer)])
+            }
+            Ok(Instruction::Str(args)) => (vec![], vec![reg(args.base_r).wrapping_add(args.offset6.value())]),
+            _ => (vec![], vec![]),
+        }
+    }
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+/// # The next well formed packet's data, acknowledging it, or None once the debugger hangs up
+fn receive(connection: &mut impl Connection) -> io::Result<Option<String>> {
+    let mut byte = [0];
+    loop {
+        // Acks, and interrupts sent while the program is already stopped, are skipped
+        loop {
+            if connection.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            if connection.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        connection.read_exact(&mut sum)?;
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if expected == Some(checksum(&data)) {
+            connection.write_all(b"+")?;
+            return Ok(Some(data));
+        }
+        connection.write_all(b"-")?;
+        connection.flush()?;
+    }
+}
+
+fn send(connection: &mut impl Connection, data: &str) -> io::Result<()> {
+    write!(connection, "${}#{:02x}", data, checksum(data))?;
+    connection.flush()
+}
+
+fn address_and_len(text: &str) -> Option<(u16, u16)> {
+    let (address, len) = text.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+fn words_from_hex(text: &str) -> Option<Vec<u16>> {
+    text.as_bytes()
+        .chunks(4)
+        .map(|chunk| u16::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+struct Scripted {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+#[cfg(test)]
+impl Read for Scripted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for Scripted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Connection for Scripted {}
+
+#[test]
+fn test_gdb_stub_breaks_watches_and_reads_state() {
+    use crate::asm::assemble;
+    use crate::obj::ObjectFile;
+
+    let assembly = assemble(
+        "
+        .ORIG x3000
+        AND R1, R1, #0
+LOOP    ADD R1, R1, #1
+        ST R1, COUNT
+        ADD R2, R1, #-3
+        BRn LOOP
+        HALT
+COUNT   .FILL #0
+        .END
+    ",
+    )
+    .unwrap();
+    let machine = Machine::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap();
+    let mut stub = GdbStub::new(machine);
+
+    let packets = [
+        "qSupported:swbreak+",
+        "Z0,3001,2",
+        "c",
+        "p1",
+        "z0,3001,2",
+        "Z2,3006,2",
+        "c",
+        "m3006,2",
+        "z2,3006,2",
+        "P2=0007",
+        "g",
+        "c",
+    ];
+    let input: String = packets
+        .iter()
+        .map(|packet| format!("${}#{:02x}+", packet, checksum(packet)))
+        .collect();
+    let mut connection = Scripted {
+        input: io::Cursor::new(input.into_bytes()),
+        output: Vec::new(),
+    };
+    stub.serve(&mut connection).unwrap();
+
+    let output = String::from_utf8(connection.output).unwrap();
+    let replies: Vec<&str> = output
+        .split('$')
+        .skip(1)
+        .map(|reply| reply.split('#').next().unwrap())
+        .collect();
+    assert_eq!(
+        replies,
+        [
+            "PacketSize=1000;swbreak+;hwbreak+",
+            "OK",
+            // Stopped at LOOP before its first ADD, with R1 still cleared
+            "S05",
+            "0000",
+            "OK",
+            "OK",
+            "T05watch:3006;",
+            "0001",
+            "OK",
+            "OK",
+            "0000000100070000000000003000000030030001",
+            // HALT's message, as console output
+            "O0a2d2d2d2048616c74696e6720746865204c432d33202d2d2d0a",
+            "W00",
+        ]
+    );
+}
+
+#[test]
+fn test_gdb_stub_forwards_display_output() {
+    use crate::asm::assemble;
+    use crate::obj::ObjectFile;
+
+    let assembly = assemble(
+        "
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        HALT
+HELLO   .STRINGZ \"hi\"
+        .END
+    ",
+    )
+    .unwrap();
+    let machine = Machine::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap();
+    let mut connection = Scripted {
+        input: io::Cursor::new(format!("$c#{:02x}+", checksum("c")).into_bytes()),
+        output: Vec::new(),
+    };
+    GdbStub::new(machine).serve(&mut connection).unwrap();
+
+    let output = String::from_utf8(connection.output).unwrap();
+    let replies: Vec<&str> = output
+        .split('$')
+        .skip(1)
+        .map(|reply| reply.split('#').next().unwrap())
+        .collect();
+    assert_eq!(replies.last(), Some(&"W00"));
+    let console: Vec<u8> = replies
+        .iter()
+        .filter_map(|reply| reply.strip_prefix('O'))
+        .flat_map(|hex| hex.as_bytes().chunks(2))
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+        .collect();
+    assert_eq!(String::from_utf8(console).unwrap(), "hi\n--- Halting the LC-3 ---\n");
+}
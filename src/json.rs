@@ -0,0 +1,399 @@
+use core::fmt::{Display, Error, Formatter};
+use std::io::{self, BufRead, Write};
+
+/* Just enough JSON for the debug adapter and language server
+ *
+ * Both protocols frame each JSON message with a Content-Length header, so
+ * the framing lives here too. Objects keep their keys in insertion order,
+ * which keeps replies readable and tests exact.
+ */
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug)]
+pub enum JsonError {
+    UnexpectedEnd,
+    UnexpectedCharacter { offset: usize, found: char },
+    TrailingCharacters(usize),
+}
+
+impl Display for JsonError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::UnexpectedEnd => write!(f, "JSON ends too early"),
+            Self::UnexpectedCharacter { offset, found } => {
+                write!(f, "unexpected {:?} at byte {} of JSON", found, offset)
+            }
+            Self::TrailingCharacters(offset) => {
+                write!(f, "JSON value is followed by more text at byte {}", offset)
+            }
+        }
+    }
+}
+
+/// # An object from (key, value) pairs
+pub fn object(pairs: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        pairs
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Self {
+        Self::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Self {
+        Self::String(text)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Self::Number(value as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Self::Array(values)
+    }
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Self, JsonError> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            offset: 0,
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.offset != text.len() {
+            return Err(JsonError::TrailingCharacters(
+                parser.offset,
+            ));
+        }
+        Ok(value)
+    }
+
+    /// # The member called key, or Null if this isn't an object or has no such member
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Self::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value)
+                .unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Self::Number(value) if value.fract() == 0.0 => Some(*value as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// # The elements of an array, or none if this isn't one
+    pub fn elements(&self) -> &[Json] {
+        match self {
+            Self::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Null => write!(f, "null"),
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::Number(value) if value.fract() == 0.0 && value.abs() < 1e15 => {
+                write!(f, "{}", *value as i64)
+            }
+            Self::Number(value) => write!(f, "{}", value),
+            Self::String(text) => {
+                write!(f, "\"")?;
+                for c in text.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Self::Array(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Self::Object(members) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in members.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", Json::String(key.clone()), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    offset: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        match self.text.get(self.offset) {
+            Some(byte) => JsonError::UnexpectedCharacter {
+                offset: self.offset,
+                found: *byte as char,
+            },
+            None => JsonError::UnexpectedEnd,
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.text.get(self.offset) {
+            self.offset += 1;
+        }
+    }
+
+    fn expect(&mut self, literal: &str) -> Result<(), JsonError> {
+        if self.text[self.offset..].starts_with(literal.as_bytes()) {
+            self.offset += literal.len();
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, JsonError> {
+        self.whitespace();
+        match self.text.get(self.offset) {
+            Some(b'n') => self.expect("null").map(|_| Json::Null),
+            Some(b't') => self.expect("true").map(|_| Json::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.offset += 1;
+                let mut values = Vec::new();
+                self.whitespace();
+                if self.text.get(self.offset) == Some(&b']') {
+                    self.offset += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.whitespace();
+                    match self.text.get(self.offset) {
+                        Some(b',') => self.offset += 1,
+                        Some(b']') => {
+                            self.offset += 1;
+                            return Ok(Json::Array(values));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.offset += 1;
+                let mut members = Vec::new();
+                self.whitespace();
+                if self.text.get(self.offset) == Some(&b'}') {
+                    self.offset += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.whitespace();
+                    if self.text.get(self.offset) != Some(&b'"') {
+                        return Err(self.error());
+                    }
+                    let key = self.string()?;
+                    self.whitespace();
+                    self.expect(":")?;
+                    members.push((key, self.value()?));
+                    self.whitespace();
+                    match self.text.get(self.offset) {
+                        Some(b',') => self.offset += 1,
+                        Some(b'}') => {
+                            self.offset += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            Some(b'-') | Some(b'0'..=b'9') => {
+                let start = self.offset;
+                while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') =
+                    self.text.get(self.offset)
+                {
+                    self.offset += 1;
+                }
+                std::str::from_utf8(&self.text[start..self.offset])
+                    .ok()
+                    .and_then(|number| number.parse().ok())
+                    .map(Json::Number)
+                    .ok_or_else(|| {
+                        self.offset = start;
+                        self.error()
+                    })
+            }
+            _ => Err(self.error()),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, JsonError> {
+        // Skip the opening quote
+        self.offset += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.text.get(self.offset) {
+                None => return Err(self.error()),
+                Some(b'"') => {
+                    self.offset += 1;
+                    return Ok(String::from_utf8_lossy(&bytes).into_owned());
+                }
+                Some(b'\\') => {
+                    self.offset += 1;
+                    let escaped = match self.text.get(self.offset) {
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'u') => {
+                            let unit = |parser: &Self, at: usize| {
+                                parser
+                                    .text
+                                    .get(at..at + 4)
+                                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                            };
+                            let high = unit(self, self.offset + 1).ok_or_else(|| self.error())?;
+                            self.offset += 4;
+                            // Characters outside the BMP arrive as a surrogate pair
+                            let code = if (0xd800..0xdc00).contains(&high)
+                                && self.text.get(self.offset + 1..self.offset + 3) == Some(b"\\u")
+                            {
+                                let low = unit(self, self.offset + 3).ok_or_else(|| self.error())?;
+                                self.offset += 6;
+                                0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+                            } else {
+                                high
+                            };
+                            char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        Some(byte) => *byte as char,
+                        None => return Err(self.error()),
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(escaped.encode_utf8(&mut buffer).as_bytes());
+                    self.offset += 1;
+                }
+                Some(byte) => {
+                    bytes.push(*byte);
+                    self.offset += 1;
+                }
+            }
+        }
+    }
+}
+
+/// # The body of the next Content-Length framed message, or None at end of input
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[test]
+fn test_json_round_trips_and_frames() {
+    let text = r#"{"seq":1,"args":{"path":"a \"b\"\né😀","lines":[1,-2.5,true,null]}}"#;
+    let value = Json::parse(text).unwrap();
+    assert_eq!(value.get("args").get("path").as_str(), Some("a \"b\"\né😀"));
+    assert_eq!(value.get("args").get("lines").elements()[1], Json::Number(-2.5));
+    assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+    assert!(Json::parse("[1,]").is_err());
+
+    let mut framed = Vec::new();
+    write_message(&mut framed, &value).unwrap();
+    let mut reader = io::Cursor::new(framed);
+    let body = read_message(&mut reader).unwrap().unwrap();
+    assert_eq!(Json::parse(&body).unwrap(), value);
+    assert_eq!(read_message(&mut reader).unwrap(), None);
+}
@@ -0,0 +1,16 @@
+pub mod asm;
+pub mod cfg;
+pub mod dap;
+pub mod debugger;
+pub mod devices;
+pub mod difftest;
+pub mod disasm;
+pub mod gdb;
+pub mod json;
+pub mod lrc3;
+pub mod lsp;
+pub mod lst;
+pub mod machine;
+pub mod obj;
+pub mod os;
+pub mod sym;
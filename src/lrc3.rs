@@ -2,8 +2,11 @@
 //use std::vec::Vec;
 use core::fmt::{Display, Error, Formatter};
 use core::ops::{Add, BitAnd, Not};
+use crate::devices::Devices;
+use crate::obj::{ObjectError, ObjectFile};
+use crate::os;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum RegisterName {
     R0,
     R1,
@@ -17,10 +20,13 @@ pub enum RegisterName {
     IR,
     MDR,
     MAR,
+    PSR,
+    SavedSSP,
+    SavedUSP,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct RegisterContents(u16);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterContents(pub u16);
 
 fn width_mask(lsb: usize, msb: usize) -> (usize, u16) {
     if lsb > msb {
@@ -82,8 +88,8 @@ impl RegisterContents {
         Self::new(0)
     }
 
-    fn new(data: u16) -> Self {
-        Self { 0: data }
+    pub fn new(data: u16) -> Self {
+        Self(data)
     }
 
     fn zext(self, lsb: usize, msb: usize) -> Self {
@@ -91,7 +97,7 @@ impl RegisterContents {
     }
 
     fn sext(self, msb: usize) -> Self {
-        Self { 0: sext16(self.0, msb) }
+        Self(sext16(self.0, msb))
     }
 }
 
@@ -99,9 +105,7 @@ impl Add for RegisterContents {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        Self {
-            0: self.0 + other.0,
-        }
+        Self(self.0.wrapping_add(other.0))
     }
 }
 
@@ -134,6 +138,10 @@ impl RegisterName {
         }
     }
 
+    pub fn bits(&self) -> u16 {
+        self.index() as u16
+    }
+
     pub fn from_bits(bits: u16) -> Self {
         match bits & 0x7 {
             0 => Self::R0,
@@ -153,6 +161,7 @@ impl RegisterName {
 pub struct OpcodeAssumptionsViolation {
     msb: usize,
     lsb: usize,
+    #[allow(dead_code)]
     width: usize,
     mask: u16,
     expected: RegisterContents,
@@ -169,16 +178,21 @@ impl OpcodeAssumptionsViolation {
         let (width, mask) = width_mask(lsb, msb);
 
         Self {
-            lsb: lsb,
-            msb: msb,
-            width: width,
-            mask: mask,
+            lsb,
+            msb,
+            width,
+            mask,
             expected: RegisterContents::new(expected),
             actual: RegisterContents::new((actual & mask) >> lsb),
             opcode: opcode_name,
         }
     }
 
+    /// # bits with the offending field set to the value it should hold
+    pub fn repaired(&self, bits: u16) -> u16 {
+        (bits & !self.mask) | (self.expected.0 << self.lsb)
+    }
+
     //    fn check(&self) -> Option<Self> {
     //        // Checks pass if this returns None
     //        if self.expected == self.actual {
@@ -197,16 +211,16 @@ impl Display for OpcodeAssumptionsViolation {
             self.opcode,
             self.msb,
             self.lsb,
-            self.expected,
-            (self.actual & self.mask) >> self.lsb
+            self.expected.0,
+            self.actual.0
         )
     }
 }
 
 #[derive(Debug)]
 pub struct UnknownOpcodeArgs {
-    opcode: u16,
-    bits: u16,
+    pub opcode: u16,
+    pub bits: u16,
 }
 
 #[derive(Debug)]
@@ -241,15 +255,54 @@ pub struct Register {
     id: RegisterName,
 }
 
-struct Memory {
-    memory: [RegisterContents; 65536],
+/* Plain storage for all 64K words. Addresses with a device mapped over them
+ * are routed to Devices by whoever performs the access, so Memory only ever
+ * sees the rest.
+ */
+#[derive(Clone)]
+pub struct Memory {
+    // On the heap, so simulators holding a Memory can be moved around cheaply
+    memory: Box<[u16]>,
 }
 
 impl Memory {
     pub fn new() -> Self {
-        let mut memory = [RegisterContents::init(); 65536];
-        memory[0x3000] = RegisterContents::new(0xfe00);
-        Self { memory: memory }
+        Self {
+            memory: vec![0; 65536].into_boxed_slice(),
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u16 {
+        self.memory[address as usize]
+    }
+
+    pub fn write(&mut self, address: u16, data: u16) {
+        self.memory[address as usize] = data;
+    }
+
+    /// # Lowest address at which the two memories hold different words
+    pub fn first_difference(&self, other: &Memory) -> Option<u16> {
+        if self.memory == other.memory {
+            return None;
+        }
+        self.memory
+            .iter()
+            .zip(other.memory.iter())
+            .position(|(a, b)| a != b)
+            .map(|address| address as u16)
+    }
+
+    /// # Copies words into memory starting at origin
+    pub fn load(&mut self, origin: u16, words: &[u16]) {
+        for (offset, word) in words.iter().enumerate() {
+            self.write(origin.wrapping_add(offset as u16), *word);
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -261,7 +314,7 @@ impl Register {
     pub fn zeroed(id: RegisterName) -> Self {
         Self {
             content: RegisterContents::init(),
-            id: id,
+            id,
         }
     }
 
@@ -300,6 +353,120 @@ impl Regfile {
     }
 }
 
+impl Default for Regfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// # Address the PSR is mapped to, so programs can read and write it
+pub const PSR_ADDRESS: u16 = 0xfffc;
+
+/// # Base of the interrupt vector table, indexed by the 8 bit interrupt or exception vector
+pub const INTERRUPT_TABLE: u16 = 0x0100;
+
+/// # Supervisor stack pointer before any OS has set one, just below user space
+pub const INITIAL_SSP: u16 = 0x3000;
+
+/// # User space is x3000-xFDFF; everything else is system space, including the device registers
+pub const USER_SPACE: core::ops::Range<u16> = 0x3000..0xfe00;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Privilege {
+    Supervisor,
+    User,
+}
+
+/// # Processor Status Register: PSR[15] privilege, PSR[10:8] priority, PSR[2:0] NZP
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Psr {
+    pub privilege: Privilege,
+    pub priority: u16,
+    pub n: bool,
+    pub z: bool,
+    pub p: bool,
+}
+
+impl Psr {
+    pub fn from_bits(bits: u16) -> Self {
+        Self {
+            privilege: match mask_out(bits, 15, 15) {
+                0 => Privilege::Supervisor,
+                _ => Privilege::User,
+            },
+            priority: mask_out(bits, 8, 10),
+            n: mask_out(bits, 2, 2) == 1,
+            z: mask_out(bits, 1, 1) == 1,
+            p: mask_out(bits, 0, 0) == 1,
+        }
+    }
+
+    pub fn bits(&self) -> u16 {
+        ((self.privilege == Privilege::User) as u16) << 15
+            | (self.priority & 0b111) << 8
+            | (self.n as u16) << 2
+            | (self.z as u16) << 1
+            | self.p as u16
+    }
+}
+
+impl Display for Psr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(
+            f,
+            "PSR[{:04x}] {:?} PL{} {}{}{}",
+            self.bits(),
+            self.privilege,
+            self.priority,
+            if self.n { "n" } else { "-" },
+            if self.z { "z" } else { "-" },
+            if self.p { "p" } else { "-" }
+        )
+    }
+}
+
+#[test]
+fn test_psr_bits() {
+    let psr = Psr::from_bits(0x8602);
+    assert_eq!(psr.privilege, Privilege::User);
+    assert_eq!(psr.priority, 6);
+    assert_eq!((psr.n, psr.z, psr.p), (false, true, false));
+    assert_eq!(psr.bits(), 0x8602);
+}
+
+/// # An interrupt request: the vector to take and the priority it is raised at
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interrupt {
+    pub vector: u8,
+    pub priority: u16,
+}
+
+/// # Exceptions, raised through the interrupt vector table like interrupts but without changing priority
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Exception {
+    /// RTI executed in user mode
+    PrivilegeViolation,
+    /// Opcode 1101, which is reserved
+    IllegalOpcode,
+    /// A user mode access to system space
+    AccessControlViolation,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Self::PrivilegeViolation => 0x00,
+            Self::IllegalOpcode => 0x01,
+            Self::AccessControlViolation => 0x02,
+        }
+    }
+}
+
+/// # ACV: whether a program running at privilege may not touch address
+pub fn access_violation(privilege: Privilege, address: u16) -> bool {
+    privilege == Privilege::User && !USER_SPACE.contains(&address)
+}
+
 //trait SetCc{};
 //trait PcOffset9{};
 //trait PcOffset11{};
@@ -311,7 +478,7 @@ impl Not for GateFlag {
     type Output = Self;
 
     fn not(self) -> Self {
-        Self { 0: !self.0 }
+        Self(!self.0)
     }
 }
 
@@ -325,16 +492,21 @@ struct OneBitMux(bool);
 struct TwoBitMux(u8);
 
 #[derive(Debug)]
-struct PcOffset9(u16);
+pub struct PcOffset9(u16);
 
 impl PcOffset9 {
     pub fn new(bits: u16) -> Self {
-        Self{ 0: sext16(bits & 0x1ff, 8) }
+        Self(sext16(bits & 0x1ff, 8))
     }
 
     pub fn masked(&self) -> u16 {
         self.0 & 0x1ff
     }
+
+    /// # The offset sign extended to 16 bits
+    pub fn value(&self) -> u16 {
+        self.0
+    }
 }
 
 impl Display for PcOffset9 {
@@ -345,16 +517,21 @@ impl Display for PcOffset9 {
 }
 
 #[derive(Debug)]
-struct PcOffset11(u16);
+pub struct PcOffset11(u16);
 
 impl PcOffset11 {
     pub fn new(bits: u16) -> Self {
-        Self{ 0: sext16(bits & 0x7ff, 10) }
+        Self(sext16(bits & 0x7ff, 10))
     }
 
     pub fn masked(&self) -> u16 {
         self.0 & 0x7ff
     }
+
+    /// # The offset sign extended to 16 bits
+    pub fn value(&self) -> u16 {
+        self.0
+    }
 }
 
 impl Display for PcOffset11 {
@@ -369,7 +546,16 @@ pub struct Imm5(u16);
 
 impl Imm5 {
     pub fn new(bits: u16) -> Self {
-        Self{ 0: sext16(bits & 0x1f, 4) }
+        Self(sext16(bits & 0x1f, 4))
+    }
+
+    pub fn masked(&self) -> u16 {
+        self.0 & 0x1f
+    }
+
+    /// # The immediate sign extended to 16 bits
+    pub fn value(&self) -> u16 {
+        self.0
     }
 }
 
@@ -385,16 +571,21 @@ fn test_imm5_display() {
 }
 
 #[derive(Debug)]
-struct Offset6(u16);
+pub struct Offset6(u16);
 
 impl Offset6 {
     pub fn new(bits: u16) -> Self {
-        Self{ 0: sext16(bits & 0x3f, 5) }
+        Self(sext16(bits & 0x3f, 5))
     }
 
     pub fn masked(&self) -> u16 {
         self.0 & 0x3f
     }
+
+    /// # The offset sign extended to 16 bits
+    pub fn value(&self) -> u16 {
+        self.0
+    }
 }
 
 impl Display for Offset6 {
@@ -404,16 +595,26 @@ impl Display for Offset6 {
 }
 
 #[derive(Debug)]
-struct TrapVect(u16);
+pub struct TrapVect(u16);
+
+impl TrapVect {
+    pub fn new(bits: u16) -> Self {
+        Self(bits & 0xff)
+    }
+
+    pub fn masked(&self) -> u16 {
+        self.0 & 0xff
+    }
+}
 
 #[derive(Debug)]
-struct BranchFlag(bool);
+pub struct BranchFlag(pub bool);
 
 #[derive(Debug)]
-struct TwoSourceArithArgs {
-    dr: RegisterName,
-    sr1: RegisterName,
-    sr2: RegisterName,
+pub struct TwoSourceArithArgs {
+    pub dr: RegisterName,
+    pub sr1: RegisterName,
+    pub sr2: RegisterName,
 }
 
 impl Display for TwoSourceArithArgs {
@@ -423,9 +624,9 @@ impl Display for TwoSourceArithArgs {
 }
 
 #[derive(Debug)]
-struct OneSourceArithArgs {
-    dr: RegisterName,
-    sr: RegisterName,
+pub struct OneSourceArithArgs {
+    pub dr: RegisterName,
+    pub sr: RegisterName,
 }
 
 impl Display for OneSourceArithArgs {
@@ -435,10 +636,10 @@ impl Display for OneSourceArithArgs {
 }
 
 #[derive(Debug)]
-struct ImmedArithArgs {
-    dr: RegisterName,
-    sr1: RegisterName,
-    imm5: Imm5,
+pub struct ImmedArithArgs {
+    pub dr: RegisterName,
+    pub sr1: RegisterName,
+    pub imm5: Imm5,
 }
 
 impl Display for ImmedArithArgs {
@@ -448,11 +649,11 @@ impl Display for ImmedArithArgs {
 }
 
 #[derive(Debug)]
-struct BranchArgs {
-    n: BranchFlag,
-    z: BranchFlag,
-    p: BranchFlag,
-    pcoffset9: PcOffset9,
+pub struct BranchArgs {
+    pub n: BranchFlag,
+    pub z: BranchFlag,
+    pub p: BranchFlag,
+    pub pcoffset9: PcOffset9,
 }
 
 impl Display for BranchArgs {
@@ -469,8 +670,8 @@ impl Display for BranchArgs {
 }
 
 #[derive(Debug)]
-struct BaseRArgs {
-    base_r: RegisterName,
+pub struct BaseRArgs {
+    pub base_r: RegisterName,
 }
 
 impl Display for BaseRArgs {
@@ -480,8 +681,8 @@ impl Display for BaseRArgs {
 }
 
 #[derive(Debug)]
-struct TrapArgs {
-    trapvect8: TrapVect,
+pub struct TrapArgs {
+    pub trapvect8: TrapVect,
 }
 
 impl Display for TrapArgs {
@@ -491,8 +692,8 @@ impl Display for TrapArgs {
 }
 
 #[derive(Debug)]
-struct JsrArgs {
-    pcoffset11: PcOffset11,
+pub struct JsrArgs {
+    pub pcoffset11: PcOffset11,
 }
 
 impl Display for JsrArgs {
@@ -506,9 +707,9 @@ impl Display for JsrArgs {
 }
 
 #[derive(Debug)]
-struct LdArgs {
-    dr: RegisterName,
-    pcoffset9: PcOffset9,
+pub struct LdArgs {
+    pub dr: RegisterName,
+    pub pcoffset9: PcOffset9,
 }
 
 impl Display for LdArgs {
@@ -522,9 +723,9 @@ impl Display for LdArgs {
 }
 
 #[derive(Debug)]
-struct LdiArgs {
-    dr: RegisterName,
-    pcoffset9: PcOffset9,
+pub struct LdiArgs {
+    pub dr: RegisterName,
+    pub pcoffset9: PcOffset9,
 }
 
 impl Display for LdiArgs {
@@ -538,9 +739,9 @@ impl Display for LdiArgs {
 }
 
 #[derive(Debug)]
-struct LeaArgs {
-    dr: RegisterName,
-    pcoffset9: PcOffset9,
+pub struct LeaArgs {
+    pub dr: RegisterName,
+    pub pcoffset9: PcOffset9,
 }
 
 impl Display for LeaArgs {
@@ -554,10 +755,10 @@ impl Display for LeaArgs {
 }
 
 #[derive(Debug)]
-struct LdrArgs {
-    dr: RegisterName,
-    base_r: RegisterName,
-    offset6: Offset6,
+pub struct LdrArgs {
+    pub dr: RegisterName,
+    pub base_r: RegisterName,
+    pub offset6: Offset6,
 }
 
 impl Display for LdrArgs {
@@ -576,10 +777,10 @@ impl Display for LdrArgs {
 }
 
 #[derive(Debug)]
-struct StrArgs {
-    sr: RegisterName,
-    base_r: RegisterName,
-    offset6: Offset6,
+pub struct StrArgs {
+    pub sr: RegisterName,
+    pub base_r: RegisterName,
+    pub offset6: Offset6,
 }
 
 impl Display for StrArgs {
@@ -593,9 +794,9 @@ impl Display for StrArgs {
 }
 
 #[derive(Debug)]
-struct StArgs {
-    sr: RegisterName,
-    offset9: PcOffset9,
+pub struct StArgs {
+    pub sr: RegisterName,
+    pub offset9: PcOffset9,
 }
 
 impl Display for StArgs {
@@ -609,9 +810,9 @@ impl Display for StArgs {
 }
 
 #[derive(Debug)]
-struct StiArgs {
-    sr: RegisterName,
-    offset9: PcOffset9,
+pub struct StiArgs {
+    pub sr: RegisterName,
+    pub offset9: PcOffset9,
 }
 
 impl Display for StiArgs {
@@ -681,8 +882,8 @@ impl Display for Instruction {
             Self::Jsr(args) => {
                 write!(f, "JSR {}", args)
             }
-            Self::Jsrr(_args) => {
-                write!(f, "JSRR UNIMPLEMENTED")
+            Self::Jsrr(args) => {
+                write!(f, "JSRR {:?} ; PC -> R7, {:?} -> PC", args.base_r, args.base_r)
             }
             Self::Ld(args) => {
                 write!(f, "LD {}", args)
@@ -777,7 +978,7 @@ impl Instruction {
                     0b1 => Ok(Instruction::Addi(ImmedArithArgs {
                         dr: reg9to11,
                         sr1: reg6to8,
-                        imm5: imm5,
+                        imm5,
                     })),
                     _ => unreachable!(),
                 }
@@ -804,7 +1005,7 @@ impl Instruction {
                     0b1 => Ok(Instruction::Andi(ImmedArithArgs {
                         dr: reg9to11,
                         sr1: reg6to8,
-                        imm5: imm5,
+                        imm5,
                     })),
                     _ => unreachable!(),
                 }
@@ -812,9 +1013,9 @@ impl Instruction {
             /* opcode 0b0000: BR(branch)
              * BR      : n; z; p; pcoffset9 */
             0b0000 => Ok(Instruction::Br(BranchArgs {
-                n: n,
-                z: z,
-                p: p,
+                n,
+                z,
+                p,
                 pcoffset9: off9,
             })),
             /* opcode 0b1100: JMP
@@ -898,6 +1099,17 @@ impl Instruction {
                     sr: reg6to8,
                 }))
             }
+            /* opcode 0b1000: RTI
+             * RTI     : 0b000000000000
+             */
+            0b1000 => {
+                if mask_out(bits, 0, 11) != 0b0 {
+                    return Err(Lrc3Error::IllegalOpcode(OpcodeAssumptionsViolation::new(
+                        0, 11, 0b0, bits, "RTI",
+                    )));
+                }
+                Ok(Instruction::Rti())
+            }
             /* opcode 0b0011: ST
              * ST      : sr; pcoffset9
              */
@@ -926,21 +1138,67 @@ impl Instruction {
             0b1111 => {
                 if mask_out(bits, 8, 11) != 0b0 {
                     return Err(Lrc3Error::IllegalOpcode(OpcodeAssumptionsViolation::new(
-                        8, 11, 0b0, bits, "JSRR",
+                        8, 11, 0b0, bits, "TRAP",
                     )));
                 }
                 Ok(Instruction::Trap(TrapArgs { trapvect8: trap8 }))
             }
             _ => Err(Lrc3Error::UnknownOpcode(UnknownOpcodeArgs {
-                opcode: opcode,
-                bits: bits,
+                opcode,
+                bits,
             })),
         }
     }
 
-    pub fn decode_ir(ir: &Register) -> Self {
+    /// # Inverse of decode_bits: packs the instruction back into its 16 bit encoding
+    pub fn encode_bits(&self) -> u16 {
+        /* Fields are placed using the same layout as the legend in decode_bits,
+         * with all constant bits filled in with their required values
+         */
+        let op = |opcode: u16| opcode << 12;
+        let dr = |reg: &RegisterName| reg.bits() << 9;
+        let sr1 = |reg: &RegisterName| reg.bits() << 6;
+        let flag = |flag: &BranchFlag, bit: u16| (flag.0 as u16) << bit;
+
+        match self {
+            Self::Add(args) => op(0b0001) | dr(&args.dr) | sr1(&args.sr1) | args.sr2.bits(),
+            Self::Addi(args) => {
+                op(0b0001) | dr(&args.dr) | sr1(&args.sr1) | (0b1 << 5) | args.imm5.masked()
+            }
+            Self::And(args) => op(0b0101) | dr(&args.dr) | sr1(&args.sr1) | args.sr2.bits(),
+            Self::Andi(args) => {
+                op(0b0101) | dr(&args.dr) | sr1(&args.sr1) | (0b1 << 5) | args.imm5.masked()
+            }
+            Self::Br(args) => {
+                op(0b0000)
+                    | flag(&args.n, 11)
+                    | flag(&args.z, 10)
+                    | flag(&args.p, 9)
+                    | args.pcoffset9.masked()
+            }
+            Self::Jmp(args) => op(0b1100) | sr1(&args.base_r),
+            Self::Jsr(args) => op(0b0100) | (0b1 << 11) | args.pcoffset11.masked(),
+            Self::Jsrr(args) => op(0b0100) | sr1(&args.base_r),
+            Self::Ld(args) => op(0b0010) | dr(&args.dr) | args.pcoffset9.masked(),
+            Self::Ldi(args) => op(0b1010) | dr(&args.dr) | args.pcoffset9.masked(),
+            Self::Ldr(args) => {
+                op(0b0110) | dr(&args.dr) | sr1(&args.base_r) | args.offset6.masked()
+            }
+            Self::Lea(args) => op(0b1110) | dr(&args.dr) | args.pcoffset9.masked(),
+            Self::Not(args) => op(0b1001) | dr(&args.dr) | sr1(&args.sr) | 0b111111,
+            Self::Rti() => op(0b1000),
+            Self::St(args) => op(0b0011) | dr(&args.sr) | args.offset9.masked(),
+            Self::Sti(args) => op(0b1011) | dr(&args.sr) | args.offset9.masked(),
+            Self::Str(args) => {
+                op(0b0111) | dr(&args.sr) | sr1(&args.base_r) | args.offset6.masked()
+            }
+            Self::Trap(args) => op(0b1111) | args.trapvect8.masked(),
+        }
+    }
+
+    pub fn decode_ir(ir: &Register) -> Result<Self, Lrc3Error> {
         match ir.id {
-            RegisterName::IR => Self::decode_bits(ir.content.0).expect("unhandled decode ir"),
+            RegisterName::IR => Self::decode_bits(ir.content.0),
             _ => panic!(
                 "Not allowed to build opcode from register ({:?}) that isn't IR",
                 ir.id
@@ -949,6 +1207,285 @@ impl Instruction {
     }
 }
 
+#[test]
+fn test_encode_inverts_decode() {
+    for bits in u16::MIN..=u16::MAX {
+        if let Ok(ins) = Instruction::decode_bits(bits) {
+            assert_eq!(
+                ins.encode_bits(),
+                bits,
+                "{} re-encoded as {:016b}, expected {:016b}",
+                ins,
+                ins.encode_bits(),
+                bits
+            );
+        }
+    }
+}
+
+/* What an instruction does, for analysis tools
+ *
+ * The two editions of Patt & Patel's LC-3 differ in a couple of places:
+ * the second has LEA set the condition codes and TRAP link through R7, the
+ * third leaves the condition codes alone on LEA and has TRAP push the PSR
+ * and PC onto the supervisor stack, as RTI pops them, through R6. Only
+ * R0-R7 are reported as read or written; the PC, PSR and condition codes
+ * are covered by the control-flow and sets_cc questions instead.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Edition {
+    #[default]
+    Second,
+    Third,
+}
+
+/* How to decode words that break a fixed field
+ *
+ * ADD and AND (IR[4:3]), JMP and JSRR (IR[11:9] and IR[5:0]), NOT (IR[5:0]),
+ * RTI (IR[11:0]) and TRAP (IR[11:8]) each have fields the ISA fixes, but
+ * which the datapath never looks at. Strict decoding rejects words that
+ * break them, as they are usually a mistake. Permissive decoding runs them
+ * as the hardware would, as if the fields held their required values, and
+ * reports each field it had to ignore.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Strictness {
+    #[default]
+    Strict,
+    Permissive,
+}
+
+/// # How the simulator, disassembler and assembler read the ISA
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DecodePolicy {
+    pub strictness: Strictness,
+    pub edition: Edition,
+}
+
+impl DecodePolicy {
+    pub fn permissive() -> Self {
+        Self {
+            strictness: Strictness::Permissive,
+            ..Self::default()
+        }
+    }
+}
+
+impl Instruction {
+    /// # Decodes bits under policy, with every fixed field it ignored to do so
+    pub fn decode_with(bits: u16, policy: &DecodePolicy) -> Result<(Self, Vec<OpcodeAssumptionsViolation>), Lrc3Error> {
+        let mut ignored = Vec::new();
+        let mut bits = bits;
+        loop {
+            match Self::decode_bits(bits) {
+                Err(Lrc3Error::IllegalOpcode(violation)) if policy.strictness == Strictness::Permissive => {
+                    ignored.push(violation);
+                    bits = violation.repaired(bits);
+                }
+                decoded => return decoded.map(|instruction| (instruction, ignored)),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Load,
+    Store,
+    /// Loads a pointer, then loads through it
+    IndirectLoad,
+    /// Loads a pointer, then stores through it
+    IndirectStore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Addressing {
+    /// Incremented PC plus an offset
+    PcRelative,
+    /// A base register plus an offset
+    BaseRelative,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub addressing: Addressing,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlFlow {
+    /// On to the next word, as after a branch with no flags set
+    FallThrough,
+    /// Always somewhere else: BRnzp, and JMP through anything but R7
+    Jump,
+    /// Somewhere else or on to the next word, depending on the condition codes
+    Conditional,
+    /// JSR and JSRR, which link back to the next word
+    Call,
+    /// RET, which is JMP R7
+    Return,
+    Trap,
+    Rti,
+}
+
+impl Instruction {
+    /// # The general purpose registers read
+    pub fn registers_read(&self, edition: Edition) -> Vec<RegisterName> {
+        use RegisterName::*;
+        match self {
+            Self::Add(args) | Self::And(args) => vec![args.sr1, args.sr2],
+            Self::Addi(args) | Self::Andi(args) => vec![args.sr1],
+            Self::Not(args) => vec![args.sr],
+            Self::Jmp(args) | Self::Jsrr(args) => vec![args.base_r],
+            Self::Ldr(args) => vec![args.base_r],
+            Self::St(args) => vec![args.sr],
+            Self::Sti(args) => vec![args.sr],
+            Self::Str(args) => vec![args.sr, args.base_r],
+            Self::Rti() => vec![R6],
+            Self::Trap(_) if edition == Edition::Third => vec![R6],
+            Self::Br(_) | Self::Jsr(_) | Self::Ld(_) | Self::Ldi(_) | Self::Lea(_) | Self::Trap(_) => vec![],
+        }
+    }
+
+    /// # The general purpose registers written
+    pub fn registers_written(&self, edition: Edition) -> Vec<RegisterName> {
+        use RegisterName::*;
+        match self {
+            Self::Add(args) | Self::And(args) => vec![args.dr],
+            Self::Addi(args) | Self::Andi(args) => vec![args.dr],
+            Self::Not(args) => vec![args.dr],
+            Self::Ld(args) => vec![args.dr],
+            Self::Ldi(args) => vec![args.dr],
+            Self::Ldr(args) => vec![args.dr],
+            Self::Lea(args) => vec![args.dr],
+            Self::Jsr(_) | Self::Jsrr(_) => vec![R7],
+            Self::Rti() => vec![R6],
+            Self::Trap(_) => match edition {
+                Edition::Second => vec![R7],
+                Edition::Third => vec![R6],
+            },
+            Self::Br(_) | Self::Jmp(_) | Self::St(_) | Self::Sti(_) | Self::Str(_) => vec![],
+        }
+    }
+
+    /// # Whether the condition codes are set from the value written
+    pub fn sets_cc(&self, edition: Edition) -> bool {
+        match self {
+            Self::Add(_) | Self::Addi(_) | Self::And(_) | Self::Andi(_) | Self::Not(_) => true,
+            Self::Ld(_) | Self::Ldi(_) | Self::Ldr(_) => true,
+            Self::Lea(_) => edition == Edition::Second,
+            _ => false,
+        }
+    }
+
+    /// # How the instruction reaches data memory, if it does
+    ///
+    /// The trap vector table read by TRAP and the stack popped by RTI aren't counted.
+    pub fn memory_access(&self) -> Option<MemoryAccess> {
+        let access = |kind, addressing| Some(MemoryAccess { kind, addressing });
+        match self {
+            Self::Ld(_) => access(AccessKind::Load, Addressing::PcRelative),
+            Self::Ldi(_) => access(AccessKind::IndirectLoad, Addressing::PcRelative),
+            Self::Ldr(_) => access(AccessKind::Load, Addressing::BaseRelative),
+            Self::St(_) => access(AccessKind::Store, Addressing::PcRelative),
+            Self::Sti(_) => access(AccessKind::IndirectStore, Addressing::PcRelative),
+            Self::Str(_) => access(AccessKind::Store, Addressing::BaseRelative),
+            _ => None,
+        }
+    }
+
+    pub fn control_flow(&self) -> ControlFlow {
+        match self {
+            Self::Br(args) => match (args.n.0, args.z.0, args.p.0) {
+                (false, false, false) => ControlFlow::FallThrough,
+                (true, true, true) => ControlFlow::Jump,
+                _ => ControlFlow::Conditional,
+            },
+            Self::Jmp(BaseRArgs {
+                base_r: RegisterName::R7,
+            }) => ControlFlow::Return,
+            Self::Jmp(_) => ControlFlow::Jump,
+            Self::Jsr(_) | Self::Jsrr(_) => ControlFlow::Call,
+            Self::Trap(_) => ControlFlow::Trap,
+            Self::Rti() => ControlFlow::Rti,
+            _ => ControlFlow::FallThrough,
+        }
+    }
+
+    /// # Where a branch or JSR at address goes when it does, which is known without running
+    ///
+    /// Jumps through registers, traps and RTI go where memory or a register says, so have none.
+    pub fn target(&self, address: u16) -> Option<u16> {
+        let pc = address.wrapping_add(1);
+        match self {
+            Self::Br(args) if self.control_flow() != ControlFlow::FallThrough => {
+                Some(pc.wrapping_add(args.pcoffset9.value()))
+            }
+            Self::Jsr(args) => Some(pc.wrapping_add(args.pcoffset11.value())),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_instruction_metadata() {
+    use RegisterName::*;
+
+    let decode = |bits| Instruction::decode_bits(bits).unwrap();
+    // ADD R1, R2, R3
+    let add = decode(0x1283);
+    assert_eq!(add.registers_read(Edition::Second), vec![R2, R3]);
+    assert_eq!(add.registers_written(Edition::Second), vec![R1]);
+    assert!(add.sets_cc(Edition::Third));
+
+    // LEA R0, #2 sets the condition codes in the second edition only
+    let lea = decode(0xe002);
+    assert!(lea.sets_cc(Edition::Second) && !lea.sets_cc(Edition::Third));
+    assert_eq!(lea.memory_access(), None);
+
+    // STR R7, R0, #31 and LDI R1, #-1
+    let access = decode(0x7e1f).memory_access().unwrap();
+    assert_eq!((access.kind, access.addressing), (AccessKind::Store, Addressing::BaseRelative));
+    assert_eq!(decode(0xa3ff).memory_access().unwrap().kind, AccessKind::IndirectLoad);
+
+    // BRz #-3, BRnzp #4, BR with no flags, RET, JMP R3, JSR #-9, JSRR R4, HALT and RTI
+    assert_eq!(decode(0x05fd).control_flow(), ControlFlow::Conditional);
+    assert_eq!(decode(0x05fd).target(0x3010), Some(0x300e));
+    assert_eq!(decode(0x0e04).control_flow(), ControlFlow::Jump);
+    assert_eq!(decode(0x0004).control_flow(), ControlFlow::FallThrough);
+    assert_eq!(decode(0x0004).target(0x3000), None);
+    assert_eq!(decode(0xc1c0).control_flow(), ControlFlow::Return);
+    assert_eq!(decode(0xc0c0).control_flow(), ControlFlow::Jump);
+    assert_eq!(decode(0x4ff7).target(0x3008), Some(0x3000));
+    assert_eq!(decode(0x4100).registers_written(Edition::Third), vec![R7]);
+    assert_eq!(decode(0x4100).target(0x3000), None);
+    let halt = decode(0xf025);
+    assert_eq!(halt.control_flow(), ControlFlow::Trap);
+    assert_eq!(halt.registers_written(Edition::Second), vec![R7]);
+    assert_eq!(halt.registers_written(Edition::Third), vec![R6]);
+    assert_eq!(decode(0x8000).control_flow(), ControlFlow::Rti);
+}
+
+#[test]
+fn test_permissive_decoding_ignores_fixed_fields() {
+    // JMP R3 with IR[11:9] and IR[5:0] both set
+    let bits = 0xcec1;
+    assert!(matches!(
+        Instruction::decode_with(bits, &DecodePolicy::default()),
+        Err(Lrc3Error::IllegalOpcode(_))
+    ));
+    let (instruction, ignored) = Instruction::decode_with(bits, &DecodePolicy::permissive()).unwrap();
+    assert_eq!(instruction.encode_bits(), 0xc0c0);
+    assert_eq!(ignored.len(), 2);
+    assert_eq!(
+        ignored[0].to_string(),
+        "Illegally encoded JMP instruction: IR[5:0] should be 0, but is 1"
+    );
+    assert!(Instruction::decode_with(0xd000, &DecodePolicy::permissive()).is_err());
+}
+
 #[derive(Debug)]
 pub struct Datapath {
     regfile: Regfile,
@@ -956,29 +1493,65 @@ pub struct Datapath {
     ld_pc: LoadFlag,
     ld_ir: LoadFlag,
     ld_mar: LoadFlag,
+    ld_mdr: LoadFlag,
     ld_reg: LoadFlag,
     ld_cc: LoadFlag,
-
+    ld_ben: LoadFlag,
+    ld_priv: LoadFlag,
+    ld_priority: LoadFlag,
+    ld_saved_ssp: LoadFlag,
+    ld_saved_usp: LoadFlag,
+    ld_vector: LoadFlag,
+    ld_table: LoadFlag,
+
+    // n, z and p together with privilege and priority make up the PSR
     n: BranchFlag,
     z: BranchFlag,
     p: BranchFlag,
+    ben: BranchFlag,
+    privilege: Privilege,
+    priority: u16,
+
+    // the INT signal, raised by request_interrupt and cleared once serviced,
+    // or held by a device for as long as it wants service
+    interrupt: Option<Interrupt>,
+    device_interrupt: Option<Interrupt>,
 
     // derive the bus value from gate signals
     gate_pc: GateFlag,
+    gate_mdr: GateFlag,
     gate_marmux: GateFlag,
     gate_alu: GateFlag,
+    gate_sp: GateFlag,
+    gate_psr: GateFlag,
+    gate_vector: GateFlag,
 
     pc_mux: TwoBitMux,
+    dr_mux: TwoBitMux,
+    sr1_mux: TwoBitMux,
     addr1_mux: OneBitMux,
     addr2_mux: TwoBitMux,
     sr2_mux: OneBitMux,
     mar_mux: OneBitMux,
+    sp_mux: TwoBitMux,
+    psr_mux: OneBitMux,
+    vector_mux: TwoBitMux,
+    table_mux: OneBitMux,
     aluk: TwoBitMux,
 
+    // memory is enabled with mio_en, and r_w selects a write when set
+    mio_en: LoadFlag,
+    r_w: OneBitMux,
+
     mar: Register,
     ir: Register,
     mdr: Register,
     pc: Register,
+    saved_ssp: Register,
+    saved_usp: Register,
+    vector: u16,
+    // high byte of the vector table in use: x01 for interrupts and exceptions, x00 for traps
+    table: u16,
 }
 
 impl Datapath {
@@ -989,43 +1562,137 @@ impl Datapath {
             ld_pc: LoadFlag(false),
             ld_ir: LoadFlag(false),
             ld_mar: LoadFlag(false),
+            ld_mdr: LoadFlag(false),
             ld_reg: LoadFlag(false),
             ld_cc: LoadFlag(false),
+            ld_ben: LoadFlag(false),
+            ld_priv: LoadFlag(false),
+            ld_priority: LoadFlag(false),
+            ld_saved_ssp: LoadFlag(false),
+            ld_saved_usp: LoadFlag(false),
+            ld_vector: LoadFlag(false),
+            ld_table: LoadFlag(false),
 
             n: BranchFlag(false),
             z: BranchFlag(false),
             p: BranchFlag(false),
+            ben: BranchFlag(false),
+            privilege: Privilege::Supervisor,
+            priority: 0,
+
+            interrupt: None,
+            device_interrupt: None,
 
             gate_pc: GateFlag(false),
+            gate_mdr: GateFlag(false),
             gate_marmux: GateFlag(false),
             gate_alu: GateFlag(false),
+            gate_sp: GateFlag(false),
+            gate_psr: GateFlag(false),
+            gate_vector: GateFlag(false),
 
             addr1_mux: OneBitMux(false),
             addr2_mux: TwoBitMux(5),
             mar_mux: OneBitMux(false),
             sr2_mux: OneBitMux(false),
+            sp_mux: TwoBitMux(5),
+            psr_mux: OneBitMux(false),
+            vector_mux: TwoBitMux(5),
+            table_mux: OneBitMux(false),
             aluk: TwoBitMux(5),
             pc_mux: TwoBitMux(5),
+            dr_mux: TwoBitMux(5),
+            sr1_mux: TwoBitMux(5),
+
+            mio_en: LoadFlag(false),
+            r_w: OneBitMux(false),
 
             mar: Register::zeroed(RegisterName::MAR),
             ir: Register::zeroed(RegisterName::IR),
             mdr: Register::zeroed(RegisterName::MDR),
             pc: Register::new(starting_pc, RegisterName::PC),
+            saved_ssp: Register::new(RegisterContents::new(INITIAL_SSP), RegisterName::SavedSSP),
+            saved_usp: Register::zeroed(RegisterName::SavedUSP),
+            vector: 0,
+            table: INTERRUPT_TABLE >> 8,
         }
     }
 
-    fn mux_addr1(&self, _instruction: &Instruction) -> RegisterContents {
+    /// # Returns every control signal to its inactive value, ready for the next microinstruction
+    fn deassert(&mut self) {
+        self.ld_pc = LoadFlag(false);
+        self.ld_ir = LoadFlag(false);
+        self.ld_mar = LoadFlag(false);
+        self.ld_mdr = LoadFlag(false);
+        self.ld_reg = LoadFlag(false);
+        self.ld_cc = LoadFlag(false);
+        self.ld_ben = LoadFlag(false);
+        self.ld_priv = LoadFlag(false);
+        self.ld_priority = LoadFlag(false);
+        self.ld_saved_ssp = LoadFlag(false);
+        self.ld_saved_usp = LoadFlag(false);
+        self.ld_vector = LoadFlag(false);
+        self.ld_table = LoadFlag(false);
+
+        self.gate_pc = GateFlag(false);
+        self.gate_mdr = GateFlag(false);
+        self.gate_marmux = GateFlag(false);
+        self.gate_alu = GateFlag(false);
+        self.gate_sp = GateFlag(false);
+        self.gate_psr = GateFlag(false);
+        self.gate_vector = GateFlag(false);
+
+        self.addr1_mux = OneBitMux(false);
+        self.addr2_mux = TwoBitMux(5);
+        self.mar_mux = OneBitMux(false);
+        self.sr2_mux = OneBitMux(false);
+        self.sp_mux = TwoBitMux(5);
+        self.psr_mux = OneBitMux(false);
+        self.vector_mux = TwoBitMux(5);
+        self.table_mux = OneBitMux(false);
+        self.aluk = TwoBitMux(5);
+        self.pc_mux = TwoBitMux(5);
+        self.dr_mux = TwoBitMux(5);
+        self.sr1_mux = TwoBitMux(5);
+
+        self.mio_en = LoadFlag(false);
+        self.r_w = OneBitMux(false);
+    }
+
+    fn mux_dr(&self) -> RegisterName {
+        match self.dr_mux.0 {
+            0 => RegisterName::from_bits(self.ir.content.0 >> 9),
+            1 => RegisterName::R7,
+            2 => RegisterName::R6,
+            _ => panic!("Invalid value for DRMUX: {:?}", self.dr_mux),
+        }
+    }
+
+    fn mux_sr1(&self) -> RegisterContents {
+        let sr1 = match self.sr1_mux.0 {
+            0 => RegisterName::from_bits(self.ir.content.0 >> 9),
+            1 => RegisterName::from_bits(self.ir.content.0 >> 6),
+            2 => RegisterName::R6,
+            _ => panic!("Invalid value for SR1MUX: {:?}", self.sr1_mux),
+        };
+        self.regfile.contents_of(sr1)
+    }
+
+    fn mux_sr2(&self) -> RegisterContents {
+        match self.sr2_mux.0 {
+            false => self.regfile.contents_of(RegisterName::from_bits(self.ir.content.0)),
+            true => self.ir.content.sext(4),
+        }
+    }
+
+    fn mux_addr1(&self) -> RegisterContents {
         match self.addr1_mux.0 {
-            false => {
-                // Should be regfile.contents_of[sr1]
-                panic!("incorrect implementation for addr1MUX")
-                //RegisterContents::init()
-            }
+            false => self.mux_sr1(),
             true => self.pc.content,
         }
     }
 
-    fn mux_addr2(&self, _instruction: &Instruction) -> RegisterContents {
+    fn mux_addr2(&self) -> RegisterContents {
         match self.addr2_mux.0 {
             0 => self.ir.content.sext(10),
             1 => self.ir.content.sext(8),
@@ -1035,75 +1702,1097 @@ impl Datapath {
         }
     }
 
-    fn bus(&self, instruction: &Instruction) -> RegisterContents {
-        if self.gate_pc.0 && !self.gate_marmux.0 && !self.gate_alu.0 {
+    fn adder(&self) -> RegisterContents {
+        self.mux_addr2() + self.mux_addr1()
+    }
+
+    fn alu(&self) -> RegisterContents {
+        let a = self.mux_sr1();
+        match self.aluk.0 {
+            0 => a + self.mux_sr2(),
+            1 => RegisterContents::new(self.mux_sr2() & a.0),
+            2 => RegisterContents::new(!a.0),
+            3 => a,
+            _ => panic!("Invalid value for ALUK: {:?}", self.aluk),
+        }
+    }
+
+    fn mux_sp(&self) -> RegisterContents {
+        let sp = self.mux_sr1();
+        match self.sp_mux.0 {
+            0 => sp + RegisterContents::new(1),
+            1 => sp + RegisterContents::new(0xffff),
+            2 => self.saved_ssp.content,
+            3 => self.saved_usp.content,
+            _ => panic!("Invalid value for SPMUX: {:?}", self.sp_mux),
+        }
+    }
+
+    fn mux_pc(&self, bus: RegisterContents) -> RegisterContents {
+        match self.pc_mux.0 {
+            0 => self.pc.content + RegisterContents::new(1),
+            1 => bus,
+            2 => self.adder(),
+            _ => panic!("Invalid value for PCMUX: {:?}", self.pc_mux),
+        }
+    }
+
+    fn bus(&self) -> RegisterContents {
+        let gates = [
+            self.gate_pc.0,
+            self.gate_mdr.0,
+            self.gate_marmux.0,
+            self.gate_alu.0,
+            self.gate_sp.0,
+            self.gate_psr.0,
+            self.gate_vector.0,
+        ];
+        if gates.iter().filter(|gate| **gate).count() > 1 {
+            panic!("Programming error: more than one gate is driving the bus: {:?}", gates)
+        }
+
+        if self.gate_pc.0 {
             return self.pc.content;
         }
-        if !self.gate_pc.0 && self.gate_marmux.0 && !self.gate_alu.0 {
-            match self.mar_mux.0 {
-                false => {
-                    return self.ir.content.zext(0, 7);
-                }
-                true => {
-                    return self.mux_addr2(instruction) + self.mux_addr1(instruction);
+        if self.gate_mdr.0 {
+            return self.mdr.content;
+        }
+        if self.gate_marmux.0 {
+            return match self.mar_mux.0 {
+                false => self.ir.content.zext(0, 7),
+                true => self.adder(),
+            };
+        }
+        if self.gate_alu.0 {
+            return self.alu();
+        }
+        if self.gate_sp.0 {
+            return self.mux_sp();
+        }
+        if self.gate_psr.0 {
+            return RegisterContents::new(self.psr().bits());
+        }
+        if self.gate_vector.0 {
+            return RegisterContents::new(self.table << 8 | self.vector);
+        }
+        // Nothing is driving the bus
+        RegisterContents::init()
+    }
+
+    fn psr(&self) -> Psr {
+        Psr {
+            privilege: self.privilege,
+            priority: self.priority,
+            n: self.n.0,
+            z: self.z.0,
+            p: self.p.0,
+        }
+    }
+
+    fn set_psr(&mut self, psr: Psr) {
+        self.privilege = psr.privilege;
+        self.priority = psr.priority;
+        self.n = BranchFlag(psr.n);
+        self.z = BranchFlag(psr.z);
+        self.p = BranchFlag(psr.p);
+    }
+
+    /// # The higher priority of the requested and device interrupts
+    fn pending_interrupt(&self) -> Option<Interrupt> {
+        match (self.interrupt, self.device_interrupt) {
+            (Some(requested), Some(device)) if device.priority > requested.priority => Some(device),
+            (None, device) => device,
+            (requested, _) => requested,
+        }
+    }
+
+    /// # The INT signal: a pending request outranks the running program
+    fn int(&self) -> bool {
+        match self.pending_interrupt() {
+            Some(interrupt) => interrupt.priority > self.priority,
+            None => false,
+        }
+    }
+
+    /// # Latches every register whose load signal is asserted, using the values from before the edge
+    fn clock(&mut self, memory: &mut Memory, devices: &mut Devices) {
+        let bus = self.bus();
+        let pending = self.pending_interrupt();
+        let next_pc = match self.ld_pc.0 {
+            true => self.mux_pc(bus),
+            false => self.pc.content,
+        };
+        let ir = self.ir.content.0;
+
+        // Saved_SSP and Saved_USP latch SR1 as it was before R6 is written this cycle
+        let sr1 = match self.ld_saved_ssp.0 || self.ld_saved_usp.0 {
+            true => self.mux_sr1(),
+            false => RegisterContents::init(),
+        };
+        let mar = self.mar.content.0;
+
+        if self.mio_en.0 {
+            match self.r_w.0 {
+                false if self.ld_mdr.0 => {
+                    let data = match mar {
+                        PSR_ADDRESS => self.psr().bits(),
+                        _ => devices.read(mar).unwrap_or_else(|| memory.read(mar)),
+                    };
+                    self.mdr.content = RegisterContents::new(data);
                 }
+                false => {}
+                true => match mar {
+                    PSR_ADDRESS => self.set_psr(Psr::from_bits(self.mdr.content.0)),
+                    _ => {
+                        if !devices.write(mar, self.mdr.content.0) {
+                            memory.write(mar, self.mdr.content.0);
+                        }
+                    }
+                },
             }
+        } else if self.ld_mdr.0 {
+            self.mdr.content = bus;
+        }
+
+        if self.ld_mar.0 {
+            self.mar.content = bus;
+        }
+        if self.ld_ir.0 {
+            self.ir.content = bus;
+        }
+        if self.ld_ben.0 {
+            self.ben = BranchFlag(
+                (mask_out(ir, 11, 11) == 1 && self.n.0)
+                    || (mask_out(ir, 10, 10) == 1 && self.z.0)
+                    || (mask_out(ir, 9, 9) == 1 && self.p.0),
+            );
+        }
+        if self.ld_reg.0 {
+            self.regfile.set_contents_of(self.mux_dr(), bus);
+        }
+        if self.ld_cc.0 {
+            let (n, z, p) = match self.psr_mux.0 {
+                false => (bus.0 >> 15 == 1, bus.0 == 0, bus.0 >> 15 == 0 && bus.0 != 0),
+                true => (bus & 0b100 != 0, bus & 0b010 != 0, bus & 0b001 != 0),
+            };
+            self.n = BranchFlag(n);
+            self.z = BranchFlag(z);
+            self.p = BranchFlag(p);
+        }
+        if self.ld_priv.0 {
+            self.privilege = match self.psr_mux.0 {
+                false => Privilege::Supervisor,
+                true => Psr::from_bits(bus.0).privilege,
+            };
+        }
+        if self.ld_priority.0 {
+            self.priority = match (self.psr_mux.0, pending) {
+                (false, Some(interrupt)) => interrupt.priority,
+                (false, None) => self.priority,
+                (true, _) => Psr::from_bits(bus.0).priority,
+            };
+        }
+        if self.ld_saved_ssp.0 {
+            self.saved_ssp.content = sr1;
+        }
+        if self.ld_saved_usp.0 {
+            self.saved_usp.content = sr1;
+        }
+        if self.ld_table.0 {
+            self.table = match self.table_mux.0 {
+                false => INTERRUPT_TABLE >> 8,
+                true => 0x00,
+            };
+        }
+        if self.ld_vector.0 {
+            self.vector = match self.vector_mux.0 {
+                0 => {
+                    // A request is serviced once; a device keeps asking until it is satisfied
+                    if pending == self.interrupt {
+                        self.interrupt = None;
+                    }
+                    pending.map(|i| i.vector as u16).unwrap_or(0)
+                }
+                1 => Exception::PrivilegeViolation.vector() as u16,
+                2 => Exception::IllegalOpcode.vector() as u16,
+                3 => Exception::AccessControlViolation.vector() as u16,
+                _ => panic!("Invalid value for VECTORMUX: {:?}", self.vector_mux),
+            };
+        }
+        if self.ld_pc.0 {
+            self.pc.content = next_pc;
         }
-        panic!("unimplemented")
     }
 }
 
-struct Lrc3State18;
-struct Lrc3State19;
-
-struct Lrc3CpuState {
+pub struct Lrc3CpuState {
     datapath: Datapath,
     memory: Memory,
+    devices: Devices,
+    /// Which edition's microcode runs LEA and TRAP
+    edition: Edition,
 }
 
 impl Lrc3CpuState {
-    fn new(starting_pc: RegisterContents) -> Self {
+    fn new(starting_pc: RegisterContents, memory: Memory) -> Self {
         Self {
             datapath: Datapath::new(starting_pc),
-            memory: Memory::new(),
+            memory,
+            devices: Devices::console(),
+            edition: Edition::default(),
         }
     }
+
+    /// # Performs one clock cycle with the control signals the current state asserted
+    fn clock(&mut self) {
+        self.datapath.clock(&mut self.memory, &mut self.devices);
+        self.datapath.deassert();
+    }
 }
 
-enum Lrc3State {
+/* States of the LC-3 control FSM, numbered as in Appendix C of Patt & Patel.
+ *
+ * Memory always responds within the cycle that requests it, so the states that
+ * wait on the R (ready) signal never need to loop back to themselves.
+ */
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Lrc3State {
     S0_Branch,
+    S1_Add,
+    S2_Ld,
+    S3_St,
+    S4_Jsr,
+    S5_And,
+    S6_Ldr,
+    S7_Str,
+    S8_Rti,
+    S9_Not,
+    S10_Ldi,
+    S11_Sti,
+    S12_Jmp,
+    S13_IllegalOpcode,
+    S14_Lea,
+    S15_Trap,
+    S16_Store_WriteMem,
     S18_Fetch_LdMar,
     S19_Fetch_IncPc,
-
-}
-
-trait Lrc3Transition {
+    S20_Jsrr_LdPc,
+    S21_Jsr_LdPc,
+    S22_Branch_LdPc,
+    S23_Store_LdMdr,
+    S24_Ldi_ReadMem,
+    S25_Load_ReadMem,
+    S26_Ldi_LdMar,
+    S27_Load_LdReg,
+    S28_Trap_ReadMem,
+    S29_Sti_ReadMem,
+    S30_Trap_LdPc,
+    S31_Sti_LdMar,
+    S32_Decode,
+    S33_Fetch_ReadMem,
+    S34_Rti_IncSp,
+    S35_Fetch_LdIr,
+    S36_Rti_ReadPc,
+    S38_Rti_LdPc,
+    S39_Rti_LdMar,
+    S37_Int_PushPsr_LdMar,
+    S40_Rti_ReadPsr,
+    S41_Int_PushPsr_WriteMem,
+    S42_Rti_LdPsr,
+    S43_Int_LdMdr,
+    S44_Rti_PrivilegeViolation,
+    S45_Int_SwapSp,
+    S46_Trap_LdMar,
+    S47_Int_PushPc_LdMar,
+    S48_Int_PushPc_WriteMem,
+    S49_Interrupt,
+    S50_Int_LdMar,
+    S52_Int_ReadMem,
+    S54_Int_LdPc,
+    S59_Rti_SwapSp,
+    S60_AccessViolation,
+}
+
+pub trait Lrc3Transition {
     fn transition(self, state: &mut Lrc3CpuState) -> Lrc3State;
 }
 
-impl Lrc3Transition for Lrc3State18 {
+impl Lrc3Transition for Lrc3State {
     fn transition(self, state: &mut Lrc3CpuState) -> Lrc3State {
-        
-        Lrc3State::S19_Fetch_IncPc
-    }
-}
+        let edition = state.edition;
+        let d = &mut state.datapath;
+        let ir = d.ir.content.0;
+
+        /* Shared microinstructions, used by several states below */
+        fn mar_from_pc_offset9(d: &mut Datapath) {
+            d.addr1_mux = OneBitMux(true);
+            d.addr2_mux = TwoBitMux(1);
+            d.mar_mux = OneBitMux(true);
+            d.gate_marmux = GateFlag(true);
+            d.ld_mar = LoadFlag(true);
+        }
+        fn mar_from_base_offset6(d: &mut Datapath) {
+            d.sr1_mux = TwoBitMux(1);
+            d.addr1_mux = OneBitMux(false);
+            d.addr2_mux = TwoBitMux(2);
+            d.mar_mux = OneBitMux(true);
+            d.gate_marmux = GateFlag(true);
+            d.ld_mar = LoadFlag(true);
+        }
+        fn mdr_from_memory(d: &mut Datapath) {
+            d.mio_en = LoadFlag(true);
+            d.r_w = OneBitMux(false);
+            d.ld_mdr = LoadFlag(true);
+        }
+        fn mar_from_mdr(d: &mut Datapath) {
+            d.gate_mdr = GateFlag(true);
+            d.ld_mar = LoadFlag(true);
+        }
+        fn dr_from_alu(d: &mut Datapath, aluk: u8) {
+            d.sr1_mux = TwoBitMux(1);
+            d.sr2_mux = OneBitMux(mask_out(d.ir.content.0, 5, 5) == 1);
+            d.aluk = TwoBitMux(aluk);
+            d.gate_alu = GateFlag(true);
+            d.dr_mux = TwoBitMux(0);
+            d.ld_reg = LoadFlag(true);
+            d.ld_cc = LoadFlag(true);
+        }
+        fn mar_from_trapvect8(d: &mut Datapath) {
+            d.mar_mux = OneBitMux(false);
+            d.gate_marmux = GateFlag(true);
+            d.ld_mar = LoadFlag(true);
+        }
+        fn r7_from_pc(d: &mut Datapath) {
+            d.gate_pc = GateFlag(true);
+            d.dr_mux = TwoBitMux(1);
+            d.ld_reg = LoadFlag(true);
+        }
+        fn increment_sp(d: &mut Datapath) {
+            d.sr1_mux = TwoBitMux(2);
+            d.sp_mux = TwoBitMux(0);
+            d.gate_sp = GateFlag(true);
+            d.dr_mux = TwoBitMux(2);
+            d.ld_reg = LoadFlag(true);
+        }
+        fn decrement_sp(d: &mut Datapath) {
+            d.sr1_mux = TwoBitMux(2);
+            d.sp_mux = TwoBitMux(1);
+            d.gate_sp = GateFlag(true);
+            d.dr_mux = TwoBitMux(2);
+            d.ld_reg = LoadFlag(true);
+        }
+        fn write_memory(d: &mut Datapath) {
+            d.mio_en = LoadFlag(true);
+            d.r_w = OneBitMux(true);
+        }
+        // MDR <- PSR, PSR[15] <- 0
+        fn save_psr(d: &mut Datapath) {
+            d.gate_psr = GateFlag(true);
+            d.ld_mdr = LoadFlag(true);
+            d.psr_mux = OneBitMux(false);
+            d.ld_priv = LoadFlag(true);
+        }
+        // Table <- x01, Vector <- VECTORMUX, MDR <- PSR, PSR[15] <- 0
+        fn enter_supervisor(d: &mut Datapath, vector_mux: u8) {
+            save_psr(d);
+            d.table_mux = OneBitMux(false);
+            d.ld_table = LoadFlag(true);
+            d.vector_mux = TwoBitMux(vector_mux);
+            d.ld_vector = LoadFlag(true);
+        }
+        /* ACV is decided from the address on the bus as it is latched into MAR,
+         * so the state that would access memory is never entered
+         */
+        fn acv(d: &Datapath) -> bool {
+            access_violation(d.privilege, d.bus().0)
+        }
+        let user_mode = d.privilege == Privilege::User;
+        // [PSR[15]] on the way into supervisor mode
+        let push_psr = match user_mode {
+            true => Self::S45_Int_SwapSp,
+            false => Self::S37_Int_PushPsr_LdMar,
+        };
+
+        let next = match self {
+            /* Fetch */
+            // MAR <- PC, [INT], [ACV]
+            Self::S18_Fetch_LdMar => {
+                d.gate_pc = GateFlag(true);
+                d.ld_mar = LoadFlag(true);
+                match (d.int(), acv(d)) {
+                    (true, _) => Self::S49_Interrupt,
+                    (false, true) => Self::S60_AccessViolation,
+                    (false, false) => Self::S19_Fetch_IncPc,
+                }
+            }
+            // PC <- PC + 1
+            Self::S19_Fetch_IncPc => {
+                d.pc_mux = TwoBitMux(0);
+                d.ld_pc = LoadFlag(true);
+                Self::S33_Fetch_ReadMem
+            }
+            // MDR <- M[MAR]
+            Self::S33_Fetch_ReadMem => {
+                mdr_from_memory(d);
+                Self::S35_Fetch_LdIr
+            }
+            // IR <- MDR
+            Self::S35_Fetch_LdIr => {
+                d.gate_mdr = GateFlag(true);
+                d.ld_ir = LoadFlag(true);
+                Self::S32_Decode
+            }
+
+            /* Decode */
+            // BEN <- IR[11] & N + IR[10] & Z + IR[9] & P, [IR[15:12]]
+            Self::S32_Decode => {
+                d.ld_ben = LoadFlag(true);
+                match mask_out(ir, 12, 15) {
+                    0b0000 => Self::S0_Branch,
+                    0b0001 => Self::S1_Add,
+                    0b0010 => Self::S2_Ld,
+                    0b0011 => Self::S3_St,
+                    0b0100 => Self::S4_Jsr,
+                    0b0101 => Self::S5_And,
+                    0b0110 => Self::S6_Ldr,
+                    0b0111 => Self::S7_Str,
+                    0b1000 => Self::S8_Rti,
+                    0b1001 => Self::S9_Not,
+                    0b1010 => Self::S10_Ldi,
+                    0b1011 => Self::S11_Sti,
+                    0b1100 => Self::S12_Jmp,
+                    0b1101 => Self::S13_IllegalOpcode,
+                    0b1110 => Self::S14_Lea,
+                    0b1111 => Self::S15_Trap,
+                    _ => unreachable!(),
+                }
+            }
 
-impl Lrc3Transition for Lrc3State19 {
-    fn transition(self, _: &mut Lrc3CpuState) -> Lrc3State {
-        Lrc3State::S18_Fetch_LdMar
+            /* Operate */
+            // DR <- SR1 + OP2, set CC
+            Self::S1_Add => {
+                dr_from_alu(d, 0);
+                Self::S18_Fetch_LdMar
+            }
+            // DR <- SR1 & OP2, set CC
+            Self::S5_And => {
+                dr_from_alu(d, 1);
+                Self::S18_Fetch_LdMar
+            }
+            // DR <- NOT(SR), set CC
+            Self::S9_Not => {
+                dr_from_alu(d, 2);
+                Self::S18_Fetch_LdMar
+            }
+            // DR <- PC + off9, set CC in the second edition
+            Self::S14_Lea => {
+                d.addr1_mux = OneBitMux(true);
+                d.addr2_mux = TwoBitMux(1);
+                d.mar_mux = OneBitMux(true);
+                d.gate_marmux = GateFlag(true);
+                d.dr_mux = TwoBitMux(0);
+                d.ld_reg = LoadFlag(true);
+                d.ld_cc = LoadFlag(edition == Edition::Second);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Loads */
+            // MAR <- PC + off9, [ACV]
+            Self::S2_Ld => {
+                mar_from_pc_offset9(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S25_Load_ReadMem,
+                }
+            }
+            // MAR <- B + off6, [ACV]
+            Self::S6_Ldr => {
+                mar_from_base_offset6(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S25_Load_ReadMem,
+                }
+            }
+            // MAR <- PC + off9, [ACV]
+            Self::S10_Ldi => {
+                mar_from_pc_offset9(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S24_Ldi_ReadMem,
+                }
+            }
+            // MDR <- M[MAR]
+            Self::S24_Ldi_ReadMem => {
+                mdr_from_memory(d);
+                Self::S26_Ldi_LdMar
+            }
+            // MAR <- MDR, [ACV]
+            Self::S26_Ldi_LdMar => {
+                mar_from_mdr(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S25_Load_ReadMem,
+                }
+            }
+            // MDR <- M[MAR]
+            Self::S25_Load_ReadMem => {
+                mdr_from_memory(d);
+                Self::S27_Load_LdReg
+            }
+            // DR <- MDR, set CC
+            Self::S27_Load_LdReg => {
+                d.gate_mdr = GateFlag(true);
+                d.dr_mux = TwoBitMux(0);
+                d.ld_reg = LoadFlag(true);
+                d.ld_cc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Stores */
+            // MAR <- PC + off9, [ACV]
+            Self::S3_St => {
+                mar_from_pc_offset9(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S23_Store_LdMdr,
+                }
+            }
+            // MAR <- B + off6, [ACV]
+            Self::S7_Str => {
+                mar_from_base_offset6(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S23_Store_LdMdr,
+                }
+            }
+            // MAR <- PC + off9, [ACV]
+            Self::S11_Sti => {
+                mar_from_pc_offset9(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S29_Sti_ReadMem,
+                }
+            }
+            // MDR <- M[MAR]
+            Self::S29_Sti_ReadMem => {
+                mdr_from_memory(d);
+                Self::S31_Sti_LdMar
+            }
+            // MAR <- MDR, [ACV]
+            Self::S31_Sti_LdMar => {
+                mar_from_mdr(d);
+                match acv(d) {
+                    true => Self::S60_AccessViolation,
+                    false => Self::S23_Store_LdMdr,
+                }
+            }
+            // MDR <- SR
+            Self::S23_Store_LdMdr => {
+                d.sr1_mux = TwoBitMux(0);
+                d.aluk = TwoBitMux(3);
+                d.gate_alu = GateFlag(true);
+                d.ld_mdr = LoadFlag(true);
+                Self::S16_Store_WriteMem
+            }
+            // M[MAR] <- MDR
+            Self::S16_Store_WriteMem => {
+                write_memory(d);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Control */
+            // [BEN]
+            Self::S0_Branch => match d.ben.0 {
+                true => Self::S22_Branch_LdPc,
+                false => Self::S18_Fetch_LdMar,
+            },
+            // PC <- PC + off9
+            Self::S22_Branch_LdPc => {
+                d.addr1_mux = OneBitMux(true);
+                d.addr2_mux = TwoBitMux(1);
+                d.pc_mux = TwoBitMux(2);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+            // PC <- BaseR
+            Self::S12_Jmp => {
+                d.sr1_mux = TwoBitMux(1);
+                d.addr1_mux = OneBitMux(false);
+                d.addr2_mux = TwoBitMux(3);
+                d.pc_mux = TwoBitMux(2);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+            // [IR[11]]
+            Self::S4_Jsr => match mask_out(ir, 11, 11) {
+                1 => Self::S21_Jsr_LdPc,
+                _ => Self::S20_Jsrr_LdPc,
+            },
+            // R7 <- PC, PC <- PC + off11
+            Self::S21_Jsr_LdPc => {
+                r7_from_pc(d);
+                d.addr1_mux = OneBitMux(true);
+                d.addr2_mux = TwoBitMux(0);
+                d.pc_mux = TwoBitMux(2);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+            // R7 <- PC, PC <- BaseR
+            Self::S20_Jsrr_LdPc => {
+                r7_from_pc(d);
+                d.sr1_mux = TwoBitMux(1);
+                d.addr1_mux = OneBitMux(false);
+                d.addr2_mux = TwoBitMux(3);
+                d.pc_mux = TwoBitMux(2);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+            /* In the third edition TRAP enters supervisor mode like an interrupt,
+             * pushing PSR and PC. In the second it links through R7 instead.
+             * Both take the service routine's address from the trap vector table.
+             */
+            // Second: MAR <- ZEXT(IR[7:0])
+            // Third: Table <- x00, MDR <- PSR, PSR[15] <- 0, [PSR[15]]
+            Self::S15_Trap => match edition {
+                Edition::Second => {
+                    mar_from_trapvect8(d);
+                    Self::S28_Trap_ReadMem
+                }
+                Edition::Third => {
+                    save_psr(d);
+                    d.table_mux = OneBitMux(true);
+                    d.ld_table = LoadFlag(true);
+                    push_psr
+                }
+            },
+            // MAR <- ZEXT(IR[7:0])
+            Self::S46_Trap_LdMar => {
+                mar_from_trapvect8(d);
+                Self::S28_Trap_ReadMem
+            }
+            // MDR <- M[MAR], and in the second edition R7 <- PC
+            Self::S28_Trap_ReadMem => {
+                mdr_from_memory(d);
+                if edition == Edition::Second {
+                    r7_from_pc(d);
+                }
+                Self::S30_Trap_LdPc
+            }
+            // PC <- MDR
+            Self::S30_Trap_LdPc => {
+                d.gate_mdr = GateFlag(true);
+                d.pc_mux = TwoBitMux(1);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Return from interrupt, popping PC then PSR off the stack in R6 */
+            // MAR <- SP, [PSR[15]]
+            Self::S8_Rti => {
+                d.sr1_mux = TwoBitMux(2);
+                d.aluk = TwoBitMux(3);
+                d.gate_alu = GateFlag(true);
+                d.ld_mar = LoadFlag(true);
+                match user_mode {
+                    true => Self::S44_Rti_PrivilegeViolation,
+                    false => Self::S36_Rti_ReadPc,
+                }
+            }
+            // MDR <- M[MAR]
+            Self::S36_Rti_ReadPc => {
+                mdr_from_memory(d);
+                Self::S38_Rti_LdPc
+            }
+            // PC <- MDR
+            Self::S38_Rti_LdPc => {
+                d.gate_mdr = GateFlag(true);
+                d.pc_mux = TwoBitMux(1);
+                d.ld_pc = LoadFlag(true);
+                Self::S39_Rti_LdMar
+            }
+            // MAR, SP <- SP + 1
+            Self::S39_Rti_LdMar => {
+                increment_sp(d);
+                d.ld_mar = LoadFlag(true);
+                Self::S40_Rti_ReadPsr
+            }
+            // MDR <- M[MAR]
+            Self::S40_Rti_ReadPsr => {
+                mdr_from_memory(d);
+                Self::S42_Rti_LdPsr
+            }
+            // PSR <- MDR
+            Self::S42_Rti_LdPsr => {
+                d.gate_mdr = GateFlag(true);
+                d.psr_mux = OneBitMux(true);
+                d.ld_cc = LoadFlag(true);
+                d.ld_priv = LoadFlag(true);
+                d.ld_priority = LoadFlag(true);
+                Self::S34_Rti_IncSp
+            }
+            // SP <- SP + 1, [PSR[15]]
+            Self::S34_Rti_IncSp => {
+                increment_sp(d);
+                match user_mode {
+                    true => Self::S59_Rti_SwapSp,
+                    false => Self::S18_Fetch_LdMar,
+                }
+            }
+            // Saved_SSP <- SP, SP <- Saved_USP
+            Self::S59_Rti_SwapSp => {
+                d.sr1_mux = TwoBitMux(2);
+                d.ld_saved_ssp = LoadFlag(true);
+                d.sp_mux = TwoBitMux(3);
+                d.gate_sp = GateFlag(true);
+                d.dr_mux = TwoBitMux(2);
+                d.ld_reg = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Interrupt: push PSR then PC onto the supervisor stack, and jump through the vector table */
+            // Vector <- INTV, MDR <- PSR, PSR[10:8] <- Priority, PSR[15] <- 0, [PSR[15]]
+            Self::S49_Interrupt => {
+                enter_supervisor(d, 0);
+                d.ld_priority = LoadFlag(true);
+                push_psr
+            }
+            // Saved_USP <- SP, SP <- Saved_SSP
+            Self::S45_Int_SwapSp => {
+                d.sr1_mux = TwoBitMux(2);
+                d.ld_saved_usp = LoadFlag(true);
+                d.sp_mux = TwoBitMux(2);
+                d.gate_sp = GateFlag(true);
+                d.dr_mux = TwoBitMux(2);
+                d.ld_reg = LoadFlag(true);
+                Self::S37_Int_PushPsr_LdMar
+            }
+            // MAR, SP <- SP - 1
+            Self::S37_Int_PushPsr_LdMar => {
+                decrement_sp(d);
+                d.ld_mar = LoadFlag(true);
+                Self::S41_Int_PushPsr_WriteMem
+            }
+            // M[MAR] <- MDR
+            Self::S41_Int_PushPsr_WriteMem => {
+                write_memory(d);
+                Self::S43_Int_LdMdr
+            }
+            // MDR <- PC
+            Self::S43_Int_LdMdr => {
+                d.gate_pc = GateFlag(true);
+                d.ld_mdr = LoadFlag(true);
+                Self::S47_Int_PushPc_LdMar
+            }
+            // MAR, SP <- SP - 1
+            Self::S47_Int_PushPc_LdMar => {
+                decrement_sp(d);
+                d.ld_mar = LoadFlag(true);
+                Self::S48_Int_PushPc_WriteMem
+            }
+            // M[MAR] <- MDR, [Table]
+            Self::S48_Int_PushPc_WriteMem => {
+                write_memory(d);
+                match d.table {
+                    0x00 => Self::S46_Trap_LdMar,
+                    _ => Self::S50_Int_LdMar,
+                }
+            }
+            // MAR <- x01'Vector
+            Self::S50_Int_LdMar => {
+                d.gate_vector = GateFlag(true);
+                d.ld_mar = LoadFlag(true);
+                Self::S52_Int_ReadMem
+            }
+            // MDR <- M[MAR]
+            Self::S52_Int_ReadMem => {
+                mdr_from_memory(d);
+                Self::S54_Int_LdPc
+            }
+            // PC <- MDR
+            Self::S54_Int_LdPc => {
+                d.gate_mdr = GateFlag(true);
+                d.pc_mux = TwoBitMux(1);
+                d.ld_pc = LoadFlag(true);
+                Self::S18_Fetch_LdMar
+            }
+
+            /* Exceptions take the interrupt path at the same priority, with a fixed vector.
+             * The PC pushed is the one after the offending instruction, or for an ACV
+             * on fetch, the address that could not be fetched.
+             */
+            // Vector <- x00, MDR <- PSR, PSR[15] <- 0, [PSR[15]]
+            Self::S44_Rti_PrivilegeViolation => {
+                enter_supervisor(d, 1);
+                push_psr
+            }
+            // Vector <- x01, MDR <- PSR, PSR[15] <- 0, [PSR[15]]
+            Self::S13_IllegalOpcode => {
+                enter_supervisor(d, 2);
+                push_psr
+            }
+            // Vector <- x02, MDR <- PSR, PSR[15] <- 0, [PSR[15]]
+            Self::S60_AccessViolation => {
+                enter_supervisor(d, 3);
+                push_psr
+            }
+        };
+
+        state.clock();
+        next
     }
 }
 
-struct Lrc3Cpu {
+pub struct Lrc3Cpu {
     state: Lrc3State,
     data: Lrc3CpuState,
+    /// Whether memory holds the built-in OS, whose trap return has to suit the edition
+    booted: bool,
 }
 
 impl Lrc3Cpu {
-    pub fn new() -> Self {
-        Self {
+    /// # Starts in supervisor mode, with R6 pointing at the supervisor stack
+    pub fn new(memory: Memory, starting_pc: u16) -> Self {
+        let mut cpu = Self {
             state: Lrc3State::S18_Fetch_LdMar,
-            data: Lrc3CpuState::new(RegisterContents::new(0x3000)),
+            data: Lrc3CpuState::new(RegisterContents::new(starting_pc), memory),
+            booted: false,
+        };
+        cpu.set_register(RegisterName::R6, INITIAL_SSP);
+        cpu
+    }
+
+    /// # Loads the built-in OS, then each object, and starts at the first object's origin
+    pub fn from_objects(objects: &[ObjectFile]) -> Result<Self, ObjectError> {
+        let (memory, starting_pc) = os::boot(objects)?;
+        let mut cpu = Self::new(memory, starting_pc);
+        cpu.booted = true;
+        cpu.set_edition(Edition::default());
+        Ok(cpu)
+    }
+
+    pub fn state(&self) -> Lrc3State {
+        self.state
+    }
+
+    pub fn edition(&self) -> Edition {
+        self.data.edition
+    }
+
+    /// # Switches the microcode LEA and TRAP run, and the return of a booted OS's trap routines.
+    /// The datapath never checks fixed fields, so a decode policy's strictness has nothing to change
+    pub fn set_edition(&mut self, edition: Edition) {
+        self.data.edition = edition;
+        if self.booted {
+            os::set_trap_return(&mut self.data.memory, edition);
+        }
+    }
+
+    /// # Runs the current state's microinstruction for one clock cycle, returning the next state
+    pub fn step(&mut self) -> Lrc3State {
+        if !self.data.devices.clock_enabled() {
+            // HALT has cleared MCR[15], so the clock no longer ticks
+            return self.state;
+        }
+        if self.state == Lrc3State::S18_Fetch_LdMar {
+            // Devices advance once per instruction, and may raise INT in time for this fetch
+            self.data.devices.tick();
+            self.data.datapath.device_interrupt = self.data.devices.interrupt();
         }
+        self.state = self.state.transition(&mut self.data);
+        self.state
+    }
+
+    /// # Runs microinstructions until the next instruction fetch begins
+    pub fn step_instruction(&mut self) {
+        while self.step() != Lrc3State::S18_Fetch_LdMar {}
+    }
+
+    pub fn register(&self, reg: RegisterName) -> u16 {
+        let d = &self.data.datapath;
+        match reg {
+            RegisterName::PC => d.pc.content.0,
+            RegisterName::IR => d.ir.content.0,
+            RegisterName::MDR => d.mdr.content.0,
+            RegisterName::MAR => d.mar.content.0,
+            RegisterName::PSR => d.psr().bits(),
+            RegisterName::SavedSSP => d.saved_ssp.content.0,
+            RegisterName::SavedUSP => d.saved_usp.content.0,
+            gpr => d.regfile.contents_of(gpr).0,
+        }
+    }
+
+    pub fn set_register(&mut self, reg: RegisterName, data: u16) {
+        let d = &mut self.data.datapath;
+        let data = RegisterContents::new(data);
+        match reg {
+            RegisterName::PC => d.pc.content = data,
+            RegisterName::IR => d.ir.content = data,
+            RegisterName::MDR => d.mdr.content = data,
+            RegisterName::MAR => d.mar.content = data,
+            RegisterName::PSR => d.set_psr(Psr::from_bits(data.0)),
+            RegisterName::SavedSSP => d.saved_ssp.content = data,
+            RegisterName::SavedUSP => d.saved_usp.content = data,
+            gpr => d.regfile.set_contents_of(gpr, data),
+        }
+    }
+
+    /// # Condition codes as (n, z, p)
+    pub fn nzp(&self) -> (bool, bool, bool) {
+        let d = &self.data.datapath;
+        (d.n.0, d.z.0, d.p.0)
+    }
+
+    pub fn set_nzp(&mut self, n: bool, z: bool, p: bool) {
+        let d = &mut self.data.datapath;
+        d.n = BranchFlag(n);
+        d.z = BranchFlag(z);
+        d.p = BranchFlag(p);
     }
+
+    pub fn psr(&self) -> Psr {
+        self.data.datapath.psr()
+    }
+
+    /// # Raises INT, which is serviced at the next fetch if it outranks the current priority
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.data.datapath.interrupt = Some(interrupt);
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.data.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.data.memory
+    }
+
+    pub fn devices(&self) -> &Devices {
+        &self.data.devices
+    }
+
+    pub fn devices_mut(&mut self) -> &mut Devices {
+        &mut self.data.devices
+    }
+}
+
+#[test]
+fn test_microsequencer_fetch_and_add() {
+    let mut memory = Memory::new();
+    // ADD R1, R1, #5
+    memory.write(0x3000, 0x1265);
+    let mut cpu = Lrc3Cpu::new(memory, 0x3000);
+
+    let mut visited = vec![cpu.state()];
+    while visited.len() < 7 {
+        visited.push(cpu.step());
+    }
+    assert_eq!(
+        visited,
+        vec![
+            Lrc3State::S18_Fetch_LdMar,
+            Lrc3State::S19_Fetch_IncPc,
+            Lrc3State::S33_Fetch_ReadMem,
+            Lrc3State::S35_Fetch_LdIr,
+            Lrc3State::S32_Decode,
+            Lrc3State::S1_Add,
+            Lrc3State::S18_Fetch_LdMar,
+        ]
+    );
+    assert_eq!(cpu.register(RegisterName::R1), 5);
+    assert_eq!(cpu.register(RegisterName::PC), 0x3001);
+    assert_eq!(cpu.nzp(), (false, false, true));
+}
+
+#[test]
+fn test_microsequencer_runs_program() {
+    let assembly = crate::asm::assemble(
+        "
+        .ORIG x3000
+        LD R1, COUNT
+        LEA R2, DATA
+LOOP    LDR R3, R2, #0
+        ADD R4, R4, R3
+        ADD R2, R2, #1
+        ADD R1, R1, #-1
+        BRp LOOP
+        STI R4, RESULT
+        JSR SUB
+        TRAP x40
+SUB     NOT R5, R4
+        RET
+COUNT   .FILL 3
+DATA    .FILL 10
+        .FILL 20
+        .FILL 30
+RESULT  .FILL x4000
+        .END
+        .ORIG x0040
+        .FILL x5000
+        .END
+        .ORIG x5000
+        AND R0, R0, #0
+        LD R7, BACKPTR
+        STR R7, R6, #0
+        STR R0, R6, #1
+        RTI
+BACKPTR .FILL BACK
+        .END
+        .ORIG x6000
+BACK    BRz BACK
+        .END
+    ",
+    )
+    .unwrap();
+    let mut cpu = Lrc3Cpu::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap();
+    // The handler at x5000 rewrites the PC and PSR the third edition's TRAP pushed
+    cpu.set_edition(Edition::Third);
+    cpu.set_register(RegisterName::R6, 0x7000);
+
+    while cpu.register(RegisterName::PC) != 0x6001 {
+        cpu.step_instruction();
+    }
+    assert_eq!(cpu.memory().read(0x4000), 60);
+    assert_eq!(cpu.register(RegisterName::R5), !60);
+    assert_eq!(cpu.register(RegisterName::R7), 0x6000);
+    assert_eq!(cpu.register(RegisterName::R6), 0x7000);
+    assert_eq!(cpu.nzp(), (false, false, false));
+}
+
+#[test]
+fn test_microsequencer_interrupt_swaps_stacks() {
+    let mut memory = Memory::new();
+    // Handler at x1000 is a lone RTI, the user program is a run of ADD R1, R1, #1
+    memory.write(INTERRUPT_TABLE + 0x80, 0x1000);
+    memory.write(0x1000, 0x8000);
+    memory.load(0x4000, &[0x1261, 0x1261]);
+
+    let mut cpu = Lrc3Cpu::new(memory, 0x4000);
+    cpu.set_register(RegisterName::PSR, 0x8001);
+    cpu.set_register(RegisterName::R6, 0xfd00);
+    cpu.step_instruction();
+    assert_eq!(cpu.register(RegisterName::R1), 1);
+
+    // Priority 4 outranks the user program's 0, so the next fetch becomes the interrupt
+    cpu.request_interrupt(Interrupt {
+        vector: 0x80,
+        priority: 4,
+    });
+    cpu.step_instruction();
+    assert_eq!(cpu.register(RegisterName::PC), 0x1000);
+    assert_eq!(cpu.psr().privilege, Privilege::Supervisor);
+    assert_eq!(cpu.psr().priority, 4);
+    assert_eq!(cpu.register(RegisterName::SavedUSP), 0xfd00);
+    assert_eq!(cpu.register(RegisterName::R6), INITIAL_SSP - 2);
+    assert_eq!(cpu.memory().read(INITIAL_SSP - 1), 0x8001);
+    assert_eq!(cpu.memory().read(INITIAL_SSP - 2), 0x4001);
+
+    // RTI restores the user PSR and stack
+    cpu.step_instruction();
+    assert_eq!(cpu.register(RegisterName::PC), 0x4001);
+    assert_eq!(cpu.register(RegisterName::PSR), 0x8001);
+    assert_eq!(cpu.register(RegisterName::R6), 0xfd00);
+    assert_eq!(cpu.register(RegisterName::SavedSSP), INITIAL_SSP);
+    cpu.step_instruction();
+    assert_eq!(cpu.register(RegisterName::R1), 2);
 }
@@ -0,0 +1,511 @@
+use crate::asm::{
+    assemble_best_effort, parse_line, parse_number, parse_operand, statement_size, AsmOptions,
+    Assembly, Operand, SourceLine, Token,
+};
+use crate::json::{object, read_message, write_message, Json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/* A Language Server Protocol server for LC-3 assembly
+ *
+ * Editors send whole documents (full text sync) over stdin as JSON-RPC, and
+ * every open or change re-analyses the document and republishes its
+ * diagnostics. Every assembler error becomes a diagnostic, which covers the
+ * PCoffset9, PCoffset11 and offset6 operands that don't fit their fields.
+ * Analysis also walks the lines itself, laying them out as pass 1 of the
+ * assembler does, so that each label definition (with its address) and each
+ * operand that names a label is known even when the document doesn't
+ * assemble, for go-to-definition, find-references and completion. Hover
+ * shows the words of any statement that assembled, whatever errors the
+ * other lines have.
+ *
+ * Positions on the wire are 0-based lines and UTF-16 code units, while the
+ * assembler counts lines and chars from 1.
+ */
+
+const OPCODES: [&str; 30] = [
+    "ADD", "AND", "NOT", "BR", "BRn", "BRz", "BRp", "BRnz", "BRnp", "BRzp", "BRnzp", "JMP", "RET",
+    "JSR", "JSRR", "LD", "LDI", "LDR", "LEA", "ST", "STI", "STR", "TRAP", "RTI", "GETC", "OUT",
+    "PUTS", "IN", "PUTSP", "HALT",
+];
+
+const DIRECTIVES: [&str; 5] = [".ORIG", ".FILL", ".BLKW", ".STRINGZ", ".END"];
+
+/// LSP's CompletionItemKind values
+const KEYWORD: i64 = 14;
+const REFERENCE: i64 = 18;
+
+/// LSP's DiagnosticSeverity for errors
+const ERROR: i64 = 1;
+
+/// # A run of text on one line: a 1-based line, and a 1-based column and length in chars
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Span {
+    line: usize,
+    column: usize,
+    len: usize,
+}
+
+impl Span {
+    fn of(line: usize, token: &Token) -> Self {
+        Self {
+            line,
+            column: token.column,
+            len: token.text.chars().count(),
+        }
+    }
+
+    fn contains(&self, line: usize, column: usize) -> bool {
+        self.line == line && (self.column..self.column + self.len).contains(&column)
+    }
+}
+
+/// # Everything the server knows about one document
+struct Analysis {
+    lines: Vec<String>,
+    definitions: Vec<(String, Span, Option<u16>)>,
+    references: Vec<(String, Span)>,
+    diagnostics: Vec<(Span, String)>,
+    /// What assembled, so lines keep their hover while another line has an error
+    assembly: Assembly,
+}
+
+impl Analysis {
+    fn new(text: &str) -> Self {
+        let (assembly, errors) = assemble_best_effort(text, &AsmOptions::default());
+        let mut analysis = Self {
+            lines: text.lines().map(str::to_string).collect(),
+            definitions: Vec::new(),
+            references: Vec::new(),
+            diagnostics: errors
+                .into_iter()
+                .map(|e| {
+                    let span = Span {
+                        line: e.line,
+                        column: e.column,
+                        len: e.len,
+                    };
+                    (span, e.kind.to_string())
+                })
+                .collect(),
+            assembly,
+        };
+        let parsed: Vec<SourceLine> = analysis
+            .lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, text)| parse_line(i + 1, text).ok())
+            .collect();
+
+        // Lay out statements as pass 1 does, carrying on past lines that don't parse
+        let mut location: Option<u16> = None;
+        for line in &parsed {
+            let mnemonic = line.op.as_ref().map(|op| op.text.to_ascii_uppercase());
+            if let Some(label) = &line.label {
+                analysis
+                    .definitions
+                    .push((label.text.clone(), Span::of(line.number, label), location));
+            }
+            for token in &line.operands {
+                if let Ok(Operand::Label(label)) = parse_operand(line.number, token) {
+                    analysis.references.push((label, Span::of(line.number, token)));
+                }
+            }
+            match mnemonic.as_deref() {
+                Some(".ORIG") => {
+                    location = line
+                        .operands
+                        .first()
+                        .and_then(|token| parse_number(&token.text))
+                        .map(|origin| origin as u16);
+                }
+                Some(".END") => location = None,
+                Some(mnemonic) => {
+                    if let Some(address) = location {
+                        let size = statement_size(line, mnemonic).unwrap_or(1);
+                        location = Some(address.wrapping_add(size));
+                    }
+                }
+                None => {}
+            }
+        }
+        analysis
+    }
+
+    fn line_text(&self, line: usize) -> &str {
+        line.checked_sub(1)
+            .and_then(|index| self.lines.get(index))
+            .map(String::as_str)
+            .unwrap_or("")
+    }
+
+    /// # The label named by the token under a 1-based line and char column
+    fn label_at(&self, line: usize, column: usize) -> Option<&str> {
+        self.definitions
+            .iter()
+            .map(|(name, span, _)| (name, span))
+            .chain(self.references.iter().map(|(name, span)| (name, span)))
+            .find(|(_, span)| span.contains(line, column))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// # The address, encoding and instruction of the statement on a line
+    fn hover(&self, line: usize) -> Option<String> {
+        let statement = self.assembly.statements.iter().find(|s| s.line == line)?;
+        let mut text = String::new();
+        for (offset, word) in statement.words.iter().enumerate() {
+            text += &format!(
+                "x{:04X}: x{:04X}  {:016b}\n",
+                statement.address.wrapping_add(offset as u16),
+                word,
+                word
+            );
+        }
+        if let Some(instruction) = &statement.instruction {
+            text += &instruction.to_string();
+        }
+        Some(text.trim_end().to_string())
+    }
+
+    fn range(&self, span: Span) -> Json {
+        let text = self.line_text(span.line);
+        let line = span.line as i64 - 1;
+        let start = utf16_units(text, span.column - 1);
+        let end = utf16_units(text, span.column - 1 + span.len);
+        object(vec![
+            ("start", object(vec![("line", line.into()), ("character", start.into())])),
+            ("end", object(vec![("line", line.into()), ("character", end.into())])),
+        ])
+    }
+
+    /// # The 1-based line and char column of an LSP position
+    fn position(&self, position: &Json) -> Option<(usize, usize)> {
+        let line = position.get("line").as_i64()? as usize + 1;
+        let units = position.get("character").as_i64()? as usize;
+        let mut seen = 0;
+        let chars = self
+            .line_text(line)
+            .chars()
+            .take_while(|c| {
+                seen += c.len_utf16();
+                seen <= units
+            })
+            .count();
+        Some((line, chars + 1))
+    }
+}
+
+/// # How many UTF-16 code units the first chars chars of text take
+fn utf16_units(text: &str, chars: usize) -> i64 {
+    let units: usize = text.chars().take(chars).map(char::len_utf16).sum();
+    (units + chars.saturating_sub(text.chars().count())) as i64
+}
+
+pub struct LanguageServer {
+    documents: HashMap<String, Analysis>,
+}
+
+impl Default for LanguageServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LanguageServer {
+    pub fn new() -> Self {
+        Self {
+            documents: HashMap::new(),
+        }
+    }
+
+    /// # Answers messages until the client sends exit or input ends
+    pub fn serve(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        while let Some(body) = read_message(input)? {
+            let message = match Json::parse(&body) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let method = message.get("method").as_str().unwrap_or("");
+            let params = message.get("params");
+            if method == "exit" {
+                return Ok(());
+            }
+            self.notice(method, params, output)?;
+
+            // Only requests carry an id, and only requests are answered
+            let id = message.get("id");
+            if *id == Json::Null {
+                continue;
+            }
+            let mut reply = vec![("jsonrpc", "2.0".into()), ("id", id.clone())];
+            match self.answer(method, params) {
+                Some(result) => reply.push(("result", result)),
+                None => reply.push((
+                    "error",
+                    object(vec![
+                        ("code", (-32601).into()),
+                        ("message", format!("unsupported method {}", method).into()),
+                    ]),
+                )),
+            }
+            write_message(output, &object(reply))?;
+        }
+        Ok(())
+    }
+
+    /// # Keeps documents up to date, publishing diagnostics whenever one changes
+    fn notice(&mut self, method: &str, params: &Json, output: &mut impl Write) -> io::Result<()> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let text = match method {
+            "textDocument/didOpen" => params.get("textDocument").get("text").as_str(),
+            // Full sync, so the last change holds the whole document
+            "textDocument/didChange" => params
+                .get("contentChanges")
+                .elements()
+                .last()
+                .and_then(|change| change.get("text").as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return publish(output, uri, Vec::new());
+            }
+            _ => return Ok(()),
+        };
+        let analysis = Analysis::new(text.unwrap_or(""));
+        let diagnostics = analysis
+            .diagnostics
+            .iter()
+            .map(|(span, message)| {
+                object(vec![
+                    ("range", analysis.range(*span)),
+                    ("severity", ERROR.into()),
+                    ("source", "lrc3".into()),
+                    ("message", message.as_str().into()),
+                ])
+            })
+            .collect();
+        self.documents.insert(uri.to_string(), analysis);
+        publish(output, uri, diagnostics)
+    }
+
+    /// # The result of a request, or None if the method isn't supported
+    fn answer(&self, method: &str, params: &Json) -> Option<Json> {
+        let uri = params.get("textDocument").get("uri").as_str().unwrap_or("");
+        let document = self.documents.get(uri);
+        let position = document.and_then(|d| d.position(params.get("position")));
+        let location = |analysis: &Analysis, span: Span| {
+            object(vec![("uri", uri.into()), ("range", analysis.range(span))])
+        };
+        match method {
+            "initialize" => Some(object(vec![(
+                "capabilities",
+                object(vec![
+                    ("textDocumentSync", 1.into()),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    (
+                        "completionProvider",
+                        object(vec![("triggerCharacters", vec![".".into()].into())]),
+                    ),
+                ]),
+            )])),
+            "shutdown" => Some(Json::Null),
+            "textDocument/hover" => Some(
+                match document.zip(position).and_then(|(d, (line, _))| d.hover(line)) {
+                    Some(text) => object(vec![(
+                        "contents",
+                        object(vec![("kind", "plaintext".into()), ("value", text.into())]),
+                    )]),
+                    None => Json::Null,
+                },
+            ),
+            "textDocument/definition" => Some(
+                document
+                    .zip(position)
+                    .and_then(|(d, (line, column))| {
+                        let label = d.label_at(line, column)?;
+                        let (_, span, _) = d.definitions.iter().find(|(name, _, _)| name == label)?;
+                        Some(location(d, *span))
+                    })
+                    .unwrap_or(Json::Null),
+            ),
+            "textDocument/references" => {
+                let declaration = params
+                    .get("context")
+                    .get("includeDeclaration")
+                    .as_bool()
+                    .unwrap_or(false);
+                Some(
+                    document
+                        .zip(position)
+                        .and_then(|(d, (line, column))| {
+                            let label = d.label_at(line, column)?;
+                            let definitions = d
+                                .definitions
+                                .iter()
+                                .filter(|_| declaration)
+                                .map(|(name, span, _)| (name, span));
+                            let references = d.references.iter().map(|(name, span)| (name, span));
+                            Some(
+                                definitions
+                                    .chain(references)
+                                    .filter(|(name, _)| name.as_str() == label)
+                                    .map(|(_, span)| location(d, *span))
+                                    .collect::<Vec<Json>>()
+                                    .into(),
+                            )
+                        })
+                        .unwrap_or(Json::Null),
+                )
+            }
+            "textDocument/completion" => {
+                let keywords = OPCODES.iter().chain(DIRECTIVES.iter()).map(|keyword| {
+                    object(vec![("label", (*keyword).into()), ("kind", KEYWORD.into())])
+                });
+                let labels = document
+                    .map(|d| d.definitions.as_slice())
+                    .unwrap_or(&[])
+                    .iter()
+                    .map(|(name, _, address)| {
+                        let detail = match address {
+                            Some(address) => format!("x{:04X}", address),
+                            None => "outside .ORIG".to_string(),
+                        };
+                        object(vec![
+                            ("label", name.as_str().into()),
+                            ("kind", REFERENCE.into()),
+                            ("detail", detail.into()),
+                        ])
+                    });
+                Some(keywords.chain(labels).collect::<Vec<Json>>().into())
+            }
+            _ => None,
+        }
+    }
+}
+
+fn publish(output: &mut impl Write, uri: &str, diagnostics: Vec<Json>) -> io::Result<()> {
+    write_message(
+        output,
+        &object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                object(vec![("uri", uri.into()), ("diagnostics", diagnostics.into())]),
+            ),
+        ]),
+    )
+}
+
+#[test]
+fn test_language_server_answers_about_labels_and_flags_offsets() {
+    let source = "        .ORIG x3000
+LOOP    ADD R1, R1, #-1
+        BRp LOOP
+        LD R0, FAR
+        LDR R2, R3, #40
+        HALT
+        .BLKW 300
+FAR     .FILL 0
+        .END
+";
+    let request = |id: i64, method: &str, params: Json| {
+        object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ])
+    };
+    let at = |line: i64, character: i64| {
+        object(vec![
+            ("textDocument", object(vec![("uri", "file:///a.asm".into())])),
+            (
+                "position",
+                object(vec![("line", line.into()), ("character", character.into())]),
+            ),
+            ("context", object(vec![("includeDeclaration", true.into())])),
+        ])
+    };
+    let messages = vec![
+        request(1, "initialize", object(vec![])),
+        object(vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            (
+                "params",
+                object(vec![(
+                    "textDocument",
+                    object(vec![("uri", "file:///a.asm".into()), ("text", source.into())]),
+                )]),
+            ),
+        ]),
+        request(2, "textDocument/definition", at(2, 13)),
+        request(3, "textDocument/references", at(1, 1)),
+        request(4, "textDocument/hover", at(1, 10)),
+        request(5, "textDocument/completion", at(5, 8)),
+        request(6, "textDocument/formatting", object(vec![])),
+        object(vec![("jsonrpc", "2.0".into()), ("method", "exit".into())]),
+    ];
+    let mut input = Vec::new();
+    for message in &messages {
+        write_message(&mut input, message).unwrap();
+    }
+    let mut output = Vec::new();
+    LanguageServer::new()
+        .serve(&mut io::Cursor::new(input), &mut output)
+        .unwrap();
+
+    let mut reader = io::Cursor::new(output);
+    let mut replies = Vec::new();
+    while let Some(body) = read_message(&mut reader).unwrap() {
+        replies.push(Json::parse(&body).unwrap());
+    }
+    let result = |id: i64| {
+        replies
+            .iter()
+            .find(|reply| reply.get("id").as_i64() == Some(id))
+            .unwrap()
+            .clone()
+    };
+
+    // Both offsets are flagged on their operands
+    let diagnostics = replies[1].get("params").get("diagnostics").elements().to_vec();
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(
+        diagnostics[0].get("message").as_str(),
+        Some("302 does not fit in PCoffset9 (must be between -256 and 255)")
+    );
+    assert_eq!(
+        diagnostics[0].get("range").get("start").get("character").as_i64(),
+        Some(15)
+    );
+    assert_eq!(diagnostics[1].get("range").get("start").get("line").as_i64(), Some(4));
+
+    let definition = result(2).get("result").clone();
+    assert_eq!(definition.get("range").get("start").get("line").as_i64(), Some(1));
+    assert_eq!(definition.get("range").get("end").get("character").as_i64(), Some(4));
+    assert_eq!(result(3).get("result").elements().len(), 2);
+    // Lines without errors still show what they assembled to
+    assert_eq!(
+        result(4).get("result").get("contents").get("value").as_str(),
+        Some("x3000: x127F  0001001001111111\nADDi R1, #-1 -> R1")
+    );
+    let completions = result(5).get("result").elements().to_vec();
+    assert!(completions
+        .iter()
+        .any(|item| item.get("label").as_str() == Some(".STRINGZ")));
+    assert!(completions
+        .iter()
+        .any(|item| item.get("label").as_str() == Some("FAR")
+            && item.get("detail").as_str() == Some("x3131")));
+    assert_eq!(result(6).get("error").get("code").as_i64(), Some(-32601));
+
+    let fixed = Analysis::new(&source.replace("#40", "#4").replace(".BLKW 300", ".BLKW 3"));
+    assert!(fixed.diagnostics.is_empty());
+    assert_eq!(
+        fixed.hover(2).unwrap(),
+        "x3000: x127F  0001001001111111\nADDi R1, #-1 -> R1"
+    );
+}
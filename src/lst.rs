@@ -0,0 +1,139 @@
+use crate::asm::Assembly;
+use core::fmt::{Display, Error, Formatter};
+use std::path::Path;
+
+/* An assembler listing: every source line beside what it assembled to
+ *
+ *  Line  Addr   Hex    Binary            Source                 Instruction
+ *     2  x3000  x1261  0001001001100001  LOOP ADD R1, R1, #1    ADDi R1, #1 -> R1
+ *
+ * Statements that fill more than one word (.STRINGZ, .BLKW) continue on rows
+ * of their own below the source line, and a run of identical words, as .BLKW
+ * leaves, is shortened to one row with its address range. The resolved
+ * address of every label follows the listing.
+ */
+
+pub struct Listing<'a> {
+    source: &'a str,
+    assembly: &'a Assembly,
+}
+
+impl<'a> Listing<'a> {
+    pub fn new(source: &'a str, assembly: &'a Assembly) -> Self {
+        Self { source, assembly }
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+}
+
+/// # The line with tabs expanded to the next multiple of 8 columns, so the annotations line up
+fn expand_tabs(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        if c == '\t' {
+            out.push(' ');
+            while out.chars().count() % 8 != 0 {
+                out.push(' ');
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let lines: Vec<String> = self.source.lines().map(expand_tabs).collect();
+        let width = lines
+            .iter()
+            .map(|line| line.trim_end().chars().count())
+            .max()
+            .unwrap_or(0)
+            .max("Source".len());
+        let row = |f: &mut Formatter<'_>, line: &str, words: &str, source: &str, note: &str| {
+            let text = format!("{:>5}  {:<30}  {:<width$}  {}", line, words, source, note, width = width);
+            writeln!(f, "{}", text.trim_end())
+        };
+        let word = |address: u16, bits: u16| format!("x{:04X}  x{:04X}  {:016b}", address, bits, bits);
+
+        row(f, "Line", "Addr   Hex    Binary", "Source", "Instruction")?;
+        let mut statements = self.assembly.statements.iter().peekable();
+        for (index, source) in lines.iter().enumerate() {
+            let number = index + 1;
+            let source = source.trim_end();
+            let statement = match statements.peek() {
+                Some(statement) if statement.line == number => statements.next().unwrap(),
+                _ => {
+                    row(f, &number.to_string(), "", source, "")?;
+                    continue;
+                }
+            };
+            let (first, rest) = match statement.words.split_first() {
+                Some(split) => split,
+                None => {
+                    row(f, &number.to_string(), "", source, "")?;
+                    continue;
+                }
+            };
+            let note = match &statement.instruction {
+                Some(instruction) => instruction.to_string(),
+                None => String::new(),
+            };
+            row(f, &number.to_string(), &word(statement.address, *first), source, &note)?;
+
+            let mut offset = 1;
+            while offset <= rest.len() {
+                let bits = rest[offset - 1];
+                let run = rest[offset - 1..].iter().take_while(|w| **w == bits).count();
+                let address = statement.address.wrapping_add(offset as u16);
+                if run > 2 {
+                    let last = address.wrapping_add(run as u16 - 1);
+                    let note = format!("through x{:04X} ({} words)", last, run);
+                    row(f, "", &word(address, bits), "", &note)?;
+                    offset += run;
+                } else {
+                    row(f, "", &word(address, bits), "", "")?;
+                    offset += 1;
+                }
+            }
+        }
+
+        if !self.assembly.symbols.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "Label             Address")?;
+            for (label, address) in &self.assembly.symbols {
+                writeln!(f, "{:<16}  x{:04X}", label, address)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_listing_shows_encodings_beside_source() {
+    use crate::asm::assemble;
+
+    let source = "\t.ORIG x3000\nLOOP\tADD R1, R1, #1 ; count\n\tBRp LOOP\nMSG\t.STRINGZ \"Hi\"\n\t.BLKW 4\n\t.END";
+    let assembly = assemble(source).unwrap();
+    let listing = Listing::new(source, &assembly).to_string();
+    let expected = r#"
+ Line  Addr   Hex    Binary            Source                          Instruction
+    1                                          .ORIG x3000
+    2  x3000  x1261  0001001001100001  LOOP    ADD R1, R1, #1 ; count  ADDi R1, #1 -> R1
+    3  x3001  x03FE  0000001111111110          BRp LOOP                BRp #-2
+    4  x3002  x0048  0000000001001000  MSG     .STRINGZ "Hi"
+       x3003  x0069  0000000001101001
+       x3004  x0000  0000000000000000
+    5  x3005  x0000  0000000000000000          .BLKW 4
+       x3006  x0000  0000000000000000                                  through x3008 (3 words)
+    6                                          .END
+
+Label             Address
+LOOP              x3000
+MSG               x3002
+"#;
+    assert_eq!(listing, &expected[1..]);
+}
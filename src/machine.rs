@@ -0,0 +1,845 @@
+use crate::devices::{Devices, DDR, KBDR, KBSR};
+use crate::lrc3::*;
+use crate::obj::{ObjectError, ObjectFile};
+use crate::os;
+use std::collections::{HashMap, HashSet};
+
+/* An instruction level LC-3 simulator
+ *
+ * Where Lrc3Cpu steps the datapath one microstate at a time, Machine executes
+ * one whole Instruction per step, which is all that's needed to run programs
+ * to completion and check their results.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HaltReason {
+    /// MCR[15] was cleared, as the OS's HALT routine does
+    Halted,
+    /// run() used up its step budget before the program halted
+    StepLimit,
+    /// The word at pc has a defined opcode, but breaks one of its encoding's fixed fields, which
+    /// a strict DecodePolicy doesn't allow. Lrc3Cpu never looks at those fields, and runs the word
+    /// as if they held their required values, as a permissive policy does
+    IllegalInstruction { pc: u16, bits: u16 },
+}
+
+/// # A trap service routine written in Rust, run in place of the one the vector table points to
+pub type TrapHandler = Box<dyn FnMut(&mut Machine)>;
+
+pub struct Machine {
+    regfile: Regfile,
+    pc: u16,
+    psr: Psr,
+    saved_ssp: u16,
+    saved_usp: u16,
+    interrupt: Option<Interrupt>,
+    memory: Memory,
+    devices: Devices,
+    traps: HashMap<u8, TrapHandler>,
+    halted: Option<HaltReason>,
+    policy: DecodePolicy,
+    /// Fixed fields a permissive policy ignored, with the address of the word that broke them
+    ignored: Vec<(u16, OpcodeAssumptionsViolation)>,
+    /// Each address and word already recorded in ignored, so a loop only adds its words once
+    ignored_words: HashSet<(u16, u16)>,
+    /// Whether memory holds the built-in OS, whose trap return has to suit the edition
+    booted: bool,
+}
+
+impl Machine {
+    /// # Starts in supervisor mode, with R6 pointing at the supervisor stack
+    pub fn new(memory: Memory, starting_pc: u16) -> Self {
+        let mut regfile = Regfile::new();
+        regfile.set_contents_of(RegisterName::R6, RegisterContents::new(INITIAL_SSP));
+        Self {
+            regfile,
+            pc: starting_pc,
+            psr: Psr::from_bits(0x0002),
+            saved_ssp: INITIAL_SSP,
+            saved_usp: 0,
+            interrupt: None,
+            memory,
+            devices: Devices::console(),
+            traps: HashMap::new(),
+            halted: None,
+            policy: DecodePolicy::default(),
+            ignored: Vec::new(),
+            ignored_words: HashSet::new(),
+            booted: false,
+        }
+    }
+
+    /// # Loads the built-in OS, then each object, and starts at the first object's origin
+    pub fn from_objects(objects: &[ObjectFile]) -> Result<Self, ObjectError> {
+        let (memory, starting_pc) = os::boot(objects)?;
+        let mut machine = Self::new(memory, starting_pc);
+        machine.booted = true;
+        machine.set_decode_policy(DecodePolicy::default());
+        Ok(machine)
+    }
+
+    pub fn register(&self, reg: RegisterName) -> u16 {
+        match reg {
+            RegisterName::PC => self.pc,
+            RegisterName::PSR => self.psr.bits(),
+            RegisterName::SavedSSP => self.saved_ssp,
+            RegisterName::SavedUSP => self.saved_usp,
+            gpr => self.regfile.contents_of(gpr).0,
+        }
+    }
+
+    pub fn set_register(&mut self, reg: RegisterName, data: u16) {
+        match reg {
+            RegisterName::PC => self.pc = data,
+            RegisterName::PSR => self.psr = Psr::from_bits(data),
+            RegisterName::SavedSSP => self.saved_ssp = data,
+            RegisterName::SavedUSP => self.saved_usp = data,
+            gpr => self.regfile.set_contents_of(gpr, RegisterContents::new(data)),
+        }
+    }
+
+    pub fn regfile(&self) -> &Regfile {
+        &self.regfile
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn psr(&self) -> Psr {
+        self.psr
+    }
+
+    /// # Condition codes as (n, z, p)
+    pub fn nzp(&self) -> (bool, bool, bool) {
+        (self.psr.n, self.psr.z, self.psr.p)
+    }
+
+    pub fn set_nzp(&mut self, n: bool, z: bool, p: bool) {
+        self.psr.n = n;
+        self.psr.z = z;
+        self.psr.p = p;
+    }
+
+    /// # Raises an interrupt, which is taken before the next fetch if it outranks the current priority
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn devices(&self) -> &Devices {
+        &self.devices
+    }
+
+    pub fn devices_mut(&mut self) -> &mut Devices {
+        &mut self.devices
+    }
+
+    pub fn decode_policy(&self) -> DecodePolicy {
+        self.policy
+    }
+
+    /// # Also points the built-in OS's trap routines at the return the policy's edition needs
+    pub fn set_decode_policy(&mut self, policy: DecodePolicy) {
+        self.policy = policy;
+        if self.booted {
+            os::set_trap_return(&mut self.memory, policy.edition);
+        }
+    }
+
+    /// # Decodes bits as this machine would run them
+    pub fn decode(&self, bits: u16) -> Result<Instruction, Lrc3Error> {
+        Instruction::decode_with(bits, &self.policy).map(|(instruction, _)| instruction)
+    }
+
+    /// # The fixed fields a permissive policy has ignored since the last call, with where each word was.
+    /// A word is only reported the first time it runs from its address
+    pub fn take_ignored_fields(&mut self) -> Vec<(u16, OpcodeAssumptionsViolation)> {
+        std::mem::take(&mut self.ignored)
+    }
+
+    pub fn halted(&self) -> Option<HaltReason> {
+        self.halted
+    }
+
+    /// # Stops the machine as HALT would, for use by trap handlers
+    pub fn halt(&mut self) {
+        self.halted = Some(HaltReason::Halted);
+    }
+
+    /* Host traps
+     *
+     * A registered handler runs instead of the whole TRAP sequence: nothing is
+     * pushed, and the handler sees PC already pointing past the TRAP. A handler
+     * that cannot finish yet, such as GETC with no key typed, can set PC back
+     * to the TRAP so it runs again on the next step.
+     */
+
+    /// # Runs handler whenever TRAP vector executes, replacing any handler already registered
+    pub fn register_trap(&mut self, vector: u8, handler: impl FnMut(&mut Machine) + 'static) {
+        self.traps.insert(vector, Box::new(handler));
+    }
+
+    /// # Goes back to taking vector through the trap vector table
+    pub fn unregister_trap(&mut self, vector: u8) {
+        self.traps.remove(&vector);
+    }
+
+    /// # Replaces the OS's GETC, OUT, PUTS, IN, PUTSP and HALT with native versions
+    pub fn register_builtin_traps(&mut self) {
+        self.register_trap(0x20, host_getc);
+        self.register_trap(0x21, |machine| {
+            let c = machine.register(RegisterName::R0);
+            machine.devices_mut().write(DDR, c);
+        });
+        self.register_trap(0x22, |machine| {
+            host_puts(machine, |word| vec![word]);
+        });
+        let mut prompted = false;
+        self.register_trap(0x23, move |machine| {
+            if !prompted {
+                host_display(machine, b"\nInput a character> ");
+                prompted = true;
+            }
+            if let Some(c) = host_key(machine) {
+                machine.devices_mut().write(DDR, c);
+                machine.set_register(RegisterName::R0, c);
+                host_display(machine, b"\n");
+                prompted = false;
+            }
+        });
+        self.register_trap(0x24, |machine| {
+            host_puts(machine, |word| vec![word & 0xff, word >> 8]);
+        });
+        self.register_trap(0x25, |machine| {
+            host_display(machine, b"\n--- Halting the LC-3 ---\n");
+            machine.halt();
+        });
+    }
+
+    fn reg(&self, reg: RegisterName) -> u16 {
+        self.regfile.contents_of(reg).0
+    }
+
+    fn set_reg(&mut self, reg: RegisterName, data: u16) {
+        self.regfile.set_contents_of(reg, RegisterContents::new(data));
+    }
+
+    /// # Memory as the program would read it, without the side effects of reading devices
+    pub fn peek(&self, address: u16) -> u16 {
+        match address {
+            PSR_ADDRESS => self.psr.bits(),
+            _ => match self.devices.peek(address) {
+                Some(data) => data,
+                None => self.memory.read(address),
+            },
+        }
+    }
+
+    /// # Writes memory as a program's store would, with the PSR and devices mapped in
+    pub fn poke(&mut self, address: u16, data: u16) {
+        self.write(address, data);
+    }
+
+    /// # Memory as the program sees it, with the PSR and devices mapped in
+    fn read(&mut self, address: u16) -> u16 {
+        match address {
+            PSR_ADDRESS => self.psr.bits(),
+            _ => match self.devices.read(address) {
+                Some(data) => data,
+                None => self.memory.read(address),
+            },
+        }
+    }
+
+    fn write(&mut self, address: u16, data: u16) {
+        match address {
+            PSR_ADDRESS => self.psr = Psr::from_bits(data),
+            _ => {
+                if !self.devices.write(address, data) {
+                    self.memory.write(address, data);
+                }
+            }
+        }
+    }
+
+    /// # A read on behalf of the program, which may not reach system space from user mode
+    fn load(&mut self, address: u16) -> Result<u16, Exception> {
+        match access_violation(self.psr.privilege, address) {
+            true => Err(Exception::AccessControlViolation),
+            false => Ok(self.read(address)),
+        }
+    }
+
+    fn store(&mut self, address: u16, data: u16) -> Result<(), Exception> {
+        if access_violation(self.psr.privilege, address) {
+            return Err(Exception::AccessControlViolation);
+        }
+        self.write(address, data);
+        Ok(())
+    }
+
+    fn push(&mut self, data: u16) {
+        let sp = self.reg(RegisterName::R6).wrapping_sub(1);
+        self.set_reg(RegisterName::R6, sp);
+        self.write(sp, data);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let sp = self.reg(RegisterName::R6);
+        self.set_reg(RegisterName::R6, sp.wrapping_add(1));
+        self.read(sp)
+    }
+
+    /// # Enters supervisor mode, saving PSR and PC on the supervisor stack, and jumps through entry
+    fn initiate(&mut self, entry: u16, priority: u16) {
+        let psr = self.psr;
+        if psr.privilege == Privilege::User {
+            self.saved_usp = self.reg(RegisterName::R6);
+            self.set_reg(RegisterName::R6, self.saved_ssp);
+        }
+        self.psr.privilege = Privilege::Supervisor;
+        self.psr.priority = priority;
+
+        self.push(psr.bits());
+        self.push(self.pc);
+        self.pc = self.read(entry);
+    }
+
+    fn raise(&mut self, exception: Exception) {
+        self.initiate(INTERRUPT_TABLE | exception.vector() as u16, self.psr.priority);
+    }
+
+    fn set_cc(&mut self, data: u16) {
+        self.set_nzp(data >> 15 == 1, data == 0, data >> 15 == 0 && data != 0);
+    }
+
+    /// # Sets DR and the condition codes from the value written to it
+    fn write_dr(&mut self, dr: RegisterName, data: u16) {
+        self.set_reg(dr, data);
+        self.set_cc(data);
+    }
+
+    /// # Fetches, decodes and executes one instruction, returning why the machine stopped if it did
+    pub fn step(&mut self) -> Option<HaltReason> {
+        if self.halted.is_some() {
+            return self.halted;
+        }
+
+        self.devices.tick();
+        // A requested interrupt is taken once, but a device keeps asking until it is satisfied
+        let pending = match (self.interrupt, self.devices.interrupt()) {
+            (Some(requested), Some(device)) if device.priority > requested.priority => Some(device),
+            (None, device) => device,
+            (requested, _) => requested,
+        };
+        if let Some(interrupt) = pending {
+            if interrupt.priority > self.psr.priority {
+                if pending == self.interrupt {
+                    self.interrupt = None;
+                }
+                self.initiate(INTERRUPT_TABLE | interrupt.vector as u16, interrupt.priority);
+                return None;
+            }
+        }
+
+        let bits = match self.load(self.pc) {
+            Ok(bits) => bits,
+            Err(exception) => {
+                self.raise(exception);
+                return None;
+            }
+        };
+        let instruction = match Instruction::decode_with(bits, &self.policy) {
+            Ok((instruction, ignored)) => {
+                let pc = self.pc;
+                if !ignored.is_empty() && self.ignored_words.insert((pc, bits)) {
+                    self.ignored.extend(ignored.into_iter().map(|violation| (pc, violation)));
+                }
+                instruction
+            }
+            Err(Lrc3Error::UnknownOpcode(_)) => {
+                self.pc = self.pc.wrapping_add(1);
+                self.raise(Exception::IllegalOpcode);
+                return None;
+            }
+            Err(_) => {
+                self.halted = Some(HaltReason::IllegalInstruction { pc: self.pc, bits });
+                return self.halted;
+            }
+        };
+        self.pc = self.pc.wrapping_add(1);
+        if let Err(exception) = self.execute(&instruction) {
+            self.raise(exception);
+        }
+        if !self.devices.clock_enabled() {
+            self.halted = Some(HaltReason::Halted);
+        }
+        self.halted
+    }
+
+    /// # Steps until the machine halts or max_steps instructions have run
+    pub fn run(&mut self, max_steps: usize) -> HaltReason {
+        for _ in 0..max_steps {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        HaltReason::StepLimit
+    }
+
+    /// # Runs one instruction, leaving the machine untouched apart from PC if it raises an exception
+    fn execute(&mut self, instruction: &Instruction) -> Result<(), Exception> {
+        let pc = self.pc;
+        match instruction {
+            Instruction::Add(args) => {
+                let sum = self.reg(args.sr1).wrapping_add(self.reg(args.sr2));
+                self.write_dr(args.dr, sum);
+            }
+            Instruction::Addi(args) => {
+                let sum = self.reg(args.sr1).wrapping_add(args.imm5.value());
+                self.write_dr(args.dr, sum);
+            }
+            Instruction::And(args) => {
+                let and = self.reg(args.sr1) & self.reg(args.sr2);
+                self.write_dr(args.dr, and);
+            }
+            Instruction::Andi(args) => {
+                let and = self.reg(args.sr1) & args.imm5.value();
+                self.write_dr(args.dr, and);
+            }
+            Instruction::Not(args) => {
+                let not = !self.reg(args.sr);
+                self.write_dr(args.dr, not);
+            }
+            Instruction::Br(args) => {
+                let (n, z, p) = self.nzp();
+                if (args.n.0 && n) || (args.z.0 && z) || (args.p.0 && p) {
+                    self.pc = pc.wrapping_add(args.pcoffset9.value());
+                }
+            }
+            Instruction::Jmp(args) => {
+                self.pc = self.reg(args.base_r);
+            }
+            Instruction::Jsr(args) => {
+                self.set_reg(RegisterName::R7, pc);
+                self.pc = pc.wrapping_add(args.pcoffset11.value());
+            }
+            Instruction::Jsrr(args) => {
+                let target = self.reg(args.base_r);
+                self.set_reg(RegisterName::R7, pc);
+                self.pc = target;
+            }
+            Instruction::Ld(args) => {
+                let data = self.load(pc.wrapping_add(args.pcoffset9.value()))?;
+                self.write_dr(args.dr, data);
+            }
+            Instruction::Ldi(args) => {
+                let pointer = self.load(pc.wrapping_add(args.pcoffset9.value()))?;
+                let data = self.load(pointer)?;
+                self.write_dr(args.dr, data);
+            }
+            Instruction::Ldr(args) => {
+                let address = self.reg(args.base_r).wrapping_add(args.offset6.value());
+                let data = self.load(address)?;
+                self.write_dr(args.dr, data);
+            }
+            Instruction::Lea(args) => {
+                let address = pc.wrapping_add(args.pcoffset9.value());
+                match instruction.sets_cc(self.policy.edition) {
+                    true => self.write_dr(args.dr, address),
+                    false => self.set_reg(args.dr, address),
+                }
+            }
+            Instruction::St(args) => {
+                let data = self.reg(args.sr);
+                self.store(pc.wrapping_add(args.offset9.value()), data)?;
+            }
+            Instruction::Sti(args) => {
+                let pointer = self.load(pc.wrapping_add(args.offset9.value()))?;
+                let data = self.reg(args.sr);
+                self.store(pointer, data)?;
+            }
+            Instruction::Str(args) => {
+                let address = self.reg(args.base_r).wrapping_add(args.offset6.value());
+                let data = self.reg(args.sr);
+                self.store(address, data)?;
+            }
+            Instruction::Trap(args) => {
+                let vector = args.trapvect8.masked() as u8;
+                match self.traps.remove(&vector) {
+                    Some(mut handler) => {
+                        handler(self);
+                        // Unless the handler registered a replacement for itself
+                        self.traps.entry(vector).or_insert(handler);
+                    }
+                    // The second edition's TRAP is a subroutine call through the vector table
+                    None => match self.policy.edition {
+                        Edition::Second => {
+                            self.set_reg(RegisterName::R7, self.pc);
+                            self.pc = self.read(args.trapvect8.masked());
+                        }
+                        Edition::Third => self.initiate(args.trapvect8.masked(), self.psr.priority),
+                    },
+                }
+            }
+            Instruction::Rti() => {
+                if self.psr.privilege == Privilege::User {
+                    return Err(Exception::PrivilegeViolation);
+                }
+                self.pc = self.pop();
+                self.psr = Psr::from_bits(self.pop());
+                if self.psr.privilege == Privilege::User {
+                    self.saved_ssp = self.reg(RegisterName::R6);
+                    self.set_reg(RegisterName::R6, self.saved_usp);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// # The next typed character, or None after setting PC back so the TRAP runs again
+fn host_key(machine: &mut Machine) -> Option<u16> {
+    let devices = machine.devices_mut();
+    match devices.read(KBSR) {
+        Some(status) if status & 0x8000 != 0 => devices.read(KBDR),
+        _ => {
+            let pc = machine.pc().wrapping_sub(1);
+            machine.set_register(RegisterName::PC, pc);
+            None
+        }
+    }
+}
+
+fn host_getc(machine: &mut Machine) {
+    if let Some(c) = host_key(machine) {
+        machine.set_register(RegisterName::R0, c);
+    }
+}
+
+fn host_display(machine: &mut Machine, text: &[u8]) {
+    for c in text {
+        machine.devices_mut().write(DDR, *c as u16);
+    }
+}
+
+/// # Displays the characters unpacked from each word at R0, up to the first zero
+fn host_puts(machine: &mut Machine, unpack: impl Fn(u16) -> Vec<u16>) {
+    let mut address = machine.register(RegisterName::R0);
+    loop {
+        for c in unpack(machine.memory().read(address)) {
+            if c == 0 {
+                return;
+            }
+            machine.devices_mut().write(DDR, c);
+        }
+        address = address.wrapping_add(1);
+    }
+}
+
+#[cfg(test)]
+fn assembled(source: &str) -> Machine {
+    let assembly = crate::asm::assemble(source).unwrap();
+    Machine::from_objects(&ObjectFile::from_assembly(&assembly)).unwrap()
+}
+
+#[test]
+fn test_machine_runs_until_halt() {
+    let mut machine = assembled(
+        "
+        .ORIG x3000
+        AND R0, R0, #0
+        LD R1, COUNT
+LOOP    ADD R0, R0, R1
+        ADD R1, R1, #-1
+        BRp LOOP
+        ST R0, SUM
+        HALT
+COUNT   .FILL 10
+SUM     .BLKW 1
+        .END
+    ",
+    );
+    assert_eq!(machine.run(1000), HaltReason::Halted);
+    assert_eq!(machine.memory().read(0x3008), 55);
+    assert_eq!(machine.register(RegisterName::R0), 55);
+    assert_eq!(machine.nzp(), (false, true, false));
+    assert_eq!(machine.step(), Some(HaltReason::Halted));
+}
+
+#[test]
+fn test_machine_stops_at_step_limit_and_illegal_instruction() {
+    let mut spin = assembled(".ORIG x3000\nSPIN BR SPIN\n.END");
+    assert_eq!(spin.run(50), HaltReason::StepLimit);
+    assert_eq!(spin.pc(), 0x3000);
+
+    let mut illegal = assembled(".ORIG x3000\nADD R0, R0, #1\n.FILL x1018\n.END");
+    assert_eq!(
+        illegal.run(50),
+        HaltReason::IllegalInstruction {
+            pc: 0x3001,
+            bits: 0x1018
+        }
+    );
+}
+
+#[test]
+fn test_machine_follows_its_decode_policy() {
+    // ADD R0, R1, R1 and ADD R0, R0, R0 with IR[3] set make R0 -4, then LEA R2, #-2
+    let source = ".ORIG x3000\nADD R1, R1, #-1\n.FILL x1041\n.FILL x1008\nLEA R2, #-2\n.END";
+    let mut strict = assembled(source);
+    assert_eq!(strict.run(50), HaltReason::IllegalInstruction { pc: 0x3002, bits: 0x1008 });
+
+    let mut permissive = assembled(source);
+    permissive.set_decode_policy(DecodePolicy::permissive());
+    for _ in 0..3 {
+        permissive.step();
+    }
+    assert_eq!(permissive.register(RegisterName::R0), 0xfffc);
+    let ignored = permissive.take_ignored_fields();
+    assert_eq!(ignored.len(), 1);
+    assert_eq!(ignored[0].0, 0x3002);
+    assert!(permissive.take_ignored_fields().is_empty());
+    // Running the same word again reports nothing new
+    permissive.set_register(RegisterName::PC, 0x3002);
+    permissive.step();
+    assert!(permissive.take_ignored_fields().is_empty());
+
+    // Only the second edition's LEA sets the condition codes
+    permissive.step();
+    assert_eq!(permissive.register(RegisterName::R2), 0x3002);
+    assert_eq!(permissive.nzp(), (false, false, true));
+    let mut third = assembled(source);
+    third.set_decode_policy(DecodePolicy {
+        strictness: Strictness::Permissive,
+        edition: Edition::Third,
+    });
+    for _ in 0..4 {
+        third.step();
+    }
+    assert_eq!(third.nzp(), (true, false, false));
+}
+
+#[test]
+fn test_machine_interrupt_and_rti_swap_stacks() {
+    let mut machine = assembled(
+        "
+        .ORIG x0180
+        .FILL HANDLER
+        .END
+        .ORIG x1000
+HANDLER LDI R2, PSR
+        RTI
+PSR     .FILL xFFFC
+        .END
+        .ORIG x4000
+        ADD R1, R1, #1
+        ADD R1, R1, #1
+        .END
+    ",
+    );
+    machine.set_register(RegisterName::PC, 0x4000);
+    machine.set_register(RegisterName::PSR, 0x8001);
+    machine.set_register(RegisterName::R6, 0xfd00);
+    machine.step();
+
+    machine.request_interrupt(Interrupt {
+        vector: 0x80,
+        priority: 4,
+    });
+    assert_eq!(machine.step(), None);
+    assert_eq!(machine.pc(), 0x1000);
+    assert_eq!(machine.register(RegisterName::R6), INITIAL_SSP - 2);
+    assert_eq!(machine.register(RegisterName::SavedUSP), 0xfd00);
+
+    // The handler sees the PSR through its memory mapped address
+    machine.step();
+    assert_eq!(machine.register(RegisterName::R2), 0x0401);
+    machine.step();
+    assert_eq!(machine.pc(), 0x4001);
+    assert_eq!(machine.register(RegisterName::PSR), 0x8001);
+    assert_eq!(machine.register(RegisterName::R6), 0xfd00);
+    machine.step();
+    assert_eq!(machine.register(RegisterName::R1), 2);
+}
+
+#[test]
+fn test_machine_raises_exceptions_through_vector_table() {
+    let mut machine = assembled(
+        "
+        .ORIG x0100
+        .FILL PRIV
+        .FILL ILLEGAL
+        .FILL ACV
+        .END
+        .ORIG x1000
+PRIV    ADD R0, R0, #1
+        RTI
+ILLEGAL ADD R1, R1, #1
+        RTI
+ACV     ADD R2, R2, #1
+        RTI
+        .END
+        .ORIG x3000
+        .FILL xD000
+        RTI
+        LDI R3, KBSR
+        HALT
+KBSR    .FILL xFE00
+        .END
+    ",
+    );
+    // A user mode HALT needs the third edition's TRAP to reach the OS in supervisor mode
+    machine.set_decode_policy(DecodePolicy {
+        edition: Edition::Third,
+        ..DecodePolicy::default()
+    });
+    machine.set_register(RegisterName::PC, 0x3000);
+    machine.set_register(RegisterName::PSR, 0x8002);
+    machine.set_register(RegisterName::R6, 0xfd00);
+
+    // Each handler returns past the offending instruction, back to user mode
+    assert_eq!(machine.step(), None);
+    assert_eq!(machine.pc(), 0x1002);
+    assert_eq!(machine.register(RegisterName::R6), INITIAL_SSP - 2);
+    assert_eq!(machine.memory().read(INITIAL_SSP - 2), 0x3001);
+    assert_eq!(machine.memory().read(INITIAL_SSP - 1), 0x8002);
+    assert_eq!(machine.run(1000), HaltReason::Halted);
+    assert_eq!(machine.register(RegisterName::R0), 1);
+    assert_eq!(machine.register(RegisterName::R1), 1);
+    assert_eq!(machine.register(RegisterName::R2), 1);
+    assert_eq!(machine.register(RegisterName::R3), 0);
+    // HALT stops the clock inside the OS, with the user stack put aside
+    assert_eq!(machine.register(RegisterName::SavedUSP), 0xfd00);
+    assert_eq!(machine.psr().privilege, Privilege::Supervisor);
+}
+
+#[test]
+fn test_machine_traps_as_its_edition_does() {
+    let source = "
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        ADD R1, R1, #1
+        HALT
+HELLO   .STRINGZ \"Hi\"
+        .END
+    ";
+    // The second edition links through R7 and leaves the stack and privilege alone
+    let mut second = assembled(source);
+    second.step();
+    second.step();
+    assert_eq!(second.register(RegisterName::R7), 0x3002);
+    assert_eq!(second.register(RegisterName::R6), INITIAL_SSP);
+    assert_eq!(second.memory().read(os::TRAP_RETURN), 0xc1c0);
+
+    // The third pushes the PSR and PC onto the supervisor stack
+    let mut third = assembled(source);
+    third.set_decode_policy(DecodePolicy {
+        edition: Edition::Third,
+        ..DecodePolicy::default()
+    });
+    third.step();
+    third.step();
+    assert_eq!(third.register(RegisterName::R7), 0);
+    assert_eq!(third.register(RegisterName::R6), INITIAL_SSP - 2);
+    assert_eq!(third.memory().read(INITIAL_SSP - 2), 0x3002);
+    assert_eq!(third.memory().read(os::TRAP_RETURN), 0x8000);
+
+    // Either way PUTS returns to the caller, and HALT stops the clock inside the OS
+    for (mut machine, sp) in [(second, INITIAL_SSP), (third, INITIAL_SSP - 2)] {
+        assert_eq!(machine.run(1000), HaltReason::Halted);
+        assert_eq!(machine.register(RegisterName::R1), 1);
+        assert_eq!(machine.register(RegisterName::R6), sp);
+        let output = machine.devices().get::<crate::devices::Display>().unwrap().output();
+        assert_eq!(output, b"Hi\n--- Halting the LC-3 ---\n");
+    }
+}
+
+#[test]
+fn test_machine_polls_console_devices() {
+    // The echo loop from chapter 9 of Patt & Patel, stopping after a newline
+    let mut machine = assembled(
+        "
+        .ORIG x3000
+START   LDI R1, A
+        BRzp START
+        LDI R0, B
+ECHO    LDI R1, C
+        BRzp ECHO
+        STI R0, D
+        ADD R0, R0, #-10
+        BRnp START
+        HALT
+A       .FILL xFE00
+B       .FILL xFE02
+C       .FILL xFE04
+D       .FILL xFE06
+        .END
+    ",
+    );
+    machine.devices().get::<crate::devices::Keyboard>().unwrap().type_bytes(b"lc3\n");
+    assert_eq!(machine.run(1000), HaltReason::Halted);
+    let output = machine.devices().get::<crate::devices::Display>().unwrap().output();
+    assert_eq!(output, b"lc3\n\n--- Halting the LC-3 ---\n");
+}
+
+#[test]
+fn test_machine_runs_host_traps() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut machine = assembled(
+        "
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        GETC
+        LD R1, NEG_Y
+        ADD R0, R0, R1
+        TRAP x26
+        TRAP x27
+        ADD R0, R0, #-12
+        TRAP x26
+        TRAP x27
+        HALT
+HELLO   .STRINGZ \"Hi \"
+NEG_Y   .FILL #-121
+        .END
+    ",
+    );
+    machine.register_builtin_traps();
+    let failures = Rc::new(RefCell::new(Vec::new()));
+    let recorded = Rc::clone(&failures);
+    // A grader's "print R0 as decimal" and "assert R0 is zero"
+    machine.register_trap(0x26, |machine| {
+        let text = format!("{} ", machine.register(RegisterName::R0) as i16);
+        host_display(machine, text.as_bytes());
+    });
+    machine.register_trap(0x27, move |machine| {
+        if machine.register(RegisterName::R0) != 0 {
+            recorded.borrow_mut().push(machine.pc().wrapping_sub(1));
+        }
+    });
+
+    // GETC waits without consuming steps beyond the TRAP itself
+    assert_eq!(machine.run(10), HaltReason::StepLimit);
+    assert_eq!(machine.pc(), 0x3002);
+    machine.devices().get::<crate::devices::Keyboard>().unwrap().type_bytes(b"y");
+    assert_eq!(machine.run(100), HaltReason::Halted);
+
+    let output = machine.devices().get::<crate::devices::Display>().unwrap().output();
+    assert_eq!(output, b"Hi 0 -12 \n--- Halting the LC-3 ---\n");
+    assert_eq!(*failures.borrow(), vec![0x3009]);
+    // Nothing was pushed, and the OS never ran
+    assert_eq!(machine.register(RegisterName::R6), INITIAL_SSP);
+    assert_eq!(machine.psr().privilege, Privilege::Supervisor);
+}
@@ -1,18 +1,377 @@
-mod lrc3;
+use ::lrc3::asm::{assemble_all_with, parse_register, AsmOptions, Assembly, Diagnostics};
+use ::lrc3::cfg::{Cfg, Dot};
+use ::lrc3::dap::DapServer;
+use ::lrc3::debugger::Debugger;
+use ::lrc3::devices::{Display, Keyboard};
+use ::lrc3::disasm::{Disassembly, Flow, Syntax};
+use ::lrc3::gdb::{GdbStub, Stdio};
+use ::lrc3::lsp::LanguageServer;
+use ::lrc3::lst::Listing;
+use ::lrc3::machine::{HaltReason, Machine};
+use ::lrc3::obj::ObjectFile;
+use ::lrc3::sym::SymbolTable;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use lrc3::lrc3;
 //#use lrc3::*;
 
 fn main() {
-    for bits in u16::MIN..=u16::MAX {
-        match lrc3::Instruction::decode_bits(bits) {
-            Ok(ins) => {
-                println!("{:016b}: {}", bits, ins);
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("asm") => asm(&args[2..]),
+        Some("run") => run(&args[2..]),
+        Some("cfg") => cfg(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("disasm") => disasm(&args[2..]),
+        Some("gdb") => gdb(&args[2..]),
+        Some("dap") => dap(&args[2..]),
+        Some("lsp") => lsp(),
+        _ => decode_table(),
+    }
+}
+
+/// # The decode policy --permissive and --edition 2|3 ask for, wherever they are in args, and the other args
+fn decode_policy(args: &[String]) -> (lrc3::DecodePolicy, Vec<String>) {
+    let mut policy = lrc3::DecodePolicy::default();
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--permissive" => policy.strictness = lrc3::Strictness::Permissive,
+            "--edition" => {
+                policy.edition = match args.next().map(String::as_str) {
+                    Some("2") => lrc3::Edition::Second,
+                    Some("3") => lrc3::Edition::Third,
+                    _ => {
+                        eprintln!("--edition takes 2 or 3");
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => rest.push(arg.clone()),
+        }
+    }
+    (policy, rest)
+}
+
+/// # lrc3 asm [--edition 2|3] [--relax=R5] prog.asm [...]: writes prog.obj, prog.sym and the prog.lst listing beside each source, and any further .ORIG blocks to prog.x4000.obj and so on
+///
+/// --relax=Rn rewrites BR, LD, ST, LEA and JSR whose label is out of reach into longer forms that
+/// reach it, with far branches jumping through Rn. There is no default register, since whichever
+/// one it is loses its value, and R7 would lose the return address of the subroutine around it.
+fn asm(args: &[String]) {
+    let (policy, args) = decode_policy(args);
+    let mut options = AsmOptions {
+        policy,
+        ..AsmOptions::default()
+    };
+    let mut paths = Vec::new();
+    for arg in args {
+        if !arg.starts_with("--relax") {
+            paths.push(arg);
+            continue;
+        }
+        match arg.strip_prefix("--relax=").and_then(parse_register) {
+            Some(register) => options.relax = Some(register),
+            None => {
+                eprintln!(
+                    "--relax needs the register far branches may overwrite, as in --relax=R5, found {}",
+                    arg
+                );
+                std::process::exit(1);
             }
-            _ => {}
+        }
+    }
+    for path in &paths {
+        let (source, assembly) = assemble_file(path, &options);
+        let base = Path::new(path).with_extension("");
+        for (index, object) in ObjectFile::from_assembly(&assembly).iter().enumerate() {
+            let object_path = match index {
+                0 => base.with_extension("obj"),
+                _ => PathBuf::from(format!("{}.x{:04X}.obj", base.display(), object.origin)),
+            };
+            if let Err(e) = object.write(object_path) {
+                exit_with(e);
+            }
+        }
+        if let Err(e) = SymbolTable::from_assembly(&assembly).write(base.with_extension("sym")) {
+            exit_with(e);
+        }
+        if let Err(e) = Listing::new(&source, &assembly).write(base.with_extension("lst")) {
+            exit_with(e);
+        }
+    }
+}
+
+/// # The source and assembly of a .asm file, or every error in it printed and an exit
+fn assemble_file(path: &str, options: &AsmOptions) -> (String, Assembly) {
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => exit_with(format!("{}: {}", path, e)),
+    };
+    match assemble_all_with(&source, options) {
+        Ok(assembly) => (source, assembly),
+        Err(errors) => {
+            eprint!("{}", Diagnostics::new(path, &source, &errors));
+            std::process::exit(1);
+        }
+    }
+}
+
+/// # lrc3 run [--permissive] [--edition 2|3] prog.obj [more.obj ...]: runs on the console until the program halts
+fn run(args: &[String]) {
+    let (policy, paths) = decode_policy(args);
+    let objects: Vec<ObjectFile> = match paths.iter().map(ObjectFile::read).collect() {
+        Ok(objects) => objects,
+        Err(e) => exit_with(e),
+    };
+    let mut machine = match Machine::from_objects(&objects) {
+        Ok(machine) => machine,
+        Err(e) => exit_with(e),
+    };
+    machine.set_decode_policy(policy);
+    let devices = machine.devices_mut();
+    devices.get::<Keyboard>().unwrap().attach_stdin();
+    devices.get_mut::<Display>().unwrap().attach_stdout();
+
+    let reason = machine.run(usize::MAX);
+    for (pc, violation) in machine.take_ignored_fields() {
+        eprintln!("warning: x{:04X}: {}", pc, violation);
+    }
+    match reason {
+        HaltReason::Halted | HaltReason::StepLimit => {}
+        HaltReason::IllegalInstruction { pc, bits } => {
+            eprintln!("illegal instruction x{:04X} at x{:04X}", bits, pc);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// # Objects from .obj files and assembled .asm sources, with the labels of the sources and of .sym files
+///
+/// A .sym file is read when named, or when it sits beside a .obj with the same name, as lc3as leaves them
+fn load_program(paths: &[String], policy: &lrc3::DecodePolicy) -> (Vec<ObjectFile>, Vec<(String, u16)>) {
+    let options = AsmOptions {
+        policy: *policy,
+        ..AsmOptions::default()
+    };
+    let mut objects = Vec::new();
+    let mut symbols = Vec::new();
+    for path in paths {
+        let loaded = if path.ends_with(".asm") {
+            let (_, assembly) = assemble_file(path, &options);
+            symbols.extend(assembly.symbols.iter().cloned());
+            ObjectFile::from_assembly(&assembly)
+        } else if path.ends_with(".sym") {
+            match SymbolTable::read(path) {
+                Ok(table) => symbols.extend(table.symbols),
+                Err(e) => exit_with(e),
+            }
+            Vec::new()
+        } else {
+            let beside = Path::new(path).with_extension("sym");
+            let named = paths.iter().any(|p| Path::new(p) == beside);
+            if beside.exists() && !named {
+                match SymbolTable::read(&beside) {
+                    Ok(table) => symbols.extend(table.symbols),
+                    Err(e) => exit_with(e),
+                }
+            }
+            match ObjectFile::read(path) {
+                Ok(object) => vec![object],
+                Err(e) => exit_with(e),
+            }
+        };
+        objects.extend(loaded);
+    }
+    (objects, symbols)
+}
+
+/// # lrc3 disasm [--permissive] [--edition 2|3] [--explain] [--entry x3000 ...] prog.obj [...]: prints each object as assembly that assembles back to it
+///
+/// Code is told from data by following control flow from the first object's origin, or from each
+/// --entry address given. --explain adds a comment to each instruction saying what it does.
+fn disasm(args: &[String]) {
+    let (policy, args) = decode_policy(args);
+    let mut syntax = Syntax::Canonical;
+    let mut entries = Vec::new();
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--explain" => syntax = Syntax::Explained,
+            "--entry" => entries.push(entry_address(args.next())),
+            _ => paths.push(arg),
+        }
+    }
+    let (objects, symbols) = load_program(&paths, &policy);
+    if entries.is_empty() {
+        entries.extend(objects.first().map(|object| object.origin));
+    }
+    let flow = Flow::trace(&objects, &entries, &policy);
+    for object in &objects {
+        let disassembly = Disassembly::new(object, &symbols, syntax)
+            .following(&flow)
+            .with_policy(policy);
+        print!("{}", disassembly);
+    }
+}
+
+/// # The hex address after an --entry flag, or an exit if there isn't one
+fn entry_address(address: Option<String>) -> u16 {
+    let address = address.and_then(|address| {
+        u16::from_str_radix(address.trim_start_matches(['x', 'X']), 16).ok()
+    });
+    match address {
+        Some(address) => address,
+        None => {
+            eprintln!("--entry takes a hex address, such as x3000");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// # lrc3 cfg [--permissive] [--edition 2|3] [--entry x3000 ...] prog.obj|prog.asm [...]: prints the control-flow graph in Graphviz's DOT language
+///
+/// The graph starts from the first object's origin, or from each --entry address given
+fn cfg(args: &[String]) {
+    let (policy, args) = decode_policy(args);
+    let mut entries = Vec::new();
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--entry" => entries.push(entry_address(args.next())),
+            _ => paths.push(arg),
+        }
+    }
+    let (objects, symbols) = load_program(&paths, &policy);
+    if entries.is_empty() {
+        entries.extend(objects.first().map(|object| object.origin));
+    }
+    let graph = Cfg::build(&objects, &entries, &policy);
+    print!("{}", Dot::new(&graph, &symbols));
+}
+
+/// # lrc3 debug [--permissive] [--edition 2|3] prog.obj|prog.asm [...]: debugger commands from stdin, with labels from .asm sources
+fn debug(args: &[String]) {
+    let (policy, paths) = decode_policy(args);
+    let (objects, symbols) = load_program(&paths, &policy);
+    let mut machine = match Machine::from_objects(&objects) {
+        Ok(machine) => machine,
+        Err(e) => exit_with(e),
+    };
+    machine.set_decode_policy(policy);
+    // stdin carries debugger commands, so the keyboard is fed with the input command instead
+    machine.devices_mut().get_mut::<Display>().unwrap().attach_stdout();
+    let mut debugger = Debugger::new(machine, symbols);
+
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut last = String::new();
+    loop {
+        print!("(lrc3) ");
+        std::io::stdout().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        // An empty line repeats the last command, as in gdb
+        if !line.trim().is_empty() {
+            last = line;
+        }
+        if matches!(last.trim(), "quit" | "q") {
+            break;
+        }
+        match debugger.execute(&last) {
+            Ok(output) => print!("{}", output),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+/// # lrc3 gdb [--permissive] [--edition 2|3] [--port N | --stdio] prog.obj|prog.asm [...]: serves gdb's remote protocol, on port 1234 by default
+fn gdb(args: &[String]) {
+    let (policy, args) = decode_policy(args);
+    let mut transport = Some(1234);
+    let mut paths = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--stdio" => transport = None,
+            "--port" => match args.next().and_then(|port| port.parse::<u16>().ok()) {
+                Some(port) => transport = Some(port),
+                None => {
+                    eprintln!("--port needs a port number");
+                    std::process::exit(1);
+                }
+            },
+            _ => paths.push(arg),
+        }
+    }
+    let (objects, _) = load_program(&paths, &policy);
+    let mut machine = match Machine::from_objects(&objects) {
+        Ok(machine) => machine,
+        Err(e) => exit_with(e),
+    };
+    machine.set_decode_policy(policy);
+
+    let served = match transport {
+        // stdout carries the protocol, so the stub sends the display's output to gdb instead
+        None => GdbStub::new(machine).serve(&mut Stdio),
+        Some(port) => {
+            let devices = machine.devices_mut();
+            devices.get::<Keyboard>().unwrap().attach_stdin();
+            devices.get_mut::<Display>().unwrap().attach_stdout();
+            eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+            std::net::TcpListener::bind(("127.0.0.1", port))
+                .and_then(|listener| listener.accept())
+                .and_then(|(mut stream, _)| GdbStub::new(machine).serve(&mut stream))
+        }
+    };
+    if let Err(e) = served {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// # lrc3 dap [--permissive] [--edition 2|3]: serves the Debug Adapter Protocol on stdin and stdout, for editors to launch programs through
+///
+/// The policy applies to every launch that doesn't give its own "permissive" or "edition" argument.
+fn dap(args: &[String]) {
+    let (policy, _) = decode_policy(args);
+    let stdin = std::io::BufReader::new(std::io::stdin());
+    let mut server = DapServer::new();
+    server.set_decode_policy(policy);
+    if let Err(e) = server.serve(stdin, &mut std::io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+/// # lrc3 lsp: serves the Language Server Protocol on stdin and stdout, for editors of LC-3 assembly
+fn lsp() {
+    let stdin = std::io::stdin();
+    if let Err(e) = LanguageServer::new().serve(&mut stdin.lock(), &mut std::io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn exit_with(e: impl std::fmt::Display) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(1);
+}
+
+fn decode_table() {
+    for bits in u16::MIN..=u16::MAX {
+        if let Ok(ins) = lrc3::Instruction::decode_bits(bits) {
+            println!("{:016b}: {}", bits, ins);
         }
     }
 
     println!("{}", lrc3::Imm5::new(0x1f));
-    println!("{:016b}", lrc3::sext16(0b111,3)); 
+    println!("{:016b}", lrc3::sext16(0b111,3));
 }
 // Templates:
 //impl Display for TwoSourceArithArgs {
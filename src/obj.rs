@@ -0,0 +1,169 @@
+use crate::asm::Assembly;
+use crate::lrc3::Memory;
+use core::fmt::{Display, Error, Formatter};
+use std::path::Path;
+
+/* The classic LC-3 object file format: a big-endian origin word,
+ * followed by the big-endian words to be placed starting at that origin.
+ */
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Empty,
+    OddLength(usize),
+    WrapsAddressSpace { origin: u16, len: usize },
+    Overlap { first: (u16, u16), second: (u16, u16) },
+    Io(std::io::Error),
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Empty => write!(f, "object file is missing its origin word"),
+            Self::OddLength(len) => write!(
+                f,
+                "object file is {} bytes long, but must hold whole 16 bit words",
+                len
+            ),
+            Self::WrapsAddressSpace { origin, len } => write!(
+                f,
+                "{} words starting at x{:04X} run past the end of memory",
+                len, origin
+            ),
+            Self::Overlap { first, second } => write!(
+                f,
+                "object at x{:04X}-x{:04X} overlaps object at x{:04X}-x{:04X}",
+                first.0, first.1, second.0, second.1
+            ),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectFile {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+impl ObjectFile {
+    pub fn new(origin: u16, words: Vec<u16>) -> Self {
+        Self { origin, words }
+    }
+
+    /// # One object per .ORIG block of the assembled program
+    pub fn from_assembly(assembly: &Assembly) -> Vec<Self> {
+        assembly
+            .segments
+            .iter()
+            .map(|(origin, words)| Self::new(*origin, words.clone()))
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ObjectError> {
+        if bytes.len() % 2 != 0 {
+            return Err(ObjectError::OddLength(bytes.len()));
+        }
+
+        let mut words = bytes
+            .chunks(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]));
+        let origin = match words.next() {
+            Some(origin) => origin,
+            None => return Err(ObjectError::Empty),
+        };
+
+        let object = Self::new(origin, words.collect());
+        object.range()?;
+        Ok(object)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        std::iter::once(self.origin)
+            .chain(self.words.iter().copied())
+            .flat_map(|word| word.to_be_bytes())
+            .collect()
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ObjectError> {
+        match std::fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes),
+            Err(e) => Err(ObjectError::Io(e)),
+        }
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), ObjectError> {
+        std::fs::write(path, self.to_bytes()).map_err(ObjectError::Io)
+    }
+
+    /// # First and last address covered by this object, or None if it is empty
+    pub fn range(&self) -> Result<Option<(u16, u16)>, ObjectError> {
+        if self.words.is_empty() {
+            return Ok(None);
+        }
+        let last = self.origin as usize + self.words.len() - 1;
+        if last > 0xffff {
+            return Err(ObjectError::WrapsAddressSpace {
+                origin: self.origin,
+                len: self.words.len(),
+            });
+        }
+        Ok(Some((self.origin, last as u16)))
+    }
+}
+
+/// # Places every object into memory, returning the origin of the first one as the starting PC
+pub fn load_objects(memory: &mut Memory, objects: &[ObjectFile]) -> Result<u16, ObjectError> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for object in objects {
+        if let Some(range) = object.range()? {
+            if let Some(other) = ranges
+                .iter()
+                .find(|other| range.0 <= other.1 && other.0 <= range.1)
+            {
+                return Err(ObjectError::Overlap {
+                    first: *other,
+                    second: range,
+                });
+            }
+            ranges.push(range);
+        }
+    }
+
+    for object in objects {
+        memory.load(object.origin, &object.words);
+    }
+
+    Ok(objects.first().map(|object| object.origin).unwrap_or(0x3000))
+}
+
+#[test]
+fn test_object_bytes_round_trip() {
+    let object = ObjectFile::new(0x3000, vec![0xe002, 0xf022, 0xf025]);
+    let bytes = object.to_bytes();
+    assert_eq!(bytes, vec![0x30, 0x00, 0xe0, 0x02, 0xf0, 0x22, 0xf0, 0x25]);
+    assert_eq!(ObjectFile::from_bytes(&bytes).unwrap(), object);
+
+    assert!(ObjectFile::from_bytes(&[0x30]).is_err());
+    assert!(ObjectFile::from_bytes(&[]).is_err());
+    assert!(ObjectFile::from_bytes(&[0xff, 0xff, 0, 1, 0, 2]).is_err());
+}
+
+#[test]
+fn test_load_objects_rejects_overlap() {
+    let mut memory = Memory::new();
+    let program = ObjectFile::new(0x3000, vec![1, 2, 3]);
+    let data = ObjectFile::new(0x4000, vec![4]);
+    assert_eq!(load_objects(&mut memory, &[program.clone(), data]).unwrap(), 0x3000);
+    assert_eq!(memory.read(0x3002), 3);
+    assert_eq!(memory.read(0x4000), 4);
+
+    let overlapping = ObjectFile::new(0x3002, vec![5]);
+    match load_objects(&mut memory, &[program, overlapping]) {
+        Err(ObjectError::Overlap { first, second }) => {
+            assert_eq!(first, (0x3000, 0x3002));
+            assert_eq!(second, (0x3002, 0x3002));
+        }
+        other => panic!("expected an overlap error, got {:?}", other),
+    }
+}
@@ -0,0 +1,699 @@
+; The operating system image loaded beneath every program
+;
+; Fills the trap vector table at x0000 and the interrupt vector table at x0100,
+; and provides the standard service routines from x0200. Exceptions and
+; interrupts push the PSR and PC, so their handlers return with RTI. TRAP only
+; does so in the 3rd edition; in the 2nd it links through R7 and leaves the
+; privilege alone, so trap routines return through TRAP_RETURN, which the
+; simulator sets to RTI or RET to suit the edition it runs. Routines save the
+; registers they use, including R7 around nested traps, in their own memory
+; rather than on the stack.
+
+        .ORIG x0000
+        .FILL BAD_TRAP      ; x00
+        .FILL BAD_TRAP      ; x01
+        .FILL BAD_TRAP      ; x02
+        .FILL BAD_TRAP      ; x03
+        .FILL BAD_TRAP      ; x04
+        .FILL BAD_TRAP      ; x05
+        .FILL BAD_TRAP      ; x06
+        .FILL BAD_TRAP      ; x07
+        .FILL BAD_TRAP      ; x08
+        .FILL BAD_TRAP      ; x09
+        .FILL BAD_TRAP      ; x0A
+        .FILL BAD_TRAP      ; x0B
+        .FILL BAD_TRAP      ; x0C
+        .FILL BAD_TRAP      ; x0D
+        .FILL BAD_TRAP      ; x0E
+        .FILL BAD_TRAP      ; x0F
+        .FILL BAD_TRAP      ; x10
+        .FILL BAD_TRAP      ; x11
+        .FILL BAD_TRAP      ; x12
+        .FILL BAD_TRAP      ; x13
+        .FILL BAD_TRAP      ; x14
+        .FILL BAD_TRAP      ; x15
+        .FILL BAD_TRAP      ; x16
+        .FILL BAD_TRAP      ; x17
+        .FILL BAD_TRAP      ; x18
+        .FILL BAD_TRAP      ; x19
+        .FILL BAD_TRAP      ; x1A
+        .FILL BAD_TRAP      ; x1B
+        .FILL BAD_TRAP      ; x1C
+        .FILL BAD_TRAP      ; x1D
+        .FILL BAD_TRAP      ; x1E
+        .FILL BAD_TRAP      ; x1F
+        .FILL GETC_ROUTINE  ; x20
+        .FILL OUT_ROUTINE   ; x21
+        .FILL PUTS_ROUTINE  ; x22
+        .FILL IN_ROUTINE    ; x23
+        .FILL PUTSP_ROUTINE ; x24
+        .FILL HALT_ROUTINE  ; x25
+        .FILL BAD_TRAP      ; x26
+        .FILL BAD_TRAP      ; x27
+        .FILL BAD_TRAP      ; x28
+        .FILL BAD_TRAP      ; x29
+        .FILL BAD_TRAP      ; x2A
+        .FILL BAD_TRAP      ; x2B
+        .FILL BAD_TRAP      ; x2C
+        .FILL BAD_TRAP      ; x2D
+        .FILL BAD_TRAP      ; x2E
+        .FILL BAD_TRAP      ; x2F
+        .FILL BAD_TRAP      ; x30
+        .FILL BAD_TRAP      ; x31
+        .FILL BAD_TRAP      ; x32
+        .FILL BAD_TRAP      ; x33
+        .FILL BAD_TRAP      ; x34
+        .FILL BAD_TRAP      ; x35
+        .FILL BAD_TRAP      ; x36
+        .FILL BAD_TRAP      ; x37
+        .FILL BAD_TRAP      ; x38
+        .FILL BAD_TRAP      ; x39
+        .FILL BAD_TRAP      ; x3A
+        .FILL BAD_TRAP      ; x3B
+        .FILL BAD_TRAP      ; x3C
+        .FILL BAD_TRAP      ; x3D
+        .FILL BAD_TRAP      ; x3E
+        .FILL BAD_TRAP      ; x3F
+        .FILL BAD_TRAP      ; x40
+        .FILL BAD_TRAP      ; x41
+        .FILL BAD_TRAP      ; x42
+        .FILL BAD_TRAP      ; x43
+        .FILL BAD_TRAP      ; x44
+        .FILL BAD_TRAP      ; x45
+        .FILL BAD_TRAP      ; x46
+        .FILL BAD_TRAP      ; x47
+        .FILL BAD_TRAP      ; x48
+        .FILL BAD_TRAP      ; x49
+        .FILL BAD_TRAP      ; x4A
+        .FILL BAD_TRAP      ; x4B
+        .FILL BAD_TRAP      ; x4C
+        .FILL BAD_TRAP      ; x4D
+        .FILL BAD_TRAP      ; x4E
+        .FILL BAD_TRAP      ; x4F
+        .FILL BAD_TRAP      ; x50
+        .FILL BAD_TRAP      ; x51
+        .FILL BAD_TRAP      ; x52
+        .FILL BAD_TRAP      ; x53
+        .FILL BAD_TRAP      ; x54
+        .FILL BAD_TRAP      ; x55
+        .FILL BAD_TRAP      ; x56
+        .FILL BAD_TRAP      ; x57
+        .FILL BAD_TRAP      ; x58
+        .FILL BAD_TRAP      ; x59
+        .FILL BAD_TRAP      ; x5A
+        .FILL BAD_TRAP      ; x5B
+        .FILL BAD_TRAP      ; x5C
+        .FILL BAD_TRAP      ; x5D
+        .FILL BAD_TRAP      ; x5E
+        .FILL BAD_TRAP      ; x5F
+        .FILL BAD_TRAP      ; x60
+        .FILL BAD_TRAP      ; x61
+        .FILL BAD_TRAP      ; x62
+        .FILL BAD_TRAP      ; x63
+        .FILL BAD_TRAP      ; x64
+        .FILL BAD_TRAP      ; x65
+        .FILL BAD_TRAP      ; x66
+        .FILL BAD_TRAP      ; x67
+        .FILL BAD_TRAP      ; x68
+        .FILL BAD_TRAP      ; x69
+        .FILL BAD_TRAP      ; x6A
+        .FILL BAD_TRAP      ; x6B
+        .FILL BAD_TRAP      ; x6C
+        .FILL BAD_TRAP      ; x6D
+        .FILL BAD_TRAP      ; x6E
+        .FILL BAD_TRAP      ; x6F
+        .FILL BAD_TRAP      ; x70
+        .FILL BAD_TRAP      ; x71
+        .FILL BAD_TRAP      ; x72
+        .FILL BAD_TRAP      ; x73
+        .FILL BAD_TRAP      ; x74
+        .FILL BAD_TRAP      ; x75
+        .FILL BAD_TRAP      ; x76
+        .FILL BAD_TRAP      ; x77
+        .FILL BAD_TRAP      ; x78
+        .FILL BAD_TRAP      ; x79
+        .FILL BAD_TRAP      ; x7A
+        .FILL BAD_TRAP      ; x7B
+        .FILL BAD_TRAP      ; x7C
+        .FILL BAD_TRAP      ; x7D
+        .FILL BAD_TRAP      ; x7E
+        .FILL BAD_TRAP      ; x7F
+        .FILL BAD_TRAP      ; x80
+        .FILL BAD_TRAP      ; x81
+        .FILL BAD_TRAP      ; x82
+        .FILL BAD_TRAP      ; x83
+        .FILL BAD_TRAP      ; x84
+        .FILL BAD_TRAP      ; x85
+        .FILL BAD_TRAP      ; x86
+        .FILL BAD_TRAP      ; x87
+        .FILL BAD_TRAP      ; x88
+        .FILL BAD_TRAP      ; x89
+        .FILL BAD_TRAP      ; x8A
+        .FILL BAD_TRAP      ; x8B
+        .FILL BAD_TRAP      ; x8C
+        .FILL BAD_TRAP      ; x8D
+        .FILL BAD_TRAP      ; x8E
+        .FILL BAD_TRAP      ; x8F
+        .FILL BAD_TRAP      ; x90
+        .FILL BAD_TRAP      ; x91
+        .FILL BAD_TRAP      ; x92
+        .FILL BAD_TRAP      ; x93
+        .FILL BAD_TRAP      ; x94
+        .FILL BAD_TRAP      ; x95
+        .FILL BAD_TRAP      ; x96
+        .FILL BAD_TRAP      ; x97
+        .FILL BAD_TRAP      ; x98
+        .FILL BAD_TRAP      ; x99
+        .FILL BAD_TRAP      ; x9A
+        .FILL BAD_TRAP      ; x9B
+        .FILL BAD_TRAP      ; x9C
+        .FILL BAD_TRAP      ; x9D
+        .FILL BAD_TRAP      ; x9E
+        .FILL BAD_TRAP      ; x9F
+        .FILL BAD_TRAP      ; xA0
+        .FILL BAD_TRAP      ; xA1
+        .FILL BAD_TRAP      ; xA2
+        .FILL BAD_TRAP      ; xA3
+        .FILL BAD_TRAP      ; xA4
+        .FILL BAD_TRAP      ; xA5
+        .FILL BAD_TRAP      ; xA6
+        .FILL BAD_TRAP      ; xA7
+        .FILL BAD_TRAP      ; xA8
+        .FILL BAD_TRAP      ; xA9
+        .FILL BAD_TRAP      ; xAA
+        .FILL BAD_TRAP      ; xAB
+        .FILL BAD_TRAP      ; xAC
+        .FILL BAD_TRAP      ; xAD
+        .FILL BAD_TRAP      ; xAE
+        .FILL BAD_TRAP      ; xAF
+        .FILL BAD_TRAP      ; xB0
+        .FILL BAD_TRAP      ; xB1
+        .FILL BAD_TRAP      ; xB2
+        .FILL BAD_TRAP      ; xB3
+        .FILL BAD_TRAP      ; xB4
+        .FILL BAD_TRAP      ; xB5
+        .FILL BAD_TRAP      ; xB6
+        .FILL BAD_TRAP      ; xB7
+        .FILL BAD_TRAP      ; xB8
+        .FILL BAD_TRAP      ; xB9
+        .FILL BAD_TRAP      ; xBA
+        .FILL BAD_TRAP      ; xBB
+        .FILL BAD_TRAP      ; xBC
+        .FILL BAD_TRAP      ; xBD
+        .FILL BAD_TRAP      ; xBE
+        .FILL BAD_TRAP      ; xBF
+        .FILL BAD_TRAP      ; xC0
+        .FILL BAD_TRAP      ; xC1
+        .FILL BAD_TRAP      ; xC2
+        .FILL BAD_TRAP      ; xC3
+        .FILL BAD_TRAP      ; xC4
+        .FILL BAD_TRAP      ; xC5
+        .FILL BAD_TRAP      ; xC6
+        .FILL BAD_TRAP      ; xC7
+        .FILL BAD_TRAP      ; xC8
+        .FILL BAD_TRAP      ; xC9
+        .FILL BAD_TRAP      ; xCA
+        .FILL BAD_TRAP      ; xCB
+        .FILL BAD_TRAP      ; xCC
+        .FILL BAD_TRAP      ; xCD
+        .FILL BAD_TRAP      ; xCE
+        .FILL BAD_TRAP      ; xCF
+        .FILL BAD_TRAP      ; xD0
+        .FILL BAD_TRAP      ; xD1
+        .FILL BAD_TRAP      ; xD2
+        .FILL BAD_TRAP      ; xD3
+        .FILL BAD_TRAP      ; xD4
+        .FILL BAD_TRAP      ; xD5
+        .FILL BAD_TRAP      ; xD6
+        .FILL BAD_TRAP      ; xD7
+        .FILL BAD_TRAP      ; xD8
+        .FILL BAD_TRAP      ; xD9
+        .FILL BAD_TRAP      ; xDA
+        .FILL BAD_TRAP      ; xDB
+        .FILL BAD_TRAP      ; xDC
+        .FILL BAD_TRAP      ; xDD
+        .FILL BAD_TRAP      ; xDE
+        .FILL BAD_TRAP      ; xDF
+        .FILL BAD_TRAP      ; xE0
+        .FILL BAD_TRAP      ; xE1
+        .FILL BAD_TRAP      ; xE2
+        .FILL BAD_TRAP      ; xE3
+        .FILL BAD_TRAP      ; xE4
+        .FILL BAD_TRAP      ; xE5
+        .FILL BAD_TRAP      ; xE6
+        .FILL BAD_TRAP      ; xE7
+        .FILL BAD_TRAP      ; xE8
+        .FILL BAD_TRAP      ; xE9
+        .FILL BAD_TRAP      ; xEA
+        .FILL BAD_TRAP      ; xEB
+        .FILL BAD_TRAP      ; xEC
+        .FILL BAD_TRAP      ; xED
+        .FILL BAD_TRAP      ; xEE
+        .FILL BAD_TRAP      ; xEF
+        .FILL BAD_TRAP      ; xF0
+        .FILL BAD_TRAP      ; xF1
+        .FILL BAD_TRAP      ; xF2
+        .FILL BAD_TRAP      ; xF3
+        .FILL BAD_TRAP      ; xF4
+        .FILL BAD_TRAP      ; xF5
+        .FILL BAD_TRAP      ; xF6
+        .FILL BAD_TRAP      ; xF7
+        .FILL BAD_TRAP      ; xF8
+        .FILL BAD_TRAP      ; xF9
+        .FILL BAD_TRAP      ; xFA
+        .FILL BAD_TRAP      ; xFB
+        .FILL BAD_TRAP      ; xFC
+        .FILL BAD_TRAP      ; xFD
+        .FILL BAD_TRAP      ; xFE
+        .FILL BAD_TRAP      ; xFF
+        .END
+
+        .ORIG x0100
+        .FILL PRIV_HANDLER  ; x00
+        .FILL ILLEGAL_HANDLER; x01
+        .FILL ACV_HANDLER   ; x02
+        .FILL BAD_INTERRUPT ; x03
+        .FILL BAD_INTERRUPT ; x04
+        .FILL BAD_INTERRUPT ; x05
+        .FILL BAD_INTERRUPT ; x06
+        .FILL BAD_INTERRUPT ; x07
+        .FILL BAD_INTERRUPT ; x08
+        .FILL BAD_INTERRUPT ; x09
+        .FILL BAD_INTERRUPT ; x0A
+        .FILL BAD_INTERRUPT ; x0B
+        .FILL BAD_INTERRUPT ; x0C
+        .FILL BAD_INTERRUPT ; x0D
+        .FILL BAD_INTERRUPT ; x0E
+        .FILL BAD_INTERRUPT ; x0F
+        .FILL BAD_INTERRUPT ; x10
+        .FILL BAD_INTERRUPT ; x11
+        .FILL BAD_INTERRUPT ; x12
+        .FILL BAD_INTERRUPT ; x13
+        .FILL BAD_INTERRUPT ; x14
+        .FILL BAD_INTERRUPT ; x15
+        .FILL BAD_INTERRUPT ; x16
+        .FILL BAD_INTERRUPT ; x17
+        .FILL BAD_INTERRUPT ; x18
+        .FILL BAD_INTERRUPT ; x19
+        .FILL BAD_INTERRUPT ; x1A
+        .FILL BAD_INTERRUPT ; x1B
+        .FILL BAD_INTERRUPT ; x1C
+        .FILL BAD_INTERRUPT ; x1D
+        .FILL BAD_INTERRUPT ; x1E
+        .FILL BAD_INTERRUPT ; x1F
+        .FILL BAD_INTERRUPT ; x20
+        .FILL BAD_INTERRUPT ; x21
+        .FILL BAD_INTERRUPT ; x22
+        .FILL BAD_INTERRUPT ; x23
+        .FILL BAD_INTERRUPT ; x24
+        .FILL BAD_INTERRUPT ; x25
+        .FILL BAD_INTERRUPT ; x26
+        .FILL BAD_INTERRUPT ; x27
+        .FILL BAD_INTERRUPT ; x28
+        .FILL BAD_INTERRUPT ; x29
+        .FILL BAD_INTERRUPT ; x2A
+        .FILL BAD_INTERRUPT ; x2B
+        .FILL BAD_INTERRUPT ; x2C
+        .FILL BAD_INTERRUPT ; x2D
+        .FILL BAD_INTERRUPT ; x2E
+        .FILL BAD_INTERRUPT ; x2F
+        .FILL BAD_INTERRUPT ; x30
+        .FILL BAD_INTERRUPT ; x31
+        .FILL BAD_INTERRUPT ; x32
+        .FILL BAD_INTERRUPT ; x33
+        .FILL BAD_INTERRUPT ; x34
+        .FILL BAD_INTERRUPT ; x35
+        .FILL BAD_INTERRUPT ; x36
+        .FILL BAD_INTERRUPT ; x37
+        .FILL BAD_INTERRUPT ; x38
+        .FILL BAD_INTERRUPT ; x39
+        .FILL BAD_INTERRUPT ; x3A
+        .FILL BAD_INTERRUPT ; x3B
+        .FILL BAD_INTERRUPT ; x3C
+        .FILL BAD_INTERRUPT ; x3D
+        .FILL BAD_INTERRUPT ; x3E
+        .FILL BAD_INTERRUPT ; x3F
+        .FILL BAD_INTERRUPT ; x40
+        .FILL BAD_INTERRUPT ; x41
+        .FILL BAD_INTERRUPT ; x42
+        .FILL BAD_INTERRUPT ; x43
+        .FILL BAD_INTERRUPT ; x44
+        .FILL BAD_INTERRUPT ; x45
+        .FILL BAD_INTERRUPT ; x46
+        .FILL BAD_INTERRUPT ; x47
+        .FILL BAD_INTERRUPT ; x48
+        .FILL BAD_INTERRUPT ; x49
+        .FILL BAD_INTERRUPT ; x4A
+        .FILL BAD_INTERRUPT ; x4B
+        .FILL BAD_INTERRUPT ; x4C
+        .FILL BAD_INTERRUPT ; x4D
+        .FILL BAD_INTERRUPT ; x4E
+        .FILL BAD_INTERRUPT ; x4F
+        .FILL BAD_INTERRUPT ; x50
+        .FILL BAD_INTERRUPT ; x51
+        .FILL BAD_INTERRUPT ; x52
+        .FILL BAD_INTERRUPT ; x53
+        .FILL BAD_INTERRUPT ; x54
+        .FILL BAD_INTERRUPT ; x55
+        .FILL BAD_INTERRUPT ; x56
+        .FILL BAD_INTERRUPT ; x57
+        .FILL BAD_INTERRUPT ; x58
+        .FILL BAD_INTERRUPT ; x59
+        .FILL BAD_INTERRUPT ; x5A
+        .FILL BAD_INTERRUPT ; x5B
+        .FILL BAD_INTERRUPT ; x5C
+        .FILL BAD_INTERRUPT ; x5D
+        .FILL BAD_INTERRUPT ; x5E
+        .FILL BAD_INTERRUPT ; x5F
+        .FILL BAD_INTERRUPT ; x60
+        .FILL BAD_INTERRUPT ; x61
+        .FILL BAD_INTERRUPT ; x62
+        .FILL BAD_INTERRUPT ; x63
+        .FILL BAD_INTERRUPT ; x64
+        .FILL BAD_INTERRUPT ; x65
+        .FILL BAD_INTERRUPT ; x66
+        .FILL BAD_INTERRUPT ; x67
+        .FILL BAD_INTERRUPT ; x68
+        .FILL BAD_INTERRUPT ; x69
+        .FILL BAD_INTERRUPT ; x6A
+        .FILL BAD_INTERRUPT ; x6B
+        .FILL BAD_INTERRUPT ; x6C
+        .FILL BAD_INTERRUPT ; x6D
+        .FILL BAD_INTERRUPT ; x6E
+        .FILL BAD_INTERRUPT ; x6F
+        .FILL BAD_INTERRUPT ; x70
+        .FILL BAD_INTERRUPT ; x71
+        .FILL BAD_INTERRUPT ; x72
+        .FILL BAD_INTERRUPT ; x73
+        .FILL BAD_INTERRUPT ; x74
+        .FILL BAD_INTERRUPT ; x75
+        .FILL BAD_INTERRUPT ; x76
+        .FILL BAD_INTERRUPT ; x77
+        .FILL BAD_INTERRUPT ; x78
+        .FILL BAD_INTERRUPT ; x79
+        .FILL BAD_INTERRUPT ; x7A
+        .FILL BAD_INTERRUPT ; x7B
+        .FILL BAD_INTERRUPT ; x7C
+        .FILL BAD_INTERRUPT ; x7D
+        .FILL BAD_INTERRUPT ; x7E
+        .FILL BAD_INTERRUPT ; x7F
+        .FILL BAD_INTERRUPT ; x80
+        .FILL BAD_INTERRUPT ; x81
+        .FILL BAD_INTERRUPT ; x82
+        .FILL BAD_INTERRUPT ; x83
+        .FILL BAD_INTERRUPT ; x84
+        .FILL BAD_INTERRUPT ; x85
+        .FILL BAD_INTERRUPT ; x86
+        .FILL BAD_INTERRUPT ; x87
+        .FILL BAD_INTERRUPT ; x88
+        .FILL BAD_INTERRUPT ; x89
+        .FILL BAD_INTERRUPT ; x8A
+        .FILL BAD_INTERRUPT ; x8B
+        .FILL BAD_INTERRUPT ; x8C
+        .FILL BAD_INTERRUPT ; x8D
+        .FILL BAD_INTERRUPT ; x8E
+        .FILL BAD_INTERRUPT ; x8F
+        .FILL BAD_INTERRUPT ; x90
+        .FILL BAD_INTERRUPT ; x91
+        .FILL BAD_INTERRUPT ; x92
+        .FILL BAD_INTERRUPT ; x93
+        .FILL BAD_INTERRUPT ; x94
+        .FILL BAD_INTERRUPT ; x95
+        .FILL BAD_INTERRUPT ; x96
+        .FILL BAD_INTERRUPT ; x97
+        .FILL BAD_INTERRUPT ; x98
+        .FILL BAD_INTERRUPT ; x99
+        .FILL BAD_INTERRUPT ; x9A
+        .FILL BAD_INTERRUPT ; x9B
+        .FILL BAD_INTERRUPT ; x9C
+        .FILL BAD_INTERRUPT ; x9D
+        .FILL BAD_INTERRUPT ; x9E
+        .FILL BAD_INTERRUPT ; x9F
+        .FILL BAD_INTERRUPT ; xA0
+        .FILL BAD_INTERRUPT ; xA1
+        .FILL BAD_INTERRUPT ; xA2
+        .FILL BAD_INTERRUPT ; xA3
+        .FILL BAD_INTERRUPT ; xA4
+        .FILL BAD_INTERRUPT ; xA5
+        .FILL BAD_INTERRUPT ; xA6
+        .FILL BAD_INTERRUPT ; xA7
+        .FILL BAD_INTERRUPT ; xA8
+        .FILL BAD_INTERRUPT ; xA9
+        .FILL BAD_INTERRUPT ; xAA
+        .FILL BAD_INTERRUPT ; xAB
+        .FILL BAD_INTERRUPT ; xAC
+        .FILL BAD_INTERRUPT ; xAD
+        .FILL BAD_INTERRUPT ; xAE
+        .FILL BAD_INTERRUPT ; xAF
+        .FILL BAD_INTERRUPT ; xB0
+        .FILL BAD_INTERRUPT ; xB1
+        .FILL BAD_INTERRUPT ; xB2
+        .FILL BAD_INTERRUPT ; xB3
+        .FILL BAD_INTERRUPT ; xB4
+        .FILL BAD_INTERRUPT ; xB5
+        .FILL BAD_INTERRUPT ; xB6
+        .FILL BAD_INTERRUPT ; xB7
+        .FILL BAD_INTERRUPT ; xB8
+        .FILL BAD_INTERRUPT ; xB9
+        .FILL BAD_INTERRUPT ; xBA
+        .FILL BAD_INTERRUPT ; xBB
+        .FILL BAD_INTERRUPT ; xBC
+        .FILL BAD_INTERRUPT ; xBD
+        .FILL BAD_INTERRUPT ; xBE
+        .FILL BAD_INTERRUPT ; xBF
+        .FILL BAD_INTERRUPT ; xC0
+        .FILL BAD_INTERRUPT ; xC1
+        .FILL BAD_INTERRUPT ; xC2
+        .FILL BAD_INTERRUPT ; xC3
+        .FILL BAD_INTERRUPT ; xC4
+        .FILL BAD_INTERRUPT ; xC5
+        .FILL BAD_INTERRUPT ; xC6
+        .FILL BAD_INTERRUPT ; xC7
+        .FILL BAD_INTERRUPT ; xC8
+        .FILL BAD_INTERRUPT ; xC9
+        .FILL BAD_INTERRUPT ; xCA
+        .FILL BAD_INTERRUPT ; xCB
+        .FILL BAD_INTERRUPT ; xCC
+        .FILL BAD_INTERRUPT ; xCD
+        .FILL BAD_INTERRUPT ; xCE
+        .FILL BAD_INTERRUPT ; xCF
+        .FILL BAD_INTERRUPT ; xD0
+        .FILL BAD_INTERRUPT ; xD1
+        .FILL BAD_INTERRUPT ; xD2
+        .FILL BAD_INTERRUPT ; xD3
+        .FILL BAD_INTERRUPT ; xD4
+        .FILL BAD_INTERRUPT ; xD5
+        .FILL BAD_INTERRUPT ; xD6
+        .FILL BAD_INTERRUPT ; xD7
+        .FILL BAD_INTERRUPT ; xD8
+        .FILL BAD_INTERRUPT ; xD9
+        .FILL BAD_INTERRUPT ; xDA
+        .FILL BAD_INTERRUPT ; xDB
+        .FILL BAD_INTERRUPT ; xDC
+        .FILL BAD_INTERRUPT ; xDD
+        .FILL BAD_INTERRUPT ; xDE
+        .FILL BAD_INTERRUPT ; xDF
+        .FILL BAD_INTERRUPT ; xE0
+        .FILL BAD_INTERRUPT ; xE1
+        .FILL BAD_INTERRUPT ; xE2
+        .FILL BAD_INTERRUPT ; xE3
+        .FILL BAD_INTERRUPT ; xE4
+        .FILL BAD_INTERRUPT ; xE5
+        .FILL BAD_INTERRUPT ; xE6
+        .FILL BAD_INTERRUPT ; xE7
+        .FILL BAD_INTERRUPT ; xE8
+        .FILL BAD_INTERRUPT ; xE9
+        .FILL BAD_INTERRUPT ; xEA
+        .FILL BAD_INTERRUPT ; xEB
+        .FILL BAD_INTERRUPT ; xEC
+        .FILL BAD_INTERRUPT ; xED
+        .FILL BAD_INTERRUPT ; xEE
+        .FILL BAD_INTERRUPT ; xEF
+        .FILL BAD_INTERRUPT ; xF0
+        .FILL BAD_INTERRUPT ; xF1
+        .FILL BAD_INTERRUPT ; xF2
+        .FILL BAD_INTERRUPT ; xF3
+        .FILL BAD_INTERRUPT ; xF4
+        .FILL BAD_INTERRUPT ; xF5
+        .FILL BAD_INTERRUPT ; xF6
+        .FILL BAD_INTERRUPT ; xF7
+        .FILL BAD_INTERRUPT ; xF8
+        .FILL BAD_INTERRUPT ; xF9
+        .FILL BAD_INTERRUPT ; xFA
+        .FILL BAD_INTERRUPT ; xFB
+        .FILL BAD_INTERRUPT ; xFC
+        .FILL BAD_INTERRUPT ; xFD
+        .FILL BAD_INTERRUPT ; xFE
+        .FILL BAD_INTERRUPT ; xFF
+        .END
+
+        .ORIG x0200
+; Kept at x0200, where the simulator rewrites it to RET for the 2nd edition
+TRAP_RETURN
+        RTI
+
+; GETC: R0 <- the next character typed, without echoing it
+GETC_ROUTINE
+        LDI R0, KBSR_PTR
+        BRzp GETC_ROUTINE
+        LDI R0, KBDR_PTR
+        BR TRAP_RETURN
+
+; OUT: displays the character in R0
+OUT_ROUTINE
+        ST R1, OUT_R1
+OUT_POLL
+        LDI R1, DSR_PTR
+        BRzp OUT_POLL
+        STI R0, DDR_PTR
+        LD R1, OUT_R1
+        BR TRAP_RETURN
+
+; PUTS: displays the string of one character per word starting at R0
+PUTS_ROUTINE
+        ST R0, PUTS_R0
+        ST R1, PUTS_R1
+        ST R7, PUTS_R7
+        ADD R1, R0, #0
+PUTS_NEXT
+        LDR R0, R1, #0
+        BRz PUTS_DONE
+        OUT
+        ADD R1, R1, #1
+        BR PUTS_NEXT
+PUTS_DONE
+        LD R0, PUTS_R0
+        LD R1, PUTS_R1
+        LD R7, PUTS_R7
+        BR TRAP_RETURN
+
+; IN: prompts for a character, echoes it, and leaves it in R0
+IN_ROUTINE
+        ST R7, IN_R7
+        LEA R0, IN_PROMPT
+        PUTS
+        GETC
+        OUT
+        ST R0, IN_CHAR
+        LD R0, NEWLINE
+        OUT
+        LD R0, IN_CHAR
+        LD R7, IN_R7
+        BR TRAP_RETURN
+
+; PUTSP: displays the string of two characters per word starting at R0,
+; low byte first
+PUTSP_ROUTINE
+        ST R0, PUTSP_R0
+        ST R1, PUTSP_R1
+        ST R2, PUTSP_R2
+        ST R3, PUTSP_R3
+        ST R7, PUTSP_R7
+        ADD R1, R0, #0
+PUTSP_NEXT
+        LDR R2, R1, #0
+        LD R3, LOW_BYTE
+        AND R0, R2, R3
+        BRz PUTSP_DONE
+        OUT
+        ; Shift the high byte down by moving R2's top 8 bits into R0 one at a time
+        AND R0, R0, #0
+        AND R3, R3, #0
+        ADD R3, R3, #8
+PUTSP_BIT
+        ADD R0, R0, R0
+        ADD R2, R2, #0
+        BRzp PUTSP_SHIFT
+        ADD R0, R0, #1
+PUTSP_SHIFT
+        ADD R2, R2, R2
+        ADD R3, R3, #-1
+        BRp PUTSP_BIT
+        ADD R0, R0, #0
+        BRz PUTSP_DONE
+        OUT
+        ADD R1, R1, #1
+        BR PUTSP_NEXT
+PUTSP_DONE
+        LD R0, PUTSP_R0
+        LD R1, PUTSP_R1
+        LD R2, PUTSP_R2
+        LD R3, PUTSP_R3
+        LD R7, PUTSP_R7
+        BR TRAP_RETURN
+
+; HALT: stops the clock by clearing MCR, resuming the caller if it is restarted.
+; R7 is the one register left changed while stopped, as the 2nd edition's TRAP
+; leaves it anyway
+HALT_ROUTINE
+        ST R0, HALT_R0
+        ST R7, HALT_R7
+        LEA R0, HALT_MESSAGE
+        PUTS
+        LD R0, HALT_R0
+        AND R7, R7, #0
+        STI R7, MCR_PTR
+        LD R7, HALT_R7
+        BR TRAP_RETURN
+
+BAD_TRAP
+        ST R7, BAD_TRAP_R7
+        LEA R0, BAD_TRAP_MESSAGE
+        PUTS
+        HALT
+        LD R7, BAD_TRAP_R7
+        BR TRAP_RETURN
+
+; Exceptions report themselves and halt, since the offending program cannot continue
+PRIV_HANDLER
+        LEA R0, PRIV_MESSAGE
+        PUTS
+        HALT
+        RTI
+
+ILLEGAL_HANDLER
+        LEA R0, ILLEGAL_MESSAGE
+        PUTS
+        HALT
+        RTI
+
+ACV_HANDLER
+        LEA R0, ACV_MESSAGE
+        PUTS
+        HALT
+        RTI
+
+; Interrupts nobody has installed a handler for are dismissed
+BAD_INTERRUPT
+        RTI
+
+KBSR_PTR        .FILL xFE00
+KBDR_PTR        .FILL xFE02
+DSR_PTR         .FILL xFE04
+DDR_PTR         .FILL xFE06
+MCR_PTR         .FILL xFFFE
+LOW_BYTE        .FILL x00FF
+NEWLINE         .FILL x000A
+
+OUT_R1          .BLKW 1
+PUTS_R0         .BLKW 1
+PUTS_R1         .BLKW 1
+PUTS_R7         .BLKW 1
+IN_CHAR         .BLKW 1
+IN_R7           .BLKW 1
+PUTSP_R0        .BLKW 1
+PUTSP_R1        .BLKW 1
+PUTSP_R2        .BLKW 1
+PUTSP_R3        .BLKW 1
+PUTSP_R7        .BLKW 1
+HALT_R0         .BLKW 1
+HALT_R7         .BLKW 1
+BAD_TRAP_R7     .BLKW 1
+
+IN_PROMPT       .STRINGZ "\nInput a character> "
+HALT_MESSAGE    .STRINGZ "\n--- Halting the LC-3 ---\n"
+BAD_TRAP_MESSAGE .STRINGZ "\n--- Undefined trap executed ---\n"
+PRIV_MESSAGE    .STRINGZ "\n--- Privilege mode violation ---\n"
+ILLEGAL_MESSAGE .STRINGZ "\n--- Illegal opcode ---\n"
+ACV_MESSAGE     .STRINGZ "\n--- Access control violation ---\n"
+        .END
@@ -0,0 +1,115 @@
+use crate::asm::assemble;
+use crate::lrc3::{BaseRArgs, Edition, Instruction, Memory, RegisterName};
+use crate::obj::{load_objects, ObjectError, ObjectFile};
+use std::sync::Mutex;
+
+/* The built-in operating system
+ *
+ * os.asm is assembled the first time it is needed, so the image is always
+ * built by the same assembler students use and can be read as ordinary LC-3
+ * code. Every later boot copies the same image.
+ */
+
+pub const SOURCE: &str = include_str!("os.asm");
+
+/// # The word every trap routine returns through, RTI as assembled
+pub const TRAP_RETURN: u16 = 0x0200;
+
+/// # How a trap routine returns to its caller under edition's TRAP
+pub fn trap_return(edition: Edition) -> u16 {
+    match edition {
+        Edition::Second => Instruction::Jmp(BaseRArgs { base_r: RegisterName::R7 }).encode_bits(),
+        Edition::Third => Instruction::Rti().encode_bits(),
+    }
+}
+
+/// # Points the trap routines of an OS booted into memory at the return edition's TRAP needs,
+/// unless a program has loaded something of its own over TRAP_RETURN
+pub fn set_trap_return(memory: &mut Memory, edition: Edition) {
+    let current = memory.read(TRAP_RETURN);
+    if [trap_return(Edition::Second), trap_return(Edition::Third)].contains(&current) {
+        memory.write(TRAP_RETURN, trap_return(edition));
+    }
+}
+
+// OnceLock would do, but is newer than the oldest Rust this crate builds with
+static IMAGE: Mutex<Option<Vec<ObjectFile>>> = Mutex::new(None);
+
+/// # The trap table, interrupt table and service routines, one object per .ORIG block
+pub fn image() -> Vec<ObjectFile> {
+    let mut image = IMAGE.lock().unwrap();
+    image
+        .get_or_insert_with(|| {
+            let assembly = assemble(SOURCE).expect("the built-in OS assembles");
+            ObjectFile::from_assembly(&assembly)
+        })
+        .clone()
+}
+
+/// # Memory holding the OS with objects loaded over it, and the first object's origin
+pub fn boot(objects: &[ObjectFile]) -> Result<(Memory, u16), ObjectError> {
+    let mut memory = Memory::new();
+    load_objects(&mut memory, &image())?;
+    // Programs may replace OS words, such as vector table entries, so only they are checked for overlap
+    let starting_pc = load_objects(&mut memory, objects)?;
+    Ok((memory, starting_pc))
+}
+
+#[test]
+fn test_os_fills_vector_tables() {
+    let (memory, starting_pc) = boot(&[]).unwrap();
+    assert_eq!(starting_pc, 0x3000);
+    for vector in 0x00..=0xff {
+        assert!(memory.read(vector) >= 0x0200);
+        assert!(memory.read(0x0100 + vector) >= 0x0200);
+    }
+    assert_eq!(memory.read(TRAP_RETURN), trap_return(Edition::Third));
+    assert_eq!(trap_return(Edition::Second), 0xc1c0);
+}
+
+#[test]
+fn test_os_service_routines_on_both_simulators() {
+    use crate::devices::{Display, Keyboard};
+    use crate::lrc3::Lrc3Cpu;
+    use crate::machine::{HaltReason, Machine};
+
+    let assembly = assemble(
+        "
+        .ORIG x3000
+        LEA R0, HELLO
+        PUTS
+        LEA R0, PACKED
+        PUTSP
+        GETC
+        OUT
+        IN
+        OUT
+        HALT
+HELLO   .STRINGZ \"Hi \"
+PACKED  .FILL x6261
+        .FILL x0063
+        .END
+    ",
+    )
+    .unwrap();
+    let objects = ObjectFile::from_assembly(&assembly);
+    let expected = b"Hi abcx\nInput a character> y\ny\n--- Halting the LC-3 ---\n";
+
+    let mut machine = Machine::from_objects(&objects).unwrap();
+    machine.devices().get::<Keyboard>().unwrap().type_bytes(b"xy");
+    assert_eq!(machine.run(10000), HaltReason::Halted);
+    assert_eq!(machine.devices().get::<Display>().unwrap().output(), expected);
+
+    machine = Machine::from_objects(&objects).unwrap();
+    machine.register_builtin_traps();
+    machine.devices().get::<Keyboard>().unwrap().type_bytes(b"xy");
+    assert_eq!(machine.run(100), HaltReason::Halted);
+    assert_eq!(machine.devices().get::<Display>().unwrap().output(), expected);
+
+    let mut cpu = Lrc3Cpu::from_objects(&objects).unwrap();
+    cpu.devices().get::<Keyboard>().unwrap().type_bytes(b"xy");
+    while cpu.devices().clock_enabled() {
+        cpu.step_instruction();
+    }
+    assert_eq!(cpu.devices().get::<Display>().unwrap().output(), expected);
+}
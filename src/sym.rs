@@ -0,0 +1,134 @@
+use crate::asm::Assembly;
+use core::fmt::{Display, Error, Formatter};
+use std::path::Path;
+
+/* The symbol table file lc3as writes next to each .obj:
+ *
+ * // Symbol table
+ * // Scope level 0:
+ * //	Symbol Name       Page Address
+ * //	----------------  ------------
+ * //	LOOP              3002
+ *
+ * Every line is a comment, and each symbol line holds a label and its
+ * address in hex. Reading accepts any whitespace between the two, with or
+ * without the leading //, and skips the header lines.
+ */
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Malformed { line: usize, text: String },
+    Io(std::io::Error),
+}
+
+impl Display for SymbolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Self::Malformed { line, text } => write!(
+                f,
+                "line {} of the symbol table is not a label and a hex address: {:?}",
+                line, text
+            ),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolTable {
+    pub symbols: Vec<(String, u16)>,
+}
+
+impl SymbolTable {
+    pub fn new(symbols: Vec<(String, u16)>) -> Self {
+        Self { symbols }
+    }
+
+    pub fn from_assembly(assembly: &Assembly) -> Self {
+        Self::new(assembly.symbols.clone())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let entry = line.trim().trim_start_matches("//").trim();
+            if entry.is_empty()
+                || entry.starts_with("Symbol table")
+                || entry.starts_with("Scope level")
+                || entry.starts_with("Symbol Name")
+                || entry.starts_with('-')
+            {
+                continue;
+            }
+            let mut fields = entry.split_whitespace();
+            let symbol = match (fields.next(), fields.next(), fields.next()) {
+                (Some(label), Some(address), None) => {
+                    let digits = address.trim_start_matches(['x', 'X']);
+                    u16::from_str_radix(digits, 16)
+                        .ok()
+                        .map(|address| (label.to_string(), address))
+                }
+                _ => None,
+            };
+            match symbol {
+                Some(symbol) => symbols.push(symbol),
+                None => {
+                    return Err(SymbolError::Malformed {
+                        line: index + 1,
+                        text: line.to_string(),
+                    })
+                }
+            }
+        }
+        Ok(Self::new(symbols))
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, SymbolError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(e) => Err(SymbolError::Io(e)),
+        }
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<(), SymbolError> {
+        std::fs::write(path, self.to_string()).map_err(SymbolError::Io)
+    }
+}
+
+impl Display for SymbolTable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "// Symbol table")?;
+        writeln!(f, "// Scope level 0:")?;
+        writeln!(f, "//\tSymbol Name       Page Address")?;
+        writeln!(f, "//\t----------------  ------------")?;
+        for (label, address) in &self.symbols {
+            writeln!(f, "//\t{:<16}  {:04X}", label, address)?;
+        }
+        writeln!(f)
+    }
+}
+
+#[test]
+fn test_symbol_table_round_trips() {
+    use crate::asm::assemble;
+
+    let assembly = assemble(
+        ".ORIG x3000\nLOOP ADD R0, R0, #-1\nBRp LOOP\nHALT\nSTRING .STRINGZ \"a\"\n.END",
+    )
+    .unwrap();
+    let table = SymbolTable::from_assembly(&assembly);
+    let text = table.to_string();
+    assert!(text.contains("//\tLOOP              3000\n//\tSTRING            3003\n"));
+    assert_eq!(SymbolTable::parse(&text).unwrap(), table);
+
+    // Tables from other tools vary in spacing, case and prefixes
+    let foreign = SymbolTable::parse("//  Symbol table\n//\tMAIN  x3000\nDATA\t4a0f\n").unwrap();
+    assert_eq!(
+        foreign.symbols,
+        vec![("MAIN".to_string(), 0x3000), ("DATA".to_string(), 0x4a0f)]
+    );
+    assert!(matches!(
+        SymbolTable::parse("//\tLOOP\n"),
+        Err(SymbolError::Malformed { line: 1, .. })
+    ));
+}